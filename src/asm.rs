@@ -0,0 +1,926 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::memory::START_ADDRESS;
+
+const MAX_ADDRESS: i64 = 0xFFFF;
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// An assembly error, pointing at the offending source position. Line 0
+/// means the error is about the file as a whole.
+#[derive(Debug, PartialEq)]
+pub struct Error {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            return write!(f, "{}: error: {}", self.file, self.message);
+        }
+        write!(
+            f,
+            "{}:{}:{}: error: {}",
+            self.file, self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Assembled ROM image, loaded at 0x200, plus every label and constant.
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub symbols: Vec<(String, u16)>,
+}
+
+impl Program {
+    /// One `0xADDR name` line per symbol, sorted by value.
+    pub fn symbol_file(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|(name, value)| format!("{:#06x} {}\n", value, name))
+            .collect()
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, Error> {
+    let mut assembler = Assembler::new();
+    assembler.read_source("<input>", Path::new("."), source, 0)?;
+    assembler.finish()
+}
+
+pub fn assemble_file(path: &Path) -> Result<Program, Error> {
+    let mut assembler = Assembler::new();
+    assembler.read_file(path, None, 0)?;
+    assembler.finish()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Number(i64),
+    Str(String),
+    Punct(char),
+    Shl,
+    Shr,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    tok: Tok,
+    column: usize,
+}
+
+#[derive(Clone)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn error(&self, column: usize, message: impl Into<String>) -> Error {
+        Error {
+            file: self.file.clone(),
+            line: self.line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64, usize),
+    Symbol(String, usize),
+    Here(usize),
+    Unary(char, Box<Expr>, usize),
+    Binary(Tok, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Keyword {
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+}
+
+#[derive(Clone, Debug)]
+enum Operand {
+    Reg(u8),
+    Range(u8, u8),
+    Keyword(Keyword),
+    Long(Expr),
+    Expr(Expr),
+}
+
+#[derive(Clone)]
+enum Item {
+    Instruction(String, Vec<(Operand, usize)>),
+    Data(usize, Vec<(Expr, usize)>),
+}
+
+struct Statement {
+    location: Location,
+    column: usize,
+    address: u16,
+    item: Item,
+}
+
+enum Symbol {
+    Label(u16),
+    Const(Expr, u16, Location),
+}
+
+struct Assembler {
+    symbols: HashMap<String, Symbol>,
+    order: Vec<String>,
+    statements: Vec<Statement>,
+    address: u16,
+}
+
+impl Assembler {
+    fn new() -> Assembler {
+        Assembler {
+            symbols: HashMap::new(),
+            order: Vec::new(),
+            statements: Vec::new(),
+            address: START_ADDRESS as u16,
+        }
+    }
+
+    fn read_file(
+        &mut self,
+        path: &Path,
+        from: Option<(&Location, usize)>,
+        depth: usize,
+    ) -> Result<(), Error> {
+        let source = fs::read_to_string(path).map_err(|e| match from {
+            Some((location, column)) => {
+                location.error(column, format!("cannot include {}: {}", path.display(), e))
+            }
+            None => Error {
+                file: path.display().to_string(),
+                line: 0,
+                column: 0,
+                message: e.to_string(),
+            },
+        })?;
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        self.read_source(&path.display().to_string(), &dir, &source, depth)
+    }
+
+    /// First pass: collect symbols and lay out statements.
+    fn read_source(
+        &mut self,
+        file: &str,
+        dir: &Path,
+        source: &str,
+        depth: usize,
+    ) -> Result<(), Error> {
+        for (n, text) in source.lines().enumerate() {
+            let location = Location {
+                file: file.to_string(),
+                line: n + 1,
+            };
+            let tokens = lex(text, &location)?;
+            self.read_line(&location, dir, tokens, depth)?;
+        }
+        Ok(())
+    }
+
+    fn read_line(
+        &mut self,
+        location: &Location,
+        dir: &Path,
+        tokens: Vec<Token>,
+        depth: usize,
+    ) -> Result<(), Error> {
+        let mut p = Parser {
+            tokens,
+            pos: 0,
+            location,
+        };
+
+        while let (Some(Tok::Ident(name)), Some(Tok::Punct(':'))) = (p.peek(0), p.peek(1)) {
+            let column = p.column();
+            self.define(location, column, name, Symbol::Label(self.address))?;
+            p.pos += 2;
+        }
+        if p.at_end() {
+            return Ok(());
+        }
+
+        let column = p.column();
+        let word = match p.next() {
+            Some(Tok::Ident(word)) => word,
+            _ => return Err(location.error(column, "expected a label, directive or mnemonic")),
+        };
+
+        match p.peek(0) {
+            Some(Tok::Punct('=')) => {
+                p.pos += 1;
+                return self.read_const(&mut p, word, column);
+            }
+            Some(Tok::Ident(equ)) if equ.eq_ignore_ascii_case("equ") => {
+                p.pos += 1;
+                return self.read_const(&mut p, word, column);
+            }
+            _ => (),
+        }
+
+        let keyword = word.to_ascii_lowercase();
+        match keyword.as_str() {
+            "org" => {
+                let expr_column = p.column();
+                let expr = p.expr()?;
+                p.expect_end()?;
+                let value = self.eval(&expr, self.address, location, &mut vec![])?;
+                if value < START_ADDRESS as i64 || value > MAX_ADDRESS {
+                    return Err(location.error(
+                        expr_column,
+                        format!(
+                            "org {:#x} is outside {:#x}..={:#x}",
+                            value, START_ADDRESS, MAX_ADDRESS
+                        ),
+                    ));
+                }
+                self.address = value as u16;
+            }
+            "include" => {
+                let path_column = p.column();
+                let path = match p.next() {
+                    Some(Tok::Str(path)) => path,
+                    _ => return Err(location.error(path_column, "expected a quoted file name")),
+                };
+                p.expect_end()?;
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(location.error(path_column, "includes nested too deeply"));
+                }
+                let path: PathBuf = dir.join(path);
+                self.read_file(&path, Some((location, path_column)), depth + 1)?;
+            }
+            "db" | "dw" => {
+                let width = if keyword == "db" { 1 } else { 2 };
+                let mut values = Vec::new();
+                loop {
+                    let value_column = p.column();
+                    if let Some(Tok::Str(text)) = p.peek(0) {
+                        p.pos += 1;
+                        values.extend(
+                            text.bytes()
+                                .map(|b| (Expr::Number(b as i64, value_column), value_column)),
+                        );
+                    } else {
+                        values.push((p.expr()?, value_column));
+                    }
+                    if !p.eat(',') {
+                        break;
+                    }
+                }
+                p.expect_end()?;
+                let size = width * values.len();
+                self.push(location, column, size, Item::Data(width, values))?;
+            }
+            _ => {
+                let mut operands = Vec::new();
+                if !p.at_end() {
+                    loop {
+                        let operand_column = p.column();
+                        operands.push((p.operand()?, operand_column));
+                        if !p.eat(',') {
+                            break;
+                        }
+                    }
+                }
+                p.expect_end()?;
+                let long = operands.iter().any(|(o, _)| matches!(o, Operand::Long(_)));
+                let size = if long { 4 } else { 2 };
+                self.push(location, column, size, Item::Instruction(keyword, operands))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_const(&mut self, p: &mut Parser, name: String, column: usize) -> Result<(), Error> {
+        let expr = p.expr()?;
+        p.expect_end()?;
+        let symbol = Symbol::Const(expr, self.address, p.location.clone());
+        self.define(p.location, column, name, symbol)
+    }
+
+    fn define(
+        &mut self,
+        location: &Location,
+        column: usize,
+        name: String,
+        symbol: Symbol,
+    ) -> Result<(), Error> {
+        if self.symbols.contains_key(&name) {
+            return Err(location.error(column, format!("`{}` is already defined", name)));
+        }
+        self.order.push(name.clone());
+        self.symbols.insert(name, symbol);
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        location: &Location,
+        column: usize,
+        size: usize,
+        item: Item,
+    ) -> Result<(), Error> {
+        let end = self.address as usize + size;
+        if end > MAX_ADDRESS as usize + 1 {
+            return Err(location.error(column, "program does not fit in memory"));
+        }
+        self.statements.push(Statement {
+            location: location.clone(),
+            column,
+            address: self.address,
+            item,
+        });
+        self.address = end as u16;
+        Ok(())
+    }
+
+    fn eval(
+        &self,
+        expr: &Expr,
+        here: u16,
+        location: &Location,
+        resolving: &mut Vec<String>,
+    ) -> Result<i64, Error> {
+        Ok(match expr {
+            Expr::Number(n, _) => *n,
+            Expr::Here(_) => here as i64,
+            Expr::Symbol(name, column) => match self.symbols.get(name) {
+                Some(Symbol::Label(addr)) => *addr as i64,
+                Some(Symbol::Const(expr, at, defined)) => {
+                    if resolving.contains(name) {
+                        return Err(location
+                            .error(*column, format!("`{}` is defined in terms of itself", name)));
+                    }
+                    resolving.push(name.clone());
+                    let value = self.eval(expr, *at, defined, resolving)?;
+                    resolving.pop();
+                    value
+                }
+                None => return Err(location.error(*column, format!("undefined symbol `{}`", name))),
+            },
+            Expr::Unary(op, e, _) => {
+                let v = self.eval(e, here, location, resolving)?;
+                match op {
+                    '-' => v.wrapping_neg(),
+                    '~' => !v,
+                    _ => v,
+                }
+            }
+            Expr::Binary(op, l, r) => {
+                let l = self.eval(l, here, location, resolving)?;
+                let r_column = expr_column(r);
+                let r = self.eval(r, here, location, resolving)?;
+                match op {
+                    Tok::Punct('+') => l.wrapping_add(r),
+                    Tok::Punct('-') => l.wrapping_sub(r),
+                    Tok::Punct('*') => l.wrapping_mul(r),
+                    Tok::Punct('/') | Tok::Punct('%') if r == 0 => {
+                        return Err(location.error(r_column, "division by zero"))
+                    }
+                    Tok::Punct('/') => l.wrapping_div(r),
+                    Tok::Punct('%') => l.wrapping_rem(r),
+                    Tok::Punct('&') => l & r,
+                    Tok::Punct('|') => l | r,
+                    Tok::Punct('^') => l ^ r,
+                    Tok::Shl => l.wrapping_shl(r as u32),
+                    Tok::Shr => l.wrapping_shr(r as u32),
+                    _ => unreachable!(),
+                }
+            }
+        })
+    }
+
+    /// Second pass: evaluate operands and emit bytes.
+    fn finish(self) -> Result<Program, Error> {
+        let mut image: Vec<Option<u8>> = Vec::new();
+        for statement in self.statements.iter() {
+            let bytes = self.encode(statement)?;
+            let offset = statement.address as usize - START_ADDRESS;
+            if image.len() < offset + bytes.len() {
+                image.resize(offset + bytes.len(), None);
+            }
+            for (i, b) in bytes.into_iter().enumerate() {
+                if image[offset + i].is_some() {
+                    return Err(statement.location.error(
+                        statement.column,
+                        format!("overwrites output at {:#05x}", offset + i + START_ADDRESS),
+                    ));
+                }
+                image[offset + i] = Some(b);
+            }
+        }
+
+        let mut symbols = Vec::new();
+        for name in self.order.iter() {
+            let value = match &self.symbols[name] {
+                Symbol::Label(addr) => *addr as i64,
+                Symbol::Const(expr, at, location) => {
+                    self.eval(expr, *at, location, &mut vec![name.clone()])?
+                }
+            };
+            symbols.push((name.clone(), value as u16));
+        }
+        symbols.sort_by_key(|(_, value)| *value);
+
+        Ok(Program {
+            rom: image.into_iter().map(|b| b.unwrap_or(0)).collect(),
+            symbols,
+        })
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, Error> {
+        let location = &statement.location;
+        let value = |expr: &Expr, column: usize, min: i64, max: i64| -> Result<u16, Error> {
+            let v = self.eval(expr, statement.address, location, &mut vec![])?;
+            if v < min || v > max {
+                return Err(location.error(
+                    column,
+                    format!("value {} is out of range {}..={}", v, min, max),
+                ));
+            }
+            Ok((v & 0xFFFF) as u16)
+        };
+
+        let (mnemonic, operands) = match &statement.item {
+            Item::Data(width, values) => {
+                let mut bytes = Vec::new();
+                for (expr, column) in values {
+                    if *width == 1 {
+                        bytes.push(value(expr, *column, -128, 0xFF)? as u8);
+                    } else {
+                        let v = value(expr, *column, -0x8000, 0xFFFF)?;
+                        bytes.extend_from_slice(&v.to_be_bytes());
+                    }
+                }
+                return Ok(bytes);
+            }
+            Item::Instruction(mnemonic, operands) => (mnemonic.as_str(), operands.as_slice()),
+        };
+
+        let bad = || {
+            location.error(
+                statement.column,
+                format!("invalid operands for `{}`", mnemonic.to_ascii_uppercase()),
+            )
+        };
+        let addr = |(o, c): &(Operand, usize)| match o {
+            Operand::Expr(e) => value(e, *c, 0, 0xFFF),
+            _ => Err(bad()),
+        };
+        let byte = |(o, c): &(Operand, usize)| match o {
+            Operand::Expr(e) => value(e, *c, -128, 0xFF).map(|v| v & 0xFF),
+            _ => Err(bad()),
+        };
+        let nibble = |(o, c): &(Operand, usize)| match o {
+            Operand::Expr(e) => value(e, *c, 0, 0xF),
+            _ => Err(bad()),
+        };
+        let xy = |x: u8, y: u8| ((x as u16) << 8) | ((y as u16) << 4);
+
+        use Keyword::*;
+        use Operand::{Keyword as Kw, Long, Range, Reg};
+        let opcode: u16 = match (mnemonic, operands) {
+            ("cls", []) => 0x00E0,
+            ("ret", []) => 0x00EE,
+            ("scr", []) => 0x00FB,
+            ("scl", []) => 0x00FC,
+            ("exit", []) => 0x00FD,
+            ("low", []) => 0x00FE,
+            ("high", []) => 0x00FF,
+            ("audio", []) => 0xF002,
+            ("scd", [n]) => 0x00C0 | nibble(n)?,
+            ("scu", [n]) => 0x00D0 | nibble(n)?,
+            ("sys", [a]) => addr(a)?,
+            ("jp", [(Reg(0), _), a]) => 0xB000 | addr(a)?,
+            ("jp", [a]) => 0x1000 | addr(a)?,
+            ("call", [a]) => 0x2000 | addr(a)?,
+            ("se", [(Reg(x), _), (Reg(y), _)]) => 0x5000 | xy(*x, *y),
+            ("se", [(Reg(x), _), b]) => 0x3000 | xy(*x, 0) | byte(b)?,
+            ("sne", [(Reg(x), _), (Reg(y), _)]) => 0x9000 | xy(*x, *y),
+            ("sne", [(Reg(x), _), b]) => 0x4000 | xy(*x, 0) | byte(b)?,
+            ("ld", [(Reg(x), _), (Reg(y), _)]) => 0x8000 | xy(*x, *y),
+            ("ld", [(Reg(x), _), (Kw(Dt), _)]) => 0xF007 | xy(*x, 0),
+            ("ld", [(Reg(x), _), (Kw(K), _)]) => 0xF00A | xy(*x, 0),
+            ("ld", [(Reg(x), _), (Kw(IndirectI), _)]) => 0xF065 | xy(*x, 0),
+            ("ld", [(Reg(x), _), (Kw(R), _)]) => 0xF085 | xy(*x, 0),
+            ("ld", [(Reg(x), _), b]) => 0x6000 | xy(*x, 0) | byte(b)?,
+            ("ld", [(Kw(I), _), (Long(e), c)]) => {
+                let target = value(e, *c, 0, 0xFFFF)?;
+                return Ok(vec![0xF0, 0x00, (target >> 8) as u8, target as u8]);
+            }
+            ("ld", [(Kw(I), _), a]) => 0xA000 | addr(a)?,
+            ("ld", [(Kw(Dt), _), (Reg(x), _)]) => 0xF015 | xy(*x, 0),
+            ("ld", [(Kw(St), _), (Reg(x), _)]) => 0xF018 | xy(*x, 0),
+            ("ld", [(Kw(F), _), (Reg(x), _)]) => 0xF029 | xy(*x, 0),
+            ("ld", [(Kw(Hf), _), (Reg(x), _)]) => 0xF030 | xy(*x, 0),
+            ("ld", [(Kw(B), _), (Reg(x), _)]) => 0xF033 | xy(*x, 0),
+            ("ld", [(Kw(IndirectI), _), (Reg(x), _)]) => 0xF055 | xy(*x, 0),
+            ("ld", [(Kw(R), _), (Reg(x), _)]) => 0xF075 | xy(*x, 0),
+            ("add", [(Kw(I), _), (Reg(x), _)]) => 0xF01E | xy(*x, 0),
+            ("add", [(Reg(x), _), (Reg(y), _)]) => 0x8004 | xy(*x, *y),
+            ("add", [(Reg(x), _), b]) => 0x7000 | xy(*x, 0) | byte(b)?,
+            ("or", [(Reg(x), _), (Reg(y), _)]) => 0x8001 | xy(*x, *y),
+            ("and", [(Reg(x), _), (Reg(y), _)]) => 0x8002 | xy(*x, *y),
+            ("xor", [(Reg(x), _), (Reg(y), _)]) => 0x8003 | xy(*x, *y),
+            ("sub", [(Reg(x), _), (Reg(y), _)]) => 0x8005 | xy(*x, *y),
+            ("shr", [(Reg(x), _)]) => 0x8006 | xy(*x, *x),
+            ("shr", [(Reg(x), _), (Reg(y), _)]) => 0x8006 | xy(*x, *y),
+            ("subn", [(Reg(x), _), (Reg(y), _)]) => 0x8007 | xy(*x, *y),
+            ("shl", [(Reg(x), _)]) => 0x800E | xy(*x, *x),
+            ("shl", [(Reg(x), _), (Reg(y), _)]) => 0x800E | xy(*x, *y),
+            ("rnd", [(Reg(x), _), b]) => 0xC000 | xy(*x, 0) | byte(b)?,
+            ("drw", [(Reg(x), _), (Reg(y), _), n]) => 0xD000 | xy(*x, *y) | nibble(n)?,
+            ("skp", [(Reg(x), _)]) => 0xE09E | xy(*x, 0),
+            ("sknp", [(Reg(x), _)]) => 0xE0A1 | xy(*x, 0),
+            ("save", [(Range(x, y), _)]) => 0x5002 | xy(*x, *y),
+            ("load", [(Range(x, y), _)]) => 0x5003 | xy(*x, *y),
+            ("plane", [n]) => 0xF001 | (nibble(n)? << 8),
+            ("pitch", [(Reg(x), _)]) => 0xF03A | xy(*x, 0),
+            (
+                "cls" | "ret" | "scr" | "scl" | "exit" | "low" | "high" | "audio" | "scd" | "scu"
+                | "sys" | "jp" | "call" | "se" | "sne" | "ld" | "add" | "or" | "and" | "xor"
+                | "sub" | "shr" | "subn" | "shl" | "rnd" | "drw" | "skp" | "sknp" | "save" | "load"
+                | "plane" | "pitch",
+                _,
+            ) => return Err(bad()),
+            _ => {
+                return Err(
+                    location.error(statement.column, format!("unknown mnemonic `{}`", mnemonic))
+                )
+            }
+        };
+        Ok(opcode.to_be_bytes().to_vec())
+    }
+}
+
+/// Column where `expr` starts in its line.
+fn expr_column(expr: &Expr) -> usize {
+    match expr {
+        Expr::Number(_, column)
+        | Expr::Symbol(_, column)
+        | Expr::Here(column)
+        | Expr::Unary(_, _, column) => *column,
+        Expr::Binary(_, l, _) => expr_column(l),
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    location: &'a Location,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self, n: usize) -> Option<Tok> {
+        self.tokens.get(self.pos + n).map(|t| t.tok.clone())
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let tok = self.peek(0);
+        self.pos += 1;
+        tok
+    }
+
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map(|t| t.column)
+            .unwrap_or(1)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek(0) == Some(Tok::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), Error> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self
+                .location
+                .error(self.column(), "unexpected trailing input"))
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, Error> {
+        if self.peek(0) == Some(Tok::Punct('['))
+            && self.peek(2) == Some(Tok::Punct(']'))
+            && matches!(self.peek(1), Some(Tok::Ident(i)) if i.eq_ignore_ascii_case("i"))
+        {
+            self.pos += 3;
+            return Ok(Operand::Keyword(Keyword::IndirectI));
+        }
+        if let Some(Tok::Ident(word)) = self.peek(0) {
+            if let Some(x) = register(&word) {
+                self.pos += 1;
+                if self.peek(0) == Some(Tok::Punct('-')) {
+                    if let Some(y) = self.peek(1).and_then(|t| match t {
+                        Tok::Ident(w) => register(&w),
+                        _ => None,
+                    }) {
+                        self.pos += 2;
+                        return Ok(Operand::Range(x, y));
+                    }
+                }
+                return Ok(Operand::Reg(x));
+            }
+            let keyword = match word.to_ascii_lowercase().as_str() {
+                "i" => Some(Keyword::I),
+                "dt" => Some(Keyword::Dt),
+                "st" => Some(Keyword::St),
+                "k" => Some(Keyword::K),
+                "f" => Some(Keyword::F),
+                "hf" => Some(Keyword::Hf),
+                "b" => Some(Keyword::B),
+                "r" => Some(Keyword::R),
+                "long" => {
+                    self.pos += 1;
+                    return Ok(Operand::Long(self.expr()?));
+                }
+                _ => None,
+            };
+            if let Some(keyword) = keyword {
+                self.pos += 1;
+                return Ok(Operand::Keyword(keyword));
+            }
+        }
+        Ok(Operand::Expr(self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, Error> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, Error> {
+        const LEVELS: [&[Tok]; 6] = [
+            &[Tok::Punct('|')],
+            &[Tok::Punct('^')],
+            &[Tok::Punct('&')],
+            &[Tok::Shl, Tok::Shr],
+            &[Tok::Punct('+'), Tok::Punct('-')],
+            &[Tok::Punct('*'), Tok::Punct('/'), Tok::Punct('%')],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek(0).filter(|t| LEVELS[level].contains(t)) {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        let column = self.column();
+        match self.next() {
+            Some(Tok::Number(n)) => Ok(Expr::Number(n, column)),
+            Some(Tok::Ident(name)) => Ok(Expr::Symbol(name, column)),
+            Some(Tok::Punct('$')) => Ok(Expr::Here(column)),
+            Some(Tok::Punct(op @ ('-' | '~' | '+'))) => {
+                Ok(Expr::Unary(op, Box::new(self.unary()?), column))
+            }
+            Some(Tok::Punct('(')) => {
+                let e = self.expr()?;
+                if !self.eat(')') {
+                    return Err(self.location.error(self.column(), "expected `)`"));
+                }
+                Ok(e)
+            }
+            _ => Err(self.location.error(column, "expected an expression")),
+        }
+    }
+}
+
+fn register(word: &str) -> Option<u8> {
+    let mut chars = word.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v' | 'V'), Some(d), None) => d.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+fn lex(text: &str, location: &Location) -> Result<Vec<Token>, Error> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let tok = if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().filter(|c| **c != '_').collect();
+            let lower = literal.to_ascii_lowercase();
+            let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+            } else if let Some(bin) = lower.strip_prefix("0b") {
+                i64::from_str_radix(bin, 2)
+            } else {
+                lower.parse()
+            };
+            let n = parsed
+                .map_err(|_| location.error(column, format!("invalid number `{}`", literal)))?;
+            tokens.push(Token {
+                tok: Tok::Number(n),
+                column,
+            });
+            continue;
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            tokens.push(Token {
+                tok: Tok::Ident(chars[start..i].iter().collect()),
+                column,
+            });
+            continue;
+        } else if c == '"' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i] != '"' {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err(location.error(column, "unterminated string"));
+            }
+            let s = chars[start..i].iter().collect();
+            i += 1;
+            tokens.push(Token {
+                tok: Tok::Str(s),
+                column,
+            });
+            continue;
+        } else if c == '<' && chars.get(i + 1) == Some(&'<') {
+            i += 1;
+            Tok::Shl
+        } else if c == '>' && chars.get(i + 1) == Some(&'>') {
+            i += 1;
+            Tok::Shr
+        } else if ",:()[]+-*/%&|^~$=".contains(c) {
+            Tok::Punct(c)
+        } else {
+            return Err(location.error(column, format!("unexpected character `{}`", c)));
+        };
+        tokens.push(Token { tok, column });
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::disasm::{disassemble, Options};
+
+    fn rom(source: &str) -> Vec<u8> {
+        assemble(source).unwrap().rom
+    }
+
+    fn error(source: &str) -> Error {
+        assemble(source).err().unwrap()
+    }
+
+    #[test]
+    fn assemble_instructions() {
+        let source = "
+            start:  CLS
+                    LD V1, 0x42         ; comment
+                    ld i, sprite
+                    DRW V0, V1, 5
+                    SHR V2
+                    LD [I], VF
+                    LD I, LONG 0x1234
+                    SAVE V1-V3
+                    JP V0, start
+            sprite: db 0b00111100, 0x42
+        ";
+        assert_eq!(
+            rom(source),
+            vec![
+                0x00, 0xE0, 0x61, 0x42, 0xA2, 0x14, 0xD0, 0x15, 0x82, 0x26, 0xFF, 0x55, 0xF0, 0x00,
+                0x12, 0x34, 0x51, 0x32, 0xB2, 0x00, 0x3C, 0x42
+            ]
+        );
+    }
+
+    #[test]
+    fn expressions_and_constants() {
+        let source = "
+            WIDTH equ 64
+            HALF = WIDTH / 2 - 1
+                LD V0, HALF
+                LD V1, -(1 << 2) & 0xFF
+                LD V2, (3 + 4) * 2 % 5
+                JP $ + 2
+                dw end - $
+            end:
+        ";
+        assert_eq!(
+            rom(source),
+            vec![0x60, 0x1F, 0x61, 0xFC, 0x62, 0x04, 0x12, 0x08, 0x00, 0x02]
+        );
+    }
+
+    #[test]
+    fn org_and_symbols() {
+        let program = assemble("JP main\norg 0x210\nmain: JP main").unwrap();
+        assert_eq!(program.rom.len(), 0x12);
+        assert_eq!(&program.rom[0x10..], &[0x12, 0x10]);
+        assert_eq!(program.symbol_file(), "0x0210 main\n");
+    }
+
+    #[test]
+    fn errors_point_at_source() {
+        assert_eq!(
+            error("  LD V0, 0x100"),
+            Error {
+                file: "<input>".to_string(),
+                line: 1,
+                column: 10,
+                message: "value 256 is out of range -128..=255".to_string(),
+            }
+        );
+        let e = error("\nJP nowhere");
+        assert_eq!((e.line, e.column), (2, 4));
+        assert_eq!(e.message, "undefined symbol `nowhere`");
+        assert_eq!(error("FOO V0").message, "unknown mnemonic `foo`");
+        assert_eq!(
+            error("a: CLS\na: CLS").to_string(),
+            "<input>:2:1: error: `a` is already defined"
+        );
+        assert_eq!(error("org 0x100").column, 5);
+        assert_eq!(
+            error("X = X + 1\nLD V0, X").message,
+            "`X` is defined in terms of itself"
+        );
+        assert_eq!(error("DRW V0, V1").message, "invalid operands for `DRW`");
+        assert_eq!(error("LD V0, 1/0").column, 10);
+        assert_eq!(error("LD V0, 4 % (2 - 2)").column, 13);
+        assert_eq!(error("LD V0, 1 / -(2 - 2)").column, 12);
+    }
+
+    #[test]
+    fn division_overflow_wraps() {
+        let min = "(0 - 9223372036854775807 - 1)";
+        assert_eq!(rom(&format!("LD V0, ({} / -1) & 0", min)), vec![0x60, 0x00]);
+        assert_eq!(rom(&format!("LD V0, {} % -1", min)), vec![0x60, 0x00]);
+        assert_eq!(rom(&format!("LD V0, -{} & 0", min)), vec![0x60, 0x00]);
+        assert_eq!(error("LD V0, 1 % 0").message, "division by zero");
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        let original = vec![
+            0x22, 0x06, 0xA2, 0x0E, 0x12, 0x04, 0x60, 0x05, 0xD0, 0x01, 0x3F, 0x01, 0x00, 0xEE,
+            0x3C, 0x7E,
+        ];
+        let listing = disassemble(&original, &Options::default());
+        assert_eq!(rom(&listing), original);
+    }
+}
@@ -0,0 +1,68 @@
+use chip_8_rust::asm::assemble_file;
+use std::path::{Path, PathBuf};
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: chip8-asm [-o <out.ch8>] [-s <out.sym>] <source>
+
+Options:
+    -o <file>   ROM output path (default: source with .ch8 extension)
+    -s <file>   symbol file path (default: source with .sym extension)";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut source = None;
+    let mut rom_path = None;
+    let mut symbol_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => {
+                rom_path = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("-o needs a path")),
+                )
+            }
+            "-s" => {
+                symbol_path = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("-s needs a path")),
+                )
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => source = Some(arg),
+        }
+    }
+
+    let source = PathBuf::from(source.unwrap_or_else(|| usage_error("Missing source file")));
+    let rom_path = rom_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source.with_extension("ch8"));
+    let symbol_path = symbol_path
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source.with_extension("sym"));
+
+    let program = match assemble_file(&source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    write(&rom_path, &program.rom);
+    write(&symbol_path, program.symbol_file().as_bytes());
+}
+
+fn write(path: &Path, data: &[u8]) {
+    if let Err(e) = fs::write(path, data) {
+        eprintln!("Cannot write {}: {}", path.display(), e);
+        process::exit(1);
+    }
+}
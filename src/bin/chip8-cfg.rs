@@ -0,0 +1,56 @@
+use chip_8_rust::cfg::Cfg;
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: chip8-cfg [-o graph.dot] <rom>
+
+Prints the basic block count and any problems found (unreachable code,
+computed jumps, stack imbalance).
+
+Options:
+    -o FILE     write the control-flow graph as Graphviz DOT";
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut dot = None;
+    let mut rom = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => match args.next() {
+                Some(path) => dot = Some(path),
+                None => {
+                    eprintln!("-o needs a file name\n\n{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option: {}\n\n{}", arg, USAGE);
+                process::exit(2);
+            }
+            _ => rom = Some(arg),
+        }
+    }
+
+    let rom = match rom {
+        Some(rom) => rom,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let data = fs::read(&rom).unwrap_or_else(|e| {
+        eprintln!("Cannot read {}: {}", rom, e);
+        process::exit(1);
+    });
+    let cfg = Cfg::new(&data);
+    print!("{}", cfg.report());
+    if let Some(path) = dot {
+        if let Err(e) = fs::write(&path, cfg.to_dot()) {
+            eprintln!("Cannot write {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
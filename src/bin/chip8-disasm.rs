@@ -0,0 +1,43 @@
+use chip_8_rust::disasm::{disassemble, Options, Syntax};
+use std::{env, fs, process};
+
+const USAGE: &str = "Usage: chip8-disasm [--octo] [--sprites] <rom>
+
+Options:
+    --octo      print Octo syntax instead of classic mnemonics
+    --sprites   show data one byte per line with its bitmap";
+
+fn main() {
+    let mut options = Options::default();
+    let mut rom = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--octo" => options.syntax = Syntax::Octo,
+            "--sprites" => options.sprites = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option: {}\n\n{}", arg, USAGE);
+                process::exit(2);
+            }
+            _ => rom = Some(arg),
+        }
+    }
+
+    let rom = match rom {
+        Some(rom) => rom,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    match fs::read(&rom) {
+        Ok(data) => print!("{}", disassemble(&data, &options)),
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", rom, e);
+            process::exit(1);
+        }
+    }
+}
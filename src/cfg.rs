@@ -0,0 +1,432 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+use crate::disasm::Analysis;
+use crate::instruction::Instruction;
+use crate::memory::START_ADDRESS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edge {
+    Fallthrough,
+    Jump,
+    /// Taken branch of a skip instruction.
+    Skip,
+    Call,
+}
+
+/// Straight-line run of instructions with a single entry.
+#[derive(Debug)]
+pub struct Block {
+    pub start: u16,
+    pub instructions: Vec<(u16, Instruction)>,
+    pub successors: Vec<(u16, Edge)>,
+}
+
+impl Block {
+    fn last(&self) -> Instruction {
+        self.instructions.last().unwrap().1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    /// `Bnnn` jump whose targets cannot be known statically.
+    ComputedJump { at: u16, base: u16 },
+    /// ROM bytes never reached from 0x200 and not referenced by `LD I`.
+    Unreachable { start: u16, end: u16 },
+    /// `RET` reachable from the entry point with nothing on the stack.
+    ReturnFromMain { at: u16 },
+    /// Subroutine with no reachable `RET`; every call leaks a stack slot.
+    NoReturn { sub: u16 },
+    /// Subroutine that jumps into the main program instead of returning.
+    EscapesToMain { sub: u16, at: u16 },
+    /// Subroutine that can call itself, directly or not.
+    Recursion { sub: u16 },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Issue::ComputedJump { at, base } => write!(
+                f,
+                "{:#05x}: computed jump JP V0, {:#05x}; targets are not followed",
+                at, base
+            ),
+            Issue::Unreachable { start, end } => write!(
+                f,
+                "{:#05x}-{:#05x}: unreachable from 0x200 and not referenced by LD I",
+                start, end
+            ),
+            Issue::ReturnFromMain { at } => {
+                write!(
+                    f,
+                    "{:#05x}: RET reachable from main with an empty stack",
+                    at
+                )
+            }
+            Issue::NoReturn { sub } => write!(
+                f,
+                "sub_{:03X}: never returns, each call leaves an entry on the stack",
+                sub
+            ),
+            Issue::EscapesToMain { sub, at } => write!(
+                f,
+                "sub_{:03X}: jumps into main code at {:#05x} without returning",
+                sub, at
+            ),
+            Issue::Recursion { sub } => {
+                write!(f, "sub_{:03X}: recursive, stack depth is unbounded", sub)
+            }
+        }
+    }
+}
+
+/// Control-flow graph of the code reachable from 0x200.
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, Block>,
+    /// Entry point plus every `CALL` target.
+    pub functions: BTreeSet<u16>,
+    pub issues: Vec<Issue>,
+}
+
+fn successors(
+    addr: u16,
+    instruction: Instruction,
+    code: &BTreeMap<u16, Instruction>,
+) -> Vec<(u16, Edge)> {
+    let next = addr + instruction.size();
+    let edges = match instruction {
+        Instruction::Jump(target) => vec![(target, Edge::Jump)],
+        Instruction::Call(target) => vec![(next, Edge::Fallthrough), (target, Edge::Call)],
+        Instruction::Ret | Instruction::Exit | Instruction::JumpV0(_) => vec![],
+        i if i.is_skip() => {
+            let skipped = code.get(&next).map_or(2, |i| i.size());
+            vec![(next, Edge::Fallthrough), (next + skipped, Edge::Skip)]
+        }
+        _ => vec![(next, Edge::Fallthrough)],
+    };
+    edges
+        .into_iter()
+        .filter(|(target, _)| code.contains_key(target))
+        .collect()
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    instruction.target().is_some() || instruction.is_skip() || instruction.ends_flow()
+}
+
+impl Cfg {
+    pub fn new(rom: &[u8]) -> Cfg {
+        let analysis = Analysis::new(rom);
+        let code = &analysis.instructions;
+        let entry = START_ADDRESS as u16;
+
+        let mut leaders: BTreeSet<u16> = BTreeSet::new();
+        leaders.insert(entry);
+        for (addr, instruction) in code.iter() {
+            if ends_block(instruction) {
+                leaders.extend(
+                    successors(*addr, *instruction, code)
+                        .iter()
+                        .map(|(t, _)| *t),
+                );
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for leader in leaders.iter().filter(|l| code.contains_key(l)) {
+            let mut instructions = Vec::new();
+            let mut addr = *leader;
+            while let Some(instruction) = code.get(&addr) {
+                instructions.push((addr, *instruction));
+                addr += instruction.size();
+                if ends_block(instruction) || leaders.contains(&addr) {
+                    break;
+                }
+            }
+            let (last_addr, last) = *instructions.last().unwrap();
+            let block = Block {
+                start: *leader,
+                successors: successors(last_addr, last, code),
+                instructions,
+            };
+            blocks.insert(*leader, block);
+        }
+
+        let mut functions = BTreeSet::new();
+        functions.insert(entry);
+        functions.extend(code.values().filter_map(|i| match i {
+            Instruction::Call(target) if code.contains_key(target) => Some(*target),
+            _ => None,
+        }));
+
+        let mut cfg = Cfg {
+            blocks,
+            functions,
+            issues: Vec::new(),
+        };
+        cfg.check(rom, &analysis);
+        cfg
+    }
+
+    /// Blocks reachable from `entry` without following calls.
+    pub fn region(&self, entry: u16) -> BTreeSet<u16> {
+        let mut region = BTreeSet::new();
+        let mut pending = vec![entry];
+        while let Some(start) = pending.pop() {
+            if !region.insert(start) {
+                continue;
+            }
+            if let Some(block) = self.blocks.get(&start) {
+                pending.extend(
+                    block
+                        .successors
+                        .iter()
+                        .filter(|(_, edge)| *edge != Edge::Call)
+                        .map(|(target, _)| *target),
+                );
+            }
+        }
+        region
+    }
+
+    fn check(&mut self, rom: &[u8], analysis: &Analysis) {
+        let entry = START_ADDRESS as u16;
+        let mut issues = Vec::new();
+
+        for (addr, instruction) in analysis.instructions.iter() {
+            if let Instruction::JumpV0(base) = instruction {
+                issues.push(Issue::ComputedJump {
+                    at: *addr,
+                    base: *base,
+                });
+            }
+        }
+
+        let data: BTreeSet<u16> = analysis
+            .instructions
+            .values()
+            .filter_map(|i| match i {
+                Instruction::LoadI(addr) | Instruction::LoadILong(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        let mut addr = entry;
+        let end = entry + rom.len() as u16;
+        while addr < end {
+            if analysis.is_code(addr) {
+                addr += 1;
+                continue;
+            }
+            let start = addr;
+            while addr < end && !analysis.is_code(addr) {
+                addr += 1;
+            }
+            if !(start..addr).any(|a| data.contains(&a)) {
+                issues.push(Issue::Unreachable {
+                    start,
+                    end: addr - 1,
+                });
+            }
+        }
+
+        let returns = |region: &BTreeSet<u16>| -> Vec<u16> {
+            region
+                .iter()
+                .filter_map(|start| self.blocks.get(start))
+                .filter(|block| block.last() == Instruction::Ret)
+                .map(|block| block.instructions.last().unwrap().0)
+                .collect()
+        };
+
+        let main = self.region(entry);
+        for at in returns(&main) {
+            issues.push(Issue::ReturnFromMain { at });
+        }
+
+        let mut calls: BTreeMap<u16, BTreeSet<u16>> = BTreeMap::new();
+        for sub in self.functions.iter().filter(|f| **f != entry) {
+            let region = self.region(*sub);
+            if let Some(at) = region.iter().find(|b| main.contains(b)) {
+                issues.push(Issue::EscapesToMain { sub: *sub, at: *at });
+            } else if returns(&region).is_empty() {
+                issues.push(Issue::NoReturn { sub: *sub });
+            }
+            let callees = region
+                .iter()
+                .filter_map(|start| self.blocks.get(start))
+                .flat_map(|block| block.successors.iter())
+                .filter(|(_, edge)| *edge == Edge::Call)
+                .map(|(target, _)| *target)
+                .collect();
+            calls.insert(*sub, callees);
+        }
+        for sub in calls.keys() {
+            let mut seen = BTreeSet::new();
+            let mut pending: Vec<u16> = calls[sub].iter().copied().collect();
+            while let Some(callee) = pending.pop() {
+                if callee == *sub {
+                    issues.push(Issue::Recursion { sub: *sub });
+                    break;
+                }
+                if seen.insert(callee) {
+                    if let Some(next) = calls.get(&callee) {
+                        pending.extend(next.iter().copied());
+                    }
+                }
+            }
+        }
+
+        self.issues = issues;
+    }
+
+    fn name(&self, addr: u16) -> String {
+        if addr == START_ADDRESS as u16 {
+            "main".to_string()
+        } else {
+            format!("sub_{:03X}", addr)
+        }
+    }
+
+    /// Block, edge and subroutine counts followed by the issues found.
+    pub fn report(&self) -> String {
+        let edges: usize = self.blocks.values().map(|b| b.successors.len()).sum();
+        let mut out = format!(
+            "{} blocks, {} edges, {} subroutines\n",
+            self.blocks.len(),
+            edges,
+            self.functions.len() - 1
+        );
+        for issue in self.issues.iter() {
+            writeln!(out, "warning: {}", issue).unwrap();
+        }
+        out
+    }
+
+    /// Graphviz DOT, one box per block. Calls are dashed blue edges, taken
+    /// skips dashed black ones; blocks with issues are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let flagged: BTreeSet<u16> = self
+            .issues
+            .iter()
+            .filter_map(|issue| match *issue {
+                Issue::ComputedJump { at, .. } | Issue::ReturnFromMain { at } => Some(at),
+                _ => None,
+            })
+            .collect();
+
+        let mut out =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if self.functions.contains(&block.start) {
+                write!(label, "{}:\\l", self.name(block.start)).unwrap();
+            }
+            for (addr, instruction) in block.instructions.iter() {
+                let text = instruction
+                    .to_string()
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"");
+                write!(label, "{:#05x}  {}\\l", addr, text).unwrap();
+            }
+            let colour = if block.instructions.iter().any(|(a, _)| flagged.contains(a)) {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                "    b{:03X} [label=\"{}\"{}];",
+                block.start, label, colour
+            )
+            .unwrap();
+        }
+        for block in self.blocks.values() {
+            for (target, edge) in block.successors.iter() {
+                let style = match edge {
+                    Edge::Fallthrough | Edge::Jump => "",
+                    Edge::Skip => " [style=dashed, label=\"skip\"]",
+                    Edge::Call => " [style=dashed, color=blue, label=\"call\"]",
+                };
+                writeln!(out, "    b{:03X} -> b{:03X}{};", block.start, target, style).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks_and_edges() {
+        // 200: LD V0, 0; 202: SE V0, 1; 204: CALL 20A; 206: JP 202
+        // 208: data; 20A: ADD V0, 1; 20C: RET
+        let rom = [
+            0x60, 0x00, 0x30, 0x01, 0x22, 0x0A, 0x12, 0x02, 0xFF, 0xFF, 0x70, 0x01, 0x00, 0xEE,
+        ];
+        let cfg = Cfg::new(&rom);
+        let starts: Vec<u16> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0x200, 0x202, 0x204, 0x206, 0x20A]);
+        assert_eq!(
+            cfg.blocks[&0x202].successors,
+            vec![(0x204, Edge::Fallthrough), (0x206, Edge::Skip)]
+        );
+        assert_eq!(
+            cfg.blocks[&0x204].successors,
+            vec![(0x206, Edge::Fallthrough), (0x20A, Edge::Call)]
+        );
+        assert_eq!(cfg.blocks[&0x20A].instructions.len(), 2);
+        assert_eq!(
+            cfg.functions.iter().copied().collect::<Vec<_>>(),
+            vec![0x200, 0x20A]
+        );
+        assert_eq!(
+            cfg.issues,
+            vec![Issue::Unreachable {
+                start: 0x208,
+                end: 0x209
+            }]
+        );
+
+        let dot = cfg.to_dot();
+        assert!(dot.contains("    b202 -> b206 [style=dashed, label=\"skip\"];\n"));
+        assert!(dot.contains("b20A [label=\"sub_20A:\\l0x20a  ADD V0, 0x01\\l0x20c  RET\\l\"];"));
+    }
+
+    #[test]
+    fn issues() {
+        // 200: CALL 208; 202: JP V0, 0x300; 204: unreferenced; 208: JP 202
+        let rom = [0x22, 0x08, 0xB3, 0x00, 0x12, 0x34, 0x00, 0xEE, 0x12, 0x02];
+        let cfg = Cfg::new(&rom);
+        assert_eq!(
+            cfg.issues,
+            vec![
+                Issue::ComputedJump {
+                    at: 0x202,
+                    base: 0x300
+                },
+                Issue::Unreachable {
+                    start: 0x204,
+                    end: 0x207
+                },
+                Issue::EscapesToMain {
+                    sub: 0x208,
+                    at: 0x202
+                },
+            ]
+        );
+        assert!(cfg.to_dot().contains(", color=red"));
+    }
+
+    #[test]
+    fn recursion_and_stray_return() {
+        // 200: CALL 204; 202: RET; 204: CALL 204
+        let rom = [0x22, 0x04, 0x00, 0xEE, 0x22, 0x04];
+        let issues = Cfg::new(&rom).issues;
+        assert!(issues.contains(&Issue::ReturnFromMain { at: 0x202 }));
+        assert!(issues.contains(&Issue::Recursion { sub: 0x204 }));
+    }
+}
@@ -1,13 +1,28 @@
 #![allow(non_snake_case)]
 
-use std::array::IntoIter;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
 
+use crate::coverage::Coverage;
+use crate::detect::{Detector, Mode};
+use crate::history::{Change, History, Step, Watchpoint};
+use crate::instruction::{
+    decode_all, decode_at, decode_bytes, decode_fst, decode_snd, decode_thrd,
+};
 use crate::keypad::Keypad;
-use crate::memory::Memory;
+use crate::memory::{Memory, MAX_ROM_SIZE, START_ADDRESS};
+use crate::octo;
+use crate::palette::Palette;
+use crate::platform::Quirks;
+use crate::profile::Profiler;
 use crate::random::Generator;
 use crate::registers::Registers;
+use crate::screenshot;
 use crate::stack::Stack;
-use crate::video::{Video, VIDEO_HEIGHT, VIDEO_WIDTH};
+use crate::timeline::Timeline;
+use crate::trace::{Record, Tracer};
+use crate::video::Video;
 
 pub struct Chip {
     memory: Memory,
@@ -18,6 +33,39 @@ pub struct Chip {
     keypad: Keypad,
     delay_timer: u8,
     sound_timer: u8,
+    history: Option<History>,
+    /// Undo record of the instruction being executed, while keeping history.
+    current_step: Option<Step>,
+    source_map: BTreeMap<u16, usize>,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    timeline: Option<Timeline>,
+    coverage: Option<Coverage>,
+    rom_len: usize,
+    quirks: Quirks,
+    detector: Option<Detector>,
+    cycles: u64,
+    frames: u32,
+    key_wait: KeyWait,
+}
+
+/// Progress of an `Fx0A` wait.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyWait {
+    Idle,
+    /// Waiting for a press; keys already pressed this frame when the wait
+    /// started do not count.
+    Waiting {
+        ignore: u16,
+    },
+    /// Waiting for this key to be released.
+    Held(u8),
+}
+
+impl Default for Chip {
+    fn default() -> Chip {
+        Chip::new()
+    }
 }
 
 impl Chip {
@@ -31,6 +79,19 @@ impl Chip {
             keypad: Keypad::new(),
             delay_timer: 0,
             sound_timer: 0,
+            history: None,
+            current_step: None,
+            source_map: BTreeMap::new(),
+            tracer: None,
+            profiler: None,
+            timeline: None,
+            coverage: None,
+            rom_len: 0,
+            quirks: Quirks::default(),
+            detector: None,
+            cycles: 0,
+            frames: 0,
+            key_wait: KeyWait::Idle,
         }
     }
 
@@ -42,15 +103,177 @@ impl Chip {
         &mut self.video
     }
 
-    pub fn load_rom(&mut self, filename: &str) {
-        self.memory.load_rom(filename)
+    /// Load a ROM image, or compile and load Octo source (`.8o`).
+    pub fn load_rom(&mut self, filename: &str) -> Result<(), String> {
+        let (rom, source_map) = if filename.ends_with(".8o") {
+            let program = octo::compile_file(Path::new(filename)).map_err(|e| e.to_string())?;
+            (program.rom, program.source_map)
+        } else {
+            let rom = fs::read(filename).map_err(|e| format!("Cannot read {}: {}", filename, e))?;
+            (rom, BTreeMap::new())
+        };
+        if rom.is_empty() {
+            return Err(format!("{} is empty", filename));
+        }
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(format!(
+                "{} is {} bytes, more than the {} that fit in memory",
+                filename,
+                rom.len(),
+                MAX_ROM_SIZE
+            ));
+        }
+        self.load_program(&rom);
+        self.source_map = source_map;
+        Ok(())
+    }
+
+    /// Make `Cxkk` repeatable by seeding its random number generator.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rand_gen = Generator::with_seed(seed);
+    }
+
+    /// Load raw program bytes at 0x200.
+    pub fn load_program(&mut self, program: &[u8]) {
+        self.memory.load_instructions(program);
+        self.rom_len = program.len();
+    }
+
+    /// The loaded program as it is now in memory.
+    pub fn rom(&self) -> &[u8] {
+        &self.memory.bytes()[START_ADDRESS..START_ADDRESS + self.rom_len]
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Switch instruction semantics, e.g. to `Platform::SuperChip.quirks()`.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Watch for signs of wrong quirk settings, logging what looks better
+    /// and, in `Mode::Switch`, switching to it.
+    pub fn enable_quirk_detection(&mut self, mode: Mode) {
+        self.detector = Some(Detector::new(mode));
+    }
+
+    /// Messages logged by quirk detection so far.
+    pub fn quirk_log(&self) -> &[String] {
+        self.detector.as_ref().map_or(&[], |d| d.log())
+    }
+
+    /// Record how every byte of memory is used from now on.
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Source line that produced the byte at `address`, for compiled programs.
+    pub fn source_line(&self, address: u16) -> Option<usize> {
+        self.source_map.get(&address).copied()
+    }
+
+    /// Start keeping an undo log of the last `capacity` executed instructions.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    /// Log every executed instruction from now on.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Count executions per address, opcode class and frame from now on.
+    pub fn set_profiler(&mut self, profiler: Profiler) {
+        self.profiler = Some(profiler);
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Write frames, calls, draws, key waits and the sound timer to a
+    /// trace-event timeline from now on.
+    pub fn set_timeline(&mut self, timeline: Timeline) {
+        self.timeline = Some(timeline);
+    }
+
+    /// Return addresses of the subroutines being executed, outermost first.
+    pub fn call_stack(&self) -> &[u16] {
+        self.stack.frames()
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
+    /// Number of timer ticks so far.
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Execute one instruction and tick the timers.
     pub fn cycle(&mut self) {
+        self.step();
+        self.tick_timers();
+    }
+
+    /// Execute one instruction without touching the timers.
+    pub fn step(&mut self) {
+        if self.history.is_some() {
+            self.current_step = Some(Step {
+                pc: self.memory.get_pointer(),
+                index_register: self.memory.index_register,
+                sp: self.stack.depth(),
+                delay_timer: self.delay_timer,
+                sound_timer: self.sound_timer,
+                hires: self.video.is_hires(),
+                key_wait: self.key_wait,
+                changes: Vec::new(),
+            });
+        }
+
+        if self.tracer.is_some() {
+            self.trace();
+        }
+
+        if self.detector.is_some() {
+            self.detect_quirks();
+        }
+        let timeline_state = self.timeline.as_ref().map(|_| self.state());
+        let pc = self.memory.get_pointer();
         let opcode = self.memory.fetch();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, opcode, self.memory.bytes());
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            let instruction = decode_at(self.memory.bytes(), pc as usize);
+            coverage.record(pc, &instruction, self.memory.index_register);
+        }
 
         self.execute_instruction(opcode);
+        self.cycles += 1;
 
+        if let (Some(timeline), Some(state)) = (self.timeline.as_mut(), timeline_state) {
+            let next_pc = self.memory.get_pointer();
+            if let Err(e) = timeline.instruction(&state, next_pc, self.sound_timer) {
+                eprintln!("Timeline disabled: {}", e);
+                self.timeline = None;
+            }
+        }
+
+        if let (Some(history), Some(step)) = (self.history.as_mut(), self.current_step.take()) {
+            history.push(step);
+        }
+    }
+
+    pub fn tick_timers(&mut self) {
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -58,12 +281,202 @@ impl Chip {
         if self.sound_timer > 0 {
             self.sound_timer -= 1;
         }
+
+        self.frames += 1;
+        self.keypad.end_frame();
+        if let KeyWait::Waiting { ignore } = &mut self.key_wait {
+            *ignore = 0;
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end_frame();
+        }
+        if let Some(timeline) = self.timeline.as_mut() {
+            if let Err(e) = timeline.end_frame(self.sound_timer) {
+                eprintln!("Timeline disabled: {}", e);
+                self.timeline = None;
+            }
+        }
+    }
+
+    /// Machine state before the next instruction, as written to traces.
+    pub fn state(&self) -> Record {
+        let pc = self.memory.get_pointer();
+        let bytes = self.memory.bytes();
+        let opcode = ((bytes[pc as usize] as u16) << 8) | bytes[pc as usize + 1] as u16;
+        let mut registers = [0u8; 16];
+        registers.copy_from_slice(self.registers.values());
+        Record {
+            cycle: self.cycles,
+            frame: self.frames,
+            pc,
+            opcode,
+            registers,
+            index_register: self.memory.index_register,
+            sp: self.stack.depth() as u8,
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        self.memory.bytes()
+    }
+
+    /// The screen, row by row, `resolution().0` pixels per row.
+    pub fn framebuffer(&self) -> &[u8] {
+        self.video.buffer()
+    }
+
+    /// Screen width and height in pixels, which `00FE`/`00FF` switch
+    /// between 64x32 and 128x64.
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.video.width(), self.video.height())
+    }
+
+    /// Save the screen as a PNG, PBM or PGM file, chosen by the extension
+    /// of `path`. See `screenshot::save`.
+    pub fn save_screenshot(
+        &self,
+        path: &Path,
+        palette: &Palette,
+        scale: usize,
+    ) -> Result<(), String> {
+        screenshot::save(path, self.framebuffer(), self.resolution(), palette, scale)
+    }
+
+    pub fn set_register(&mut self, reg: u8, value: u8) {
+        self.registers.set(reg, value);
+    }
+
+    fn detect_quirks(&mut self) {
+        let state = self.state();
+        let instruction = decode_at(self.memory.bytes(), state.pc as usize);
+        let rom_end = START_ADDRESS + self.rom_len;
+        if let Some(detector) = &mut self.detector {
+            let detection = detector.observe(
+                &state,
+                &instruction,
+                self.memory.bytes(),
+                rom_end,
+                self.quirks,
+                (self.video.width(), self.video.height()),
+            );
+            if let Some(detection) = detection {
+                eprintln!("{}", detection.message);
+                if let Some(quirks) = detection.quirks {
+                    self.quirks = quirks;
+                }
+            }
+        }
+    }
+
+    fn trace(&mut self) {
+        let record = self.state();
+        let instruction = decode_at(self.memory.bytes(), record.pc as usize);
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = tracer.log(&record, &instruction) {
+                eprintln!("Trace disabled: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    /// Undo the last executed instruction. Returns false when the log is empty.
+    pub fn step_back(&mut self) -> bool {
+        self.undo().is_some()
+    }
+
+    /// Run backwards until the next instruction to execute sits on a breakpoint,
+    /// or until undoing an instruction that wrote to a watched location.
+    /// Returns false if the log ran out first.
+    pub fn reverse_continue(&mut self, breakpoints: &[u16], watchpoints: &[Watchpoint]) -> bool {
+        while let Some(step) = self.undo() {
+            if breakpoints.contains(&step.pc) || watchpoints.iter().any(|w| step.touches(w)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Log the old value of registers the current instruction is about to
+    /// write, when keeping history. The same goes for the `log_*` below.
+    fn log_registers(&mut self, regs: impl IntoIterator<Item = u8>) {
+        if let Some(step) = &mut self.current_step {
+            for reg in regs {
+                let old = self.registers.read(reg);
+                step.changes.push(Change::Register { reg, old });
+            }
+        }
+    }
+
+    fn log_memory(&mut self, from: usize, len: usize) {
+        if let Some(step) = &mut self.current_step {
+            for (address, old) in self.memory.bytes()[from..from + len].iter().enumerate() {
+                step.changes.push(Change::Memory {
+                    address: (from + address) as u16,
+                    old: *old,
+                });
+            }
+        }
+    }
+
+    fn log_pixel(&mut self, index: usize) {
+        if let Some(step) = &mut self.current_step {
+            let old = self.video.buffer()[index];
+            step.changes.push(Change::Pixel { index, old });
+        }
+    }
+
+    /// Log every lit pixel, before the screen is cleared.
+    fn log_screen(&mut self) {
+        if let Some(step) = &mut self.current_step {
+            step.changes.extend(
+                self.video
+                    .buffer()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| **p != 0)
+                    .map(|(index, old)| Change::Pixel { index, old: *old }),
+            );
+        }
+    }
+
+    fn log_stack(&mut self, slot: usize) {
+        if let Some(step) = &mut self.current_step {
+            let old = self.stack.entries()[slot];
+            step.changes.push(Change::Stack { slot, old });
+        }
+    }
+
+    fn undo(&mut self) -> Option<Step> {
+        let step = self.history.as_mut()?.pop()?;
+        if step.hires != self.video.is_hires() {
+            self.video.set_hires(step.hires);
+        }
+        // Newest first, so a location written twice gets its oldest value.
+        for change in step.changes.iter().rev() {
+            match *change {
+                Change::Register { reg, old } => self.registers.set(reg, old),
+                Change::Memory { address, old } => self.memory.write(address, old),
+                Change::Pixel { index, old } => self.video.buffer_mut()[index] = old,
+                Change::Stack { slot, old } => self.stack.set_entry(slot, old),
+            }
+        }
+        self.memory.set_address(step.pc);
+        self.memory.index_register = step.index_register;
+        self.stack.set_depth(step.sp);
+        self.delay_timer = step.delay_timer;
+        self.sound_timer = step.sound_timer;
+        self.key_wait = step.key_wait;
+        Some(step)
     }
 
     fn execute_instruction(&mut self, opcode: u16) {
         match opcode {
             0x00E0 => self.op_00E0(),
             0x00EE => self.op_00EE(),
+            0x00FE => self.set_hires(false),
+            0x00FF => self.set_hires(true),
             opcode if opcode & 0xF000 == 0x1000 => self.op_1nnn(decode_all(opcode)),
             opcode if opcode & 0xF000 == 0x2000 => self.op_2nnn(decode_all(opcode)),
             opcode if opcode & 0xF000 == 0x3000 => {
@@ -99,11 +512,15 @@ impl Chip {
             opcode if opcode & 0xF00F == 0x8005 => {
                 self.op_8xy5(decode_fst(opcode), decode_snd(opcode))
             }
-            opcode if opcode & 0xF00F == 0x8006 => self.op_8xy6(decode_fst(opcode)),
+            opcode if opcode & 0xF00F == 0x8006 => {
+                self.op_8xy6(decode_fst(opcode), decode_snd(opcode))
+            }
             opcode if opcode & 0xF00F == 0x8007 => {
                 self.op_8xy7(decode_fst(opcode), decode_snd(opcode))
             }
-            opcode if opcode & 0xF00F == 0x800E => self.op_8xyE(decode_fst(opcode)),
+            opcode if opcode & 0xF00F == 0x800E => {
+                self.op_8xyE(decode_fst(opcode), decode_snd(opcode))
+            }
             opcode if opcode & 0xF00F == 0x9000 => {
                 self.op_9xy0(decode_fst(opcode), decode_snd(opcode))
             }
@@ -131,9 +548,15 @@ impl Chip {
     }
 
     fn op_00E0(&mut self) {
+        self.log_screen();
         self.video.clear();
     }
 
+    fn set_hires(&mut self, hires: bool) {
+        self.log_screen();
+        self.video.set_hires(hires);
+    }
+
     fn op_00EE(&mut self) {
         self.memory.set_address(self.stack.pop());
     }
@@ -143,6 +566,7 @@ impl Chip {
     }
 
     fn op_2nnn(&mut self, addr: u16) {
+        self.log_stack(self.stack.depth());
         self.stack.push(self.memory.get_pointer());
         self.memory.set_address(addr);
     }
@@ -166,47 +590,73 @@ impl Chip {
     }
 
     fn op_6xkk(&mut self, reg: u8, val: u8) {
+        self.log_registers([reg]);
         self.registers.set(reg, val);
     }
 
     fn op_7xkk(&mut self, reg: u8, val: u8) {
+        self.log_registers([reg]);
         let value = self.registers.read(reg);
         self.registers.set(reg, value.wrapping_add(val));
     }
 
     fn op_8xy0(&mut self, reg_1: u8, reg_2: u8) {
+        self.log_registers([reg_1]);
         self.registers.set(reg_1, self.registers.read(reg_2));
     }
 
     fn op_8xy1(&mut self, reg_1: u8, reg_2: u8) {
+        self.log_registers([reg_1, 0xF]);
         self.registers.set(reg_1, self.registers.or(reg_1, reg_2));
+        self.logic_vf_reset();
     }
 
     fn op_8xy2(&mut self, reg_1: u8, reg_2: u8) {
+        self.log_registers([reg_1, 0xF]);
         self.registers.set(reg_1, self.registers.and(reg_1, reg_2));
+        self.logic_vf_reset();
     }
 
     fn op_8xy3(&mut self, reg_1: u8, reg_2: u8) {
+        self.log_registers([reg_1, 0xF]);
         self.registers.set(reg_1, self.registers.xor(reg_1, reg_2));
+        self.logic_vf_reset();
+    }
+
+    fn logic_vf_reset(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.registers.vx_set(0);
+        }
     }
 
     fn op_8xy4(&mut self, reg_1: u8, reg_2: u8) {
+        self.log_registers([reg_1, 0xF]);
         self.registers.add_inplace(reg_1, reg_2);
     }
 
     fn op_8xy5(&mut self, lhs: u8, rhs: u8) {
+        self.log_registers([lhs, 0xF]);
         self.registers.sub_inplace(lhs, rhs);
     }
 
-    fn op_8xy6(&mut self, reg: u8) {
+    fn op_8xy6(&mut self, reg: u8, src: u8) {
+        self.log_registers([reg, 0xF]);
+        if self.quirks.shift_uses_vy {
+            self.registers.set(reg, self.registers.read(src));
+        }
         self.registers.shitf_right_inplace(reg);
     }
 
     fn op_8xy7(&mut self, reg_1: u8, reg_2: u8) {
+        self.log_registers([reg_1, 0xF]);
         self.registers.sub_n(reg_1, reg_2);
     }
 
-    fn op_8xyE(&mut self, reg: u8) {
+    fn op_8xyE(&mut self, reg: u8, src: u8) {
+        self.log_registers([reg, 0xF]);
+        if self.quirks.shift_uses_vy {
+            self.registers.set(reg, self.registers.read(src));
+        }
         self.registers.shift_left_inplace(reg);
     }
 
@@ -221,31 +671,44 @@ impl Chip {
     }
 
     fn op_Bnnn(&mut self, address: u16) {
-        self.memory.advance(self.registers.read(0) as u16 + address);
+        let reg = if self.quirks.jump_uses_vx {
+            (address >> 8) as u8
+        } else {
+            0
+        };
+        self.memory
+            .set_address(self.registers.read(reg) as u16 + address);
     }
 
     fn op_Cxkk(&mut self, fst: u8, snd: u8) {
+        self.log_registers([fst]);
         self.registers.set(fst, self.rand_gen.get_random() & snd);
     }
 
     fn op_Dxyn(&mut self, vx: u8, vy: u8, height: u8) {
         let from = self.memory.index_register as usize;
-        let sprite = self.memory.slice(from, from + height as usize);
+        let sprite = self.memory.slice(from, from + height as usize).to_vec();
         let x_pos = self.registers.read(vx) as usize;
         let y_pos = self.registers.read(vy) as usize;
+        let (width, height) = self.resolution();
 
+        self.log_registers([0xF]);
         self.registers.vx_set(0);
 
         for (row, byte) in sprite.iter().enumerate() {
             for (col, sprite_pixel) in byte_to_enumeration(*byte) {
-                let screen_pixel = self
-                    .video
-                    .pixel((y_pos + row) % VIDEO_HEIGHT, (x_pos + col) % VIDEO_WIDTH);
+                let (y, x) = (y_pos % height + row, x_pos % width + col);
+                if self.quirks.clip_sprites && (y >= height || x >= width) {
+                    continue;
+                }
+                let (y, x) = (y % height, x % width);
                 if sprite_pixel != 0 {
-                    if *screen_pixel == 0xFFFFFFFF {
+                    self.log_pixel(y * width + x);
+                    let screen_pixel = self.video.pixel(y, x);
+                    if *screen_pixel != 0 {
                         self.registers.vx_set(1);
                     }
-                    *screen_pixel ^= 0xFFFFFFFF;
+                    *screen_pixel ^= 1;
                 }
             }
         }
@@ -264,14 +727,30 @@ impl Chip {
     }
 
     fn op_Fx07(&mut self, reg: u8) {
+        self.log_registers([reg]);
         self.registers.set(reg, self.delay_timer);
     }
 
     fn op_Fx0A(&mut self, reg: u8) {
-        if let Some(key) = self.keypad.get_pressed() {
-            self.registers.set(reg, key as u8);
-        } else {
-            self.memory.go_back(2);
+        let edges = self.keypad.pressed_edges();
+        let lowest = |keys: u16| (0..16).find(|k| keys & (1 << k) != 0);
+        let (wait, key) = match self.key_wait {
+            KeyWait::Idle => (KeyWait::Waiting { ignore: edges }, None),
+            KeyWait::Waiting { ignore } => match lowest(edges & !ignore) {
+                Some(key) if self.quirks.wait_key_on_press => (KeyWait::Idle, Some(key)),
+                Some(key) => (KeyWait::Held(key), None),
+                None => (self.key_wait, None),
+            },
+            KeyWait::Held(key) if !self.keypad.is_pressed(key) => (KeyWait::Idle, Some(key)),
+            held => (held, None),
+        };
+        self.key_wait = wait;
+        match key {
+            Some(key) => {
+                self.log_registers([reg]);
+                self.registers.set(reg, key);
+            }
+            None => self.memory.go_back(2),
         }
     }
 
@@ -292,24 +771,33 @@ impl Chip {
     }
 
     fn op_Fx33(&mut self, n: u8) {
+        self.log_memory(self.memory.index_register as usize, 3);
         self.memory.store_bcd_repr(self.registers.read(n));
     }
 
     fn op_Fx55(&mut self, val: u8) {
+        self.log_memory(self.memory.index_register as usize, val as usize + 1);
         self.memory
             .copy_from(self.registers.slice(0, (val + 1) as usize));
+        if self.quirks.memory_increments_i {
+            self.memory.index_register += val as u16 + 1;
+        }
     }
 
     fn op_Fx65(&mut self, val: u8) {
+        self.log_registers(0..=val);
         self.registers.copy_from(self.memory.slice(
             self.memory.index_register as usize,
             self.memory.index_register as usize + (val + 1) as usize,
         ));
+        if self.quirks.memory_increments_i {
+            self.memory.index_register += val as u16 + 1;
+        }
     }
 }
 
 fn byte_to_enumeration(b: u8) -> impl Iterator<Item = (usize, u8)> {
-    IntoIter::new([
+    IntoIterator::into_iter([
         b & 0b10000000,
         b & 0b01000000,
         b & 0b00100000,
@@ -322,22 +810,216 @@ fn byte_to_enumeration(b: u8) -> impl Iterator<Item = (usize, u8)> {
     .enumerate()
 }
 
-fn decode_fst(n: u16) -> u8 {
-    ((n & 0x0F00) >> 8u16) as u8
-}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform::Platform;
+    use crate::video::{VIDEO_HEIGHT, VIDEO_WIDTH};
 
-fn decode_snd(n: u16) -> u8 {
-    ((n & 0x00F0) >> 4u16) as u8
-}
+    fn chip_with(program: &[u8]) -> Chip {
+        let mut chip = Chip::new();
+        chip.load_program(program);
+        chip.enable_history(64);
+        chip
+    }
 
-fn decode_thrd(n: u16) -> u8 {
-    (n & 0x000F) as u8
-}
+    #[test]
+    fn step_back_restores_state() {
+        // LD V1, 0x42; LD I, 0x300; LD [I], V1; CALL 0x20A
+        let mut chip = chip_with(&[
+            0x61, 0x42, 0xA3, 0x00, 0xF1, 0x55, 0x00, 0x00, 0x00, 0x00, 0x22, 0x0A,
+        ]);
+        chip.memory.set_address(0x200);
+        for _ in 0..3 {
+            chip.cycle();
+        }
+        chip.memory.set_address(0x20A);
+        chip.cycle();
+        assert_eq!(chip.stack.depth(), 1);
+        assert_eq!(chip.call_stack(), &[0x20C]);
 
-fn decode_bytes(n: u16) -> u8 {
-    (n & 0x00FF) as u8
-}
+        assert!(chip.step_back());
+        assert_eq!(chip.stack.depth(), 0);
+        assert_eq!(chip.stack.entries()[0], 0);
+        assert_eq!(chip.memory.get_pointer(), 0x20A);
+
+        assert!(chip.step_back());
+        assert_eq!(chip.memory.bytes()[0x301], 0);
+        assert!(chip.step_back());
+        assert_eq!(chip.memory.index_register, 0);
+        assert!(chip.step_back());
+        assert_eq!(chip.registers.read(1), 0);
+        assert_eq!(chip.memory.get_pointer(), 0x200);
+        assert!(!chip.step_back());
+    }
+
+    #[test]
+    fn step_back_restores_pixels() {
+        // LD I, font 0; DRW V0, V0, 5
+        let mut chip = chip_with(&[0xF0, 0x29, 0xD0, 0x05]);
+        chip.cycle();
+        chip.cycle();
+        assert_ne!(chip.video.buffer().iter().filter(|p| **p != 0).count(), 0);
+        chip.step_back();
+        assert!(chip.video.buffer().iter().all(|p| *p == 0));
+    }
+
+    #[test]
+    fn step_back_restores_every_write() {
+        // LD V1, 3; LD V2, 8; LD VF, 7; SHR V1, V2; LD I, 0x300; LD B, V2
+        let mut chip = chip_with(&[
+            0x61, 0x03, 0x62, 0x08, 0x6F, 0x07, 0x81, 0x26, 0xA3, 0x00, 0xF2, 0x33,
+        ]);
+        let mut quirks = chip.quirks();
+        quirks.shift_uses_vy = true;
+        chip.set_quirks(quirks);
+        for _ in 0..6 {
+            chip.step();
+        }
+        assert_eq!(chip.memory.bytes()[0x302], 8);
+        assert_eq!((chip.registers.read(1), chip.registers.read(0xF)), (4, 0));
+
+        assert!(chip.step_back());
+        assert_eq!(chip.memory.bytes()[0x300..0x303], [0, 0, 0]);
+        assert!(chip.step_back());
+        // SHR wrote V1 twice and VF once; both get their values back.
+        assert!(chip.step_back());
+        assert_eq!((chip.registers.read(1), chip.registers.read(0xF)), (3, 7));
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_writer() {
+        // LD V2, 1; LD V3, 7; ADD V2, 1; LD V4, 9
+        let mut chip = chip_with(&[0x62, 0x01, 0x63, 0x07, 0x72, 0x01, 0x64, 0x09]);
+        for _ in 0..4 {
+            chip.cycle();
+        }
+        assert!(chip.reverse_continue(&[], &[Watchpoint::Register(2)]));
+        assert_eq!(chip.memory.get_pointer(), 0x204);
+        assert_eq!(chip.registers.read(2), 1);
+
+        assert!(chip.reverse_continue(&[0x200], &[]));
+        assert_eq!(chip.memory.get_pointer(), 0x200);
+        assert!(!chip.reverse_continue(&[0x200], &[]));
+    }
+
+    #[test]
+    fn quirks_change_semantics() {
+        // LD V1, 3; LD V2, 8; SHR V1, V2; LD V0, 2; JP V0, 0x300
+        let program = [0x61, 0x03, 0x62, 0x08, 0x81, 0x26, 0x60, 0x02, 0xB3, 0x00];
+        let mut chip = chip_with(&program);
+        for _ in 0..5 {
+            chip.step();
+        }
+        assert_eq!(chip.registers.read(1), 1);
+        assert_eq!(chip.memory.get_pointer(), 0x302);
+
+        let mut chip = chip_with(&program);
+        chip.set_quirks(Platform::Chip8.quirks());
+        for _ in 0..5 {
+            chip.step();
+        }
+        assert_eq!(chip.registers.read(1), 4);
+    }
+
+    #[test]
+    fn clipped_sprite() {
+        // LD V0, 62; LD I, font 0; DRW V0, V0, 1
+        let program = [0x60, 0x3E, 0xF0, 0x29, 0xD0, 0x01];
+        let mut chip = chip_with(&program);
+        chip.set_quirks(Platform::Chip8.quirks());
+        for _ in 0..3 {
+            chip.step();
+        }
+        // Font row 0 is 0xF0: columns 62 and 63 are drawn, the rest clipped.
+        let row = (62 % VIDEO_HEIGHT) * VIDEO_WIDTH;
+        assert_eq!(chip.framebuffer()[row + 62], 1);
+        assert_eq!(chip.framebuffer()[row], 0);
+    }
+
+    #[test]
+    fn wait_key_completes_on_release() {
+        // LD V1, K; LD V2, K
+        let mut chip = chip_with(&[0xF1, 0x0A, 0xF2, 0x0A]);
+        chip.get_keypad().press(0x7);
+        for _ in 0..3 {
+            chip.step();
+        }
+        // Held since before the wait: not a new press.
+        assert_eq!(chip.state().pc, 0x200);
+        chip.tick_timers();
+        chip.get_keypad().release(0x7);
+        chip.get_keypad().press(0x5);
+        chip.step();
+        chip.step();
+        assert_eq!(chip.state().pc, 0x200);
+        chip.get_keypad().release(0x5);
+        chip.step();
+        assert_eq!(chip.state().pc, 0x202);
+        assert_eq!(chip.state().registers[1], 0x5);
 
-fn decode_all(n: u16) -> u16 {
-    n & 0x0FFF
+        // A key that stays down does not complete the next wait.
+        chip.get_keypad().press(0x5);
+        chip.tick_timers();
+        for _ in 0..3 {
+            chip.step();
+        }
+        assert_eq!(chip.state().pc, 0x202);
+    }
+
+    #[test]
+    fn step_back_over_key_wait() {
+        // LD V1, K
+        let mut chip = chip_with(&[0xF1, 0x0A]);
+        chip.step();
+        chip.get_keypad().press(0x5);
+        chip.step();
+        chip.get_keypad().release(0x5);
+        chip.step();
+        assert_eq!(chip.state().pc, 0x202);
+        // Replaying the completing instruction completes it again.
+        assert!(chip.step_back());
+        assert_eq!(chip.key_wait, KeyWait::Held(0x5));
+        chip.step();
+        assert_eq!(chip.state().pc, 0x202);
+        assert_eq!(chip.state().registers[1], 0x5);
+    }
+
+    #[test]
+    fn wait_key_on_press() {
+        let mut chip = chip_with(&[0xF1, 0x0A, 0xF2, 0x0A]);
+        chip.set_quirks(Quirks {
+            wait_key_on_press: true,
+            ..Quirks::default()
+        });
+        chip.step();
+        chip.get_keypad().press(0xC);
+        chip.step();
+        assert_eq!(chip.state().pc, 0x202);
+        assert_eq!(chip.state().registers[1], 0xC);
+        // Still held: the second wait does not complete.
+        chip.tick_timers();
+        for _ in 0..3 {
+            chip.step();
+        }
+        assert_eq!(chip.state().pc, 0x202);
+    }
+
+    #[test]
+    fn resolution_switch() {
+        // HIGH; LD V0, 100; LD I, font 0; DRW V0, V0, 1; LOW
+        let mut chip = chip_with(&[0x00, 0xFF, 0x60, 0x64, 0xF0, 0x29, 0xD0, 0x01, 0x00, 0xFE]);
+        for _ in 0..4 {
+            chip.step();
+        }
+        assert_eq!(chip.resolution(), (128, 64));
+        assert_eq!(chip.framebuffer()[(100 % 64) * 128 + 100], 1);
+        chip.step();
+        assert_eq!(chip.resolution(), (VIDEO_WIDTH, VIDEO_HEIGHT));
+        assert!(chip.framebuffer().iter().all(|p| *p == 0));
+        // Stepping back over LOW brings the high resolution screen back.
+        assert!(chip.step_back());
+        assert_eq!(chip.resolution(), (128, 64));
+        assert_eq!(chip.framebuffer()[(100 % 64) * 128 + 100], 1);
+    }
 }
@@ -0,0 +1,706 @@
+//! Command-line parsing and the subcommands that do not open a window.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use std::{fs, process};
+
+use crate::asm::assemble_file;
+use crate::chip::Chip;
+use crate::config::{Config, Settings};
+use crate::detect;
+use crate::disasm::{disassemble, Options, Syntax};
+use crate::history::Watchpoint;
+use crate::lint;
+use crate::romdb::{self, Database, Entry};
+use crate::trace::{self, framebuffer_hash, Filter, Format, Tracer};
+use crate::trace_diff;
+
+/// Frames `bench` and `test` run for by default, five seconds of real time.
+const DEFAULT_FRAMES: u32 = 300;
+
+pub const USAGE: &str = "Usage: chip8 <command> [options]
+       chip8 <rom> [options]     same as `chip8 run <rom>`
+
+Commands:
+    run <rom>                 run a ROM or Octo source (.8o) in a window
+    disasm <rom>              print a ROM as assembly
+    asm <source>              assemble a source file into a ROM
+    info <rom>                print a ROM's hash and ROM database entry
+    lint <rom>                list platform-dependent instructions
+    bench <rom>               run a ROM without a window, as fast as possible
+    test <rom>                run a ROM without a window and check the screen
+    trace-diff <rom> <trace>  compare execution with a reference trace
+
+Run `chip8 <command> --help` for the options of a command.";
+
+const RUN_USAGE: &str = "Usage: chip8 run <rom> [options]
+
+Options:
+    --platform <name>         quirks of chip8, schip or xochip
+    --ipf <n>                 instructions per 60 Hz frame (default: 10)
+    --scale <n>               window pixels per CHIP-8 pixel (default: 10)
+    --fullscreen              start in fullscreen
+    --anti-flicker <mode>     off, fade (phosphor persistence) or blend (the last
+                              two frames) (default: off)
+    --decay <f>               brightness kept per picture when fading (default: 0.6)
+    --vblank                  draw the screen once per 60 Hz frame instead of
+                              after every instruction
+    --filter <f,...>          scale2x, scale3x, smooth, scanlines, grid or crt,
+                              applied in order (default: none)
+    --palette <theme>         classic, amber, green, lcd, octo or high-contrast,
+                              or colours as RRGGBB hex: <bg>,<fg> or four for
+                              XO-CHIP (default: classic)
+    --seed <n>                seed the random number generator
+    --keymap <file>           keymap file (default: ~/.config/chip8/keymap.toml)
+    --config <file>           configuration file (default: ~/.config/chip8/config.toml)
+    --romdb <file>            ROM database (default: ~/.config/chip8/roms.toml)
+    --mute                    no sound
+    --record-gif <file>       record the screen to an animated GIF until F9 or exit
+
+Debugging:
+    --trace <file>            write an execution trace
+    --trace-binary            write it in the binary format
+    --trace-range <from-to>   only trace these addresses
+    --trace-class <c,...>     only trace these instruction classes
+    --profile                 print a profile at exit
+    --profile-csv <file>      write per-address counts as CSV
+    --profile-folded <file>   write folded call stacks for flame graphs
+    --timeline <file>         write a Chrome trace-event timeline
+    --coverage <file>         write an annotated coverage listing
+    --coverage-image <file>   write a coverage map as an image
+    --detect-quirks           log signs of wrong quirk settings
+    --auto-quirks             also switch to the quirks that fit
+    --history <n>             keep the last n instructions to step back over:
+                              F6 pauses, F7 steps back, Shift+F7 runs back to a
+                              breakpoint or watchpoint, F8 steps forward
+    --break <addr,...>        breakpoints for Shift+F7
+    --watch <loc,...>         watchpoints for Shift+F7: registers v0-vf or
+                              memory addresses";
+
+const DISASM_USAGE: &str = "Usage: chip8 disasm [--octo] [--sprites] <rom>
+
+Options:
+    --octo      print Octo syntax instead of classic mnemonics
+    --sprites   show data one byte per line with its bitmap";
+
+const ASM_USAGE: &str = "Usage: chip8 asm [-o <out.ch8>] [-s <out.sym>] <source>
+
+Options:
+    -o <file>   ROM output path (default: source with .ch8 extension)
+    -s <file>   symbol file path (default: source with .sym extension)";
+
+const INFO_USAGE: &str = "Usage: chip8 info [--romdb <file>] <rom>";
+
+const LINT_USAGE: &str = "Usage: chip8 lint <rom>";
+
+const BENCH_USAGE: &str = "Usage: chip8 bench <rom> [options]
+
+Options:
+    --frames <n>        frames to run (default: 300)
+    --platform <name>   quirks of chip8, schip or xochip
+    --ipf <n>           instructions per frame (default: 10)
+    --seed <n>          seed the random number generator
+    --config <file>     configuration file";
+
+const TEST_USAGE: &str = "Usage: chip8 test <rom> [options]
+
+Runs the ROM for a number of frames, prints a hash of the screen and
+exits with status 1 if it is not the expected one.
+
+Options:
+    --frames <n>        frames to run (default: 300)
+    --platform <name>   quirks of chip8, schip or xochip
+    --ipf <n>           instructions per frame (default: 10)
+    --seed <n>          seed the random number generator (default: 0)
+    --config <file>     configuration file
+    --expect <hash>     expected screen hash
+    --screen            also print the screen
+    --screenshot <file> save the screen as a .png, .pbm or .pgm file
+    --palette <theme>   palette for PNG screenshots (default: classic)
+    --scale <n>         image pixels per CHIP-8 pixel in PNG screenshots
+                        (default: 10)";
+
+const TRACE_DIFF_USAGE: &str = "Usage: chip8 trace-diff <rom> <reference-trace> [options]
+
+Options:
+    --platform <name>   quirks of chip8, schip or xochip
+    --config <file>     configuration file
+    --romdb <file>      ROM database to merge over the shipped one";
+
+/// Command-line arguments sorted into flags, options with a value and
+/// positional arguments.
+struct Args {
+    flags: Vec<String>,
+    values: Vec<(String, String)>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    /// `flags` take no value, `options` take one. Anything else starting
+    /// with `-` is an error.
+    fn parse(args: &[String], flags: &[&str], options: &[&str]) -> Result<Args, String> {
+        let mut parsed = Args {
+            flags: Vec::new(),
+            values: Vec::new(),
+            positional: Vec::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if flags.contains(&arg.as_str()) {
+                parsed.flags.push(arg.clone());
+            } else if options.contains(&arg.as_str()) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("{} needs a value", arg))?;
+                parsed.values.push((arg.clone(), value.clone()));
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                return Err(format!("Unknown option: {}", arg));
+            } else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Settings shared by the commands that run a ROM.
+    fn settings(&self) -> Result<Settings, String> {
+        let scale = self.parsed("--scale")?;
+        if scale == Some(0) {
+            return Err("Invalid value for --scale: must be at least 1".to_string());
+        }
+        Ok(Settings {
+            platform: self.parsed("--platform")?,
+            ipf: self.parsed("--ipf")?,
+            scale,
+            palette: self.parsed("--palette")?,
+            seed: self.parsed("--seed")?,
+            ..Settings::default()
+        })
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    /// The last value given for option `name`.
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn parsed<T: FromStr>(&self, name: &str) -> Result<Option<T>, String>
+    where
+        T::Err: ToString,
+    {
+        self.value(name)
+            .map(|v| {
+                v.parse()
+                    .map_err(|e: T::Err| format!("Invalid value for {}: {}", name, e.to_string()))
+            })
+            .transpose()
+    }
+
+    /// The positional arguments, which must be exactly `names`.
+    fn positional(&self, names: &[&str]) -> Result<&[String], String> {
+        if self.positional.len() < names.len() {
+            Err(format!("Missing {}", names[self.positional.len()]))
+        } else if self.positional.len() > names.len() {
+            Err(format!(
+                "Unexpected argument: {}",
+                self.positional[names.len()]
+            ))
+        } else {
+            Ok(&self.positional)
+        }
+    }
+}
+
+fn is_help(args: &[String]) -> bool {
+    args.iter().any(|a| a == "-h" || a == "--help")
+}
+
+fn usage_error(message: &str, usage: &str) -> ! {
+    eprintln!("{}\n\n{}", message, usage);
+    process::exit(2);
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+/// Parse the arguments of a subcommand, handling `--help` and usage errors.
+fn parse_or_exit(args: &[String], flags: &[&str], options: &[&str], usage: &str) -> Args {
+    if is_help(args) {
+        println!("{}", usage);
+        process::exit(0);
+    }
+    Args::parse(args, flags, options).unwrap_or_else(|e| usage_error(&e, usage))
+}
+
+/// Parse a comma separated `list`, which may be absent.
+fn list<T>(
+    list: Option<&str>,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Vec<T>, String> {
+    list.map_or(Ok(Vec::new()), |list| list.split(',').map(parse).collect())
+}
+
+fn read_rom(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| fail(&format!("Cannot read {}: {}", path, e)))
+}
+
+/// Where the trace goes and what is in it.
+pub struct TraceOptions {
+    pub path: String,
+    pub format: Format,
+    pub filter: Filter,
+}
+
+impl TraceOptions {
+    pub fn create(self) -> Result<Tracer, String> {
+        let path = self.path;
+        Tracer::create(&path, self.format, self.filter)
+            .map_err(|e| format!("Cannot create {}: {}", path, e))
+    }
+}
+
+/// Options of `chip8 run`.
+pub struct RunOptions {
+    pub rom: String,
+    /// Settings given on the command line, which override the
+    /// configuration file.
+    pub settings: Settings,
+    pub config: Option<PathBuf>,
+    pub romdb: Option<PathBuf>,
+    pub trace: Option<TraceOptions>,
+    pub profile: bool,
+    pub profile_csv: Option<String>,
+    pub profile_folded: Option<String>,
+    pub timeline: Option<String>,
+    pub coverage: Option<String>,
+    pub coverage_image: Option<String>,
+    pub quirk_detection: Option<detect::Mode>,
+    pub record_gif: Option<String>,
+    /// Instructions kept for stepping back, if any.
+    pub history: Option<usize>,
+    pub breakpoints: Vec<u16>,
+    pub watchpoints: Vec<Watchpoint>,
+}
+
+const RUN_FLAGS: [&str; 8] = [
+    "--fullscreen",
+    "--vblank",
+    "--mute",
+    "--trace-binary",
+    "--profile",
+    "--detect-quirks",
+    "--auto-quirks",
+    "--help",
+];
+
+const RUN_OPTIONS: [&str; 23] = [
+    "--config",
+    "--platform",
+    "--ipf",
+    "--scale",
+    "--palette",
+    "--anti-flicker",
+    "--decay",
+    "--filter",
+    "--seed",
+    "--keymap",
+    "--romdb",
+    "--record-gif",
+    "--trace",
+    "--trace-range",
+    "--trace-class",
+    "--profile-csv",
+    "--profile-folded",
+    "--timeline",
+    "--coverage",
+    "--coverage-image",
+    "--history",
+    "--break",
+    "--watch",
+];
+
+impl RunOptions {
+    pub fn parse(args: &[String]) -> Result<RunOptions, String> {
+        let args = Args::parse(args, &RUN_FLAGS, &RUN_OPTIONS)?;
+        let rom = args.positional(&["ROM"])?[0].clone();
+        let trace = match args.value("--trace") {
+            Some(path) => Some(TraceOptions {
+                path: path.to_string(),
+                format: if args.flag("--trace-binary") {
+                    Format::Binary
+                } else {
+                    Format::Text
+                },
+                filter: Filter {
+                    range: args
+                        .value("--trace-range")
+                        .map(trace::parse_range)
+                        .transpose()?,
+                    classes: match args.value("--trace-class") {
+                        Some(classes) => trace::parse_classes(classes)?,
+                        None => Vec::new(),
+                    },
+                },
+            }),
+            None => None,
+        };
+        let quirk_detection = if args.flag("--auto-quirks") {
+            Some(detect::Mode::Switch)
+        } else if args.flag("--detect-quirks") {
+            Some(detect::Mode::Suggest)
+        } else {
+            None
+        };
+        Ok(RunOptions {
+            rom,
+            settings: Settings {
+                keymap: args.value("--keymap").map(PathBuf::from),
+                fullscreen: Some(true).filter(|_| args.flag("--fullscreen")),
+                anti_flicker: args.parsed("--anti-flicker")?,
+                decay: args.parsed("--decay")?,
+                filter: args.parsed("--filter")?,
+                vblank: Some(true).filter(|_| args.flag("--vblank")),
+                mute: Some(true).filter(|_| args.flag("--mute")),
+                ..args.settings()?
+            },
+            config: args.value("--config").map(PathBuf::from),
+            romdb: args.value("--romdb").map(PathBuf::from),
+            trace,
+            profile: args.flag("--profile"),
+            profile_csv: args.value("--profile-csv").map(String::from),
+            profile_folded: args.value("--profile-folded").map(String::from),
+            timeline: args.value("--timeline").map(String::from),
+            coverage: args.value("--coverage").map(String::from),
+            coverage_image: args.value("--coverage-image").map(String::from),
+            quirk_detection,
+            record_gif: args.value("--record-gif").map(String::from),
+            history: args.parsed("--history")?,
+            breakpoints: list(args.value("--break"), trace::parse_address)?,
+            watchpoints: list(args.value("--watch"), str::parse)?,
+        })
+    }
+
+    /// Parse `chip8 run` arguments, exiting with a message on `--help` or
+    /// a usage error.
+    pub fn parse_or_exit(args: &[String]) -> RunOptions {
+        if is_help(args) {
+            println!("{}", RUN_USAGE);
+            process::exit(0);
+        }
+        RunOptions::parse(args).unwrap_or_else(|e| usage_error(&e, RUN_USAGE))
+    }
+}
+
+/// Load `rom` and work out its settings: `cli` over the ROM's section of
+/// the configuration file, over its ROM database entry, over the global
+/// configuration. The quirks and seed are applied; the entry is returned
+/// for its title and key mapping.
+pub fn load(
+    rom: &str,
+    cli: Settings,
+    config: Option<&Path>,
+    romdb: Option<&Path>,
+) -> Result<(Chip, Settings, Entry), String> {
+    let mut chip8 = Chip::new();
+    chip8.load_rom(rom)?;
+    let romdb = Database::with_user(romdb).unwrap_or_else(|e| {
+        eprintln!("Ignoring user ROM database: {}", e);
+        Database::builtin()
+    });
+    let entry = romdb.lookup(chip8.rom()).cloned().unwrap_or_default();
+    let config = Config::load(config)?;
+    let settings = cli.or(config.for_rom(&romdb::sha1_hex(chip8.rom()), &entry));
+    chip8.set_quirks(settings.quirks(chip8.quirks()));
+    if let Some(seed) = settings.seed {
+        chip8.set_seed(seed);
+    }
+    Ok((chip8, settings, entry))
+}
+
+/// Run `frames` frames of `ipf` instructions, ticking the timers after each.
+fn run_frames(chip8: &mut Chip, frames: u32, ipf: u32) {
+    for _ in 0..frames {
+        for _ in 0..ipf {
+            chip8.step();
+        }
+        chip8.tick_timers();
+    }
+}
+
+/// Load the ROM for `bench` and `test`. Returns the instructions per frame
+/// and the number of frames to run.
+fn load_headless(args: &Args, default_seed: Option<u64>) -> (Chip, Settings, u32) {
+    let rom = &args.positional(&["ROM"]).unwrap_or_else(|e| fail(&e))[0];
+    let mut cli = args.settings().unwrap_or_else(|e: String| fail(&e));
+    cli.seed = cli.seed.or(default_seed);
+    let frames = args.parsed("--frames").unwrap_or_else(|e| fail(&e));
+    let config = args.value("--config").map(Path::new);
+    let (chip8, settings, _) = load(rom, cli, config, None).unwrap_or_else(|e: String| fail(&e));
+    (chip8, settings, frames.unwrap_or(DEFAULT_FRAMES))
+}
+
+/// `chip8 bench <rom>`: run without a window and report the speed.
+pub fn bench(args: &[String]) {
+    let args = parse_or_exit(
+        args,
+        &[],
+        &["--frames", "--platform", "--ipf", "--seed", "--config"],
+        BENCH_USAGE,
+    );
+    let (mut chip8, settings, frames) = load_headless(&args, None);
+    let started = Instant::now();
+    run_frames(&mut chip8, frames, settings.ipf());
+    let seconds = started.elapsed().as_secs_f64();
+    let instructions = chip8.cycles();
+    println!(
+        "{} instructions in {} frames, {:.3} s: {:.2} million instructions/s, {:.0}x real time",
+        instructions,
+        frames,
+        seconds,
+        instructions as f64 / seconds / 1e6,
+        frames as f64 / 60.0 / seconds
+    );
+}
+
+/// The framebuffer as text, `#` for lit pixels.
+fn screen(chip8: &Chip) -> String {
+    let mut text = String::new();
+    for row in chip8.framebuffer().chunks(chip8.resolution().0) {
+        text.extend(row.iter().map(|p| if *p != 0 { '#' } else { '.' }));
+        text.push('\n');
+    }
+    text
+}
+
+/// `chip8 test <rom>`: run without a window and compare the screen hash.
+pub fn test(args: &[String]) {
+    let args = parse_or_exit(
+        args,
+        &["--screen"],
+        &[
+            "--frames",
+            "--platform",
+            "--ipf",
+            "--seed",
+            "--config",
+            "--expect",
+            "--screenshot",
+            "--palette",
+            "--scale",
+        ],
+        TEST_USAGE,
+    );
+    let expect = args
+        .value("--expect")
+        .map(|h| u64::from_str_radix(h.trim_start_matches("0x"), 16))
+        .transpose()
+        .unwrap_or_else(|e| fail(&format!("Invalid value for --expect: {}", e)));
+    let (mut chip8, settings, frames) = load_headless(&args, Some(0));
+    run_frames(&mut chip8, frames, settings.ipf());
+    if args.flag("--screen") {
+        print!("{}", screen(&chip8));
+    }
+    if let Some(path) = args.value("--screenshot") {
+        chip8
+            .save_screenshot(
+                Path::new(path),
+                &settings.palette(),
+                settings.scale() as usize,
+            )
+            .unwrap_or_else(|e| fail(&e));
+    }
+    let hash = framebuffer_hash(chip8.framebuffer());
+    println!("screen hash: {:016x}", hash);
+    match expect {
+        Some(expected) if expected != hash => {
+            fail(&format!("expected {:016x}", expected));
+        }
+        _ => (),
+    }
+}
+
+/// `chip8 disasm <rom>`: print a ROM as assembly.
+pub fn disasm(args: &[String]) {
+    let args = parse_or_exit(args, &["--octo", "--sprites"], &[], DISASM_USAGE);
+    let rom = &args
+        .positional(&["ROM"])
+        .unwrap_or_else(|e| usage_error(&e, DISASM_USAGE))[0];
+    let options = Options {
+        syntax: if args.flag("--octo") {
+            Syntax::Octo
+        } else {
+            Syntax::Cowgod
+        },
+        sprites: args.flag("--sprites"),
+    };
+    print!("{}", disassemble(&read_rom(rom), &options));
+}
+
+/// `chip8 asm <source>`: assemble a ROM and its symbol file.
+pub fn asm(args: &[String]) {
+    let args = parse_or_exit(args, &[], &["-o", "-s"], ASM_USAGE);
+    let source = PathBuf::from(
+        &args
+            .positional(&["source file"])
+            .unwrap_or_else(|e| usage_error(&e, ASM_USAGE))[0],
+    );
+    let rom_path = args
+        .value("-o")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source.with_extension("ch8"));
+    let symbol_path = args
+        .value("-s")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| source.with_extension("sym"));
+    let program = assemble_file(&source).unwrap_or_else(|e| fail(&e.to_string()));
+    for (path, data) in [
+        (&rom_path, program.rom.clone()),
+        (&symbol_path, program.symbol_file().into_bytes()),
+    ] {
+        if let Err(e) = fs::write(path, data) {
+            fail(&format!("Cannot write {}: {}", path.display(), e));
+        }
+    }
+}
+
+/// `chip8 lint <rom>`: list platform-dependent instructions and suggest a
+/// platform profile.
+pub fn lint(args: &[String]) {
+    let args = parse_or_exit(args, &[], &[], LINT_USAGE);
+    let rom = &args
+        .positional(&["ROM"])
+        .unwrap_or_else(|e| usage_error(&e, LINT_USAGE))[0];
+    print!("{}", lint::lint(&read_rom(rom)));
+}
+
+/// `chip8 info <rom>`: print the ROM's hash and what the ROM database
+/// knows about it.
+pub fn info(args: &[String]) {
+    let args = parse_or_exit(args, &[], &["--romdb"], INFO_USAGE);
+    let path = &args
+        .positional(&["ROM"])
+        .unwrap_or_else(|e| usage_error(&e, INFO_USAGE))[0];
+    let rom = read_rom(path);
+    println!("sha1: {}", romdb::sha1_hex(&rom));
+    println!("size: {} bytes", rom.len());
+    let romdb = Database::with_user(args.value("--romdb").map(Path::new)).unwrap_or_else(|e| {
+        eprintln!("Ignoring user ROM database: {}", e);
+        Database::builtin()
+    });
+    let entry = match romdb.lookup(&rom) {
+        Some(entry) => entry,
+        None => return println!("not in the ROM database"),
+    };
+    let fields = [
+        ("title", entry.title.clone()),
+        ("author", entry.author.clone()),
+        ("platform", entry.platform.map(|p| p.to_string())),
+        ("tick rate", entry.tick_rate.map(|t| t.to_string())),
+        ("description", entry.description.clone()),
+    ];
+    for (name, value) in fields.iter() {
+        if let Some(value) = value {
+            println!("{}: {}", name, value);
+        }
+    }
+    for (key, host) in entry.keys.iter() {
+        println!("key {}: {}", key, host);
+    }
+}
+
+/// `chip8 trace-diff <rom> <reference>`: run the ROM in lockstep with a
+/// reference trace and report the first divergence.
+pub fn trace_diff(args: &[String]) {
+    let args = parse_or_exit(
+        args,
+        &[],
+        &["--platform", "--config", "--romdb"],
+        TRACE_DIFF_USAGE,
+    );
+    let paths = args
+        .positional(&["ROM", "reference trace"])
+        .unwrap_or_else(|e| usage_error(&e, TRACE_DIFF_USAGE));
+    let reference = trace_diff::load(Path::new(&paths[1])).unwrap_or_else(|e| fail(&e));
+    let cli = args.settings().unwrap_or_else(|e| fail(&e));
+    let (mut chip8, _, _) = load(
+        &paths[0],
+        cli,
+        args.value("--config").map(Path::new),
+        args.value("--romdb").map(Path::new),
+    )
+    .unwrap_or_else(|e| fail(&e));
+    match trace_diff::lockstep(&mut chip8, &reference) {
+        Ok(n) => println!("{} instructions match the reference", n),
+        Err(divergence) => {
+            print!("{}", divergence);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::flicker;
+    use crate::platform::Platform;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn run_options() {
+        let options = RunOptions::parse(&args(
+            "game.ch8 --platform schip --ipf 20 --palette #000000,ff8000 --anti-flicker blend --filter scale2x,crt --vblank --mute --trace t.log --trace-class 8,D",
+        ))
+        .unwrap();
+        assert_eq!(options.rom, "game.ch8");
+        let settings = &options.settings;
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+        assert_eq!(settings.ipf, Some(20));
+        assert_eq!(settings.scale, None);
+        assert_eq!(settings.palette.unwrap().colour(1), 0xFF8000);
+        assert_eq!((settings.mute, settings.fullscreen), (Some(true), None));
+        assert_eq!(settings.anti_flicker, Some(flicker::Mode::Blend));
+        assert_eq!(settings.filter.as_ref().unwrap().0.len(), 2);
+        assert_eq!(settings.vblank, Some(true));
+        let trace = options.trace.unwrap();
+        assert_eq!(trace.path, "t.log");
+        assert_eq!(trace.filter.classes, vec![8, 0xD]);
+        assert_eq!(options.history, None);
+
+        let options = RunOptions::parse(&args(
+            "game.ch8 --history 1000 --break 0x200,0x2A0 --watch v3,0x3A0",
+        ))
+        .unwrap();
+        assert_eq!(options.history, Some(1000));
+        assert_eq!(options.breakpoints, vec![0x200, 0x2A0]);
+        assert_eq!(
+            options.watchpoints,
+            vec![Watchpoint::Register(3), Watchpoint::Memory(0x3A0)]
+        );
+    }
+
+    #[test]
+    fn run_errors() {
+        let error = |s| RunOptions::parse(&args(s)).err().unwrap();
+        assert_eq!(error("--mute"), "Missing ROM");
+        assert_eq!(error("a.ch8 b.ch8"), "Unexpected argument: b.ch8");
+        assert_eq!(error("a.ch8 --speed 3"), "Unknown option: --speed");
+        assert_eq!(error("a.ch8 --ipf"), "--ipf needs a value");
+        assert!(error("a.ch8 --ipf fast").starts_with("Invalid value for --ipf"));
+        assert!(error("a.ch8 --platform nes").contains("unknown platform"));
+        assert!(error("a.ch8 --palette fff,000").contains("invalid colour"));
+        assert!(error("a.ch8 --palette sepia").contains("unknown theme"));
+    }
+}
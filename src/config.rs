@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::filter::Chain;
+use crate::flicker::{self, DEFAULT_DECAY};
+use crate::palette::Palette;
+use crate::platform::{Platform, QuirkOverrides, Quirks};
+use crate::romdb::Entry;
+
+pub const DEFAULT_TICK_RATE: u32 = 10;
+pub const DEFAULT_SCALE: u32 = 10;
+pub const DEFAULT_VOLUME: f32 = 0.1;
+
+/// Frontend and core settings. Every field is optional so that settings
+/// from the command line, the configuration file and the ROM database can
+/// be layered with `or`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    pub platform: Option<Platform>,
+    #[serde(default)]
+    pub quirks: QuirkOverrides,
+    /// Instructions per 60 Hz frame.
+    pub ipf: Option<u32>,
+    pub scale: Option<u32>,
+    pub fullscreen: Option<bool>,
+    pub palette: Option<Palette>,
+    pub anti_flicker: Option<flicker::Mode>,
+    /// Brightness kept each frame by pixels fading out, from 0 to 1.
+    pub decay: Option<f32>,
+    /// Software filters applied to the screen, in order.
+    pub filter: Option<Chain>,
+    /// Draw the screen once per 60 Hz frame instead of after every
+    /// instruction.
+    pub vblank: Option<bool>,
+    pub seed: Option<u64>,
+    pub keymap: Option<PathBuf>,
+    pub mute: Option<bool>,
+    /// Beep volume from 0 to 1.
+    pub volume: Option<f32>,
+    /// Where screenshots and recordings go.
+    pub save_dir: Option<PathBuf>,
+}
+
+impl Settings {
+    /// These settings, with the ones they leave unset taken from `fallback`.
+    pub fn or(self, fallback: Settings) -> Settings {
+        Settings {
+            platform: self.platform.or(fallback.platform),
+            quirks: self.quirks.or(fallback.quirks),
+            ipf: self.ipf.or(fallback.ipf),
+            scale: self.scale.or(fallback.scale),
+            fullscreen: self.fullscreen.or(fallback.fullscreen),
+            palette: self.palette.or(fallback.palette),
+            anti_flicker: self.anti_flicker.or(fallback.anti_flicker),
+            decay: self.decay.or(fallback.decay),
+            filter: self.filter.or(fallback.filter),
+            vblank: self.vblank.or(fallback.vblank),
+            seed: self.seed.or(fallback.seed),
+            keymap: self.keymap.or(fallback.keymap),
+            mute: self.mute.or(fallback.mute),
+            volume: self.volume.or(fallback.volume),
+            save_dir: self.save_dir.or(fallback.save_dir),
+        }
+    }
+
+    /// What a ROM database entry says about the settings.
+    pub fn from_entry(entry: &Entry) -> Settings {
+        Settings {
+            platform: entry.platform,
+            quirks: entry.quirks,
+            ipf: entry.tick_rate,
+            ..Settings::default()
+        }
+    }
+
+    /// The platform's quirks, or `default` without one, with the quirk
+    /// overrides applied.
+    pub fn quirks(&self, default: Quirks) -> Quirks {
+        let mut quirks = self.platform.map_or(default, |p| p.quirks());
+        self.quirks.apply(&mut quirks);
+        quirks
+    }
+
+    pub fn ipf(&self) -> u32 {
+        self.ipf.unwrap_or(DEFAULT_TICK_RATE)
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale.unwrap_or(DEFAULT_SCALE)
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette.unwrap_or_default()
+    }
+
+    pub fn anti_flicker(&self) -> flicker::Mode {
+        self.anti_flicker.unwrap_or(flicker::Mode::Off)
+    }
+
+    pub fn decay(&self) -> f32 {
+        self.decay.unwrap_or(DEFAULT_DECAY).clamp(0.0, 1.0)
+    }
+
+    pub fn vblank(&self) -> bool {
+        self.vblank.unwrap_or(false)
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume.unwrap_or(DEFAULT_VOLUME).clamp(0.0, 1.0)
+    }
+
+    pub fn save_dir(&self) -> PathBuf {
+        self.save_dir.clone().unwrap_or_else(|| PathBuf::from("."))
+    }
+}
+
+/// The user's configuration file: global settings at the top level and
+/// per-ROM overrides in `[rom.<sha1>]` tables.
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub settings: Settings,
+    rom: HashMap<String, Settings>,
+}
+
+impl Config {
+    /// Read `path`, or the user's configuration file if there is one.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path
+            .map(Path::to_path_buf)
+            .or_else(|| user_path().filter(|p| p.exists()))
+        {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn parse(toml: &str) -> Result<Config, String> {
+        // Split off the `rom` table by hand: serde cannot flatten the
+        // settings into the top level and still reject unknown keys.
+        let mut table: toml::Table = toml.parse().map_err(|e: toml::de::Error| e.to_string())?;
+        let rom = match table.remove("rom") {
+            Some(rom) => rom.try_into().map_err(|e: toml::de::Error| e.to_string())?,
+            None => HashMap::new(),
+        };
+        let settings = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| e.to_string())?;
+        Ok(Config { settings, rom })
+    }
+
+    /// Settings for the ROM with hash `sha1` and ROM database entry
+    /// `entry`. From highest to lowest priority: the ROM's section, the
+    /// database entry, the global settings.
+    pub fn for_rom(&self, sha1: &str, entry: &Entry) -> Settings {
+        let rom = self
+            .rom
+            .iter()
+            .find(|(hash, _)| hash.eq_ignore_ascii_case(sha1))
+            .map(|(_, settings)| settings.clone())
+            .unwrap_or_default();
+        rom.or(Settings::from_entry(entry))
+            .or(self.settings.clone())
+    }
+}
+
+/// `~/.config/chip8` or the platform's equivalent, where the user's
+/// configuration, keymap and ROM database live.
+pub fn dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("chip8"))
+}
+
+/// `config.toml` in `dir()`.
+pub fn user_path() -> Option<PathBuf> {
+    dir().map(|dir| dir.join("config.toml"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layers() {
+        let config = Config::parse(
+            r#"
+            platform = "chip8"
+            ipf = 12
+            scale = 8
+            palette = "amber"
+            anti_flicker = "fade"
+            vblank = true
+            save_dir = "/tmp/shots"
+
+            [rom.abc]
+            ipf = 30
+            quirks = { clip_sprites = false }
+            "#,
+        )
+        .unwrap();
+        let entry = Entry {
+            platform: Some(Platform::SuperChip),
+            tick_rate: Some(20),
+            ..Entry::default()
+        };
+        let settings = config.for_rom("ABC", &entry);
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+        assert_eq!(settings.ipf(), 30);
+        assert_eq!(settings.scale(), 8);
+        assert_eq!(settings.palette().colour(1), 0xFFB000);
+        let quirks = settings.quirks(Quirks::default());
+        assert!(quirks.jump_uses_vx && !quirks.clip_sprites);
+
+        let cli = Settings {
+            ipf: Some(5),
+            ..Settings::default()
+        };
+        let settings = cli.or(config.for_rom("def", &Entry::default()));
+        assert_eq!(settings.ipf(), 5);
+        assert_eq!(settings.platform, Some(Platform::Chip8));
+        assert_eq!(settings.save_dir(), PathBuf::from("/tmp/shots"));
+        assert_eq!(settings.volume(), DEFAULT_VOLUME);
+        assert_eq!(settings.anti_flicker(), flicker::Mode::Fade);
+        assert_eq!(settings.decay(), DEFAULT_DECAY);
+        assert!(settings.vblank());
+        assert!(!Settings::default().vblank());
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(Config::parse("speed = 3").is_err());
+        assert!(Config::parse("palette = \"sepia\"").is_err());
+        assert!(Config::parse("[rom.abc]\nplatform = \"nes\"").is_err());
+    }
+}
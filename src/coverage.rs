@@ -0,0 +1,214 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::instruction::{decode_at, Instruction};
+use crate::memory::START_ADDRESS;
+
+pub const EXECUTED: u8 = 1;
+pub const SPRITE: u8 = 2;
+pub const LOADED: u8 = 4;
+pub const WRITTEN: u8 = 8;
+
+const ADDRESS_SPACE: usize = 0x1000;
+const DATA_PER_LINE: usize = 8;
+const IMAGE_COLUMNS: usize = 64;
+const IMAGE_SCALE: usize = 4;
+
+/// How every byte of memory was used: executed as an instruction, read as
+/// sprite data by `Dxyn`, read by `Fx65`, or written by `Fx55`/`Fx33`.
+pub struct Coverage {
+    flags: Vec<u8>,
+    starts: Vec<bool>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            flags: vec![0; ADDRESS_SPACE],
+            starts: vec![false; ADDRESS_SPACE],
+        }
+    }
+
+    fn mark(&mut self, from: u16, len: usize, flag: u8) {
+        for address in from as usize..from as usize + len {
+            self.flags[address % ADDRESS_SPACE] |= flag;
+        }
+    }
+
+    /// Record `instruction` at `pc`, about to execute with `index_register`.
+    pub fn record(&mut self, pc: u16, instruction: &Instruction, index_register: u16) {
+        self.starts[pc as usize % ADDRESS_SPACE] = true;
+        self.mark(pc, instruction.size() as usize, EXECUTED);
+        match *instruction {
+            Instruction::Draw(_, _, n) => self.mark(index_register, n as usize, SPRITE),
+            Instruction::Load(x) => self.mark(index_register, x as usize + 1, LOADED),
+            Instruction::Store(x) => self.mark(index_register, x as usize + 1, WRITTEN),
+            Instruction::Bcd(_) => self.mark(index_register, 3, WRITTEN),
+            _ => (),
+        }
+    }
+
+    pub fn flags(&self, address: u16) -> u8 {
+        self.flags[address as usize % ADDRESS_SPACE]
+    }
+
+    /// ROM listing with one line per executed instruction and data rows of up
+    /// to eight bytes for everything else. `rom` is the memory from 0x200 on.
+    pub fn listing(&self, rom: &[u8]) -> String {
+        let mut memory = vec![0u8; START_ADDRESS];
+        memory.extend_from_slice(rom);
+        let end = memory.len();
+        let mut out = String::from("; X executed, S sprite data, L read by Fx65, W written\n");
+
+        let mut address = START_ADDRESS;
+        while address < end {
+            if self.starts[address] {
+                let instruction = decode_at(&memory, address);
+                let size = (instruction.size() as usize).min(end - address);
+                let _ = writeln!(
+                    out,
+                    "{:#05x}  {:<11}  {}  {}",
+                    address,
+                    hex_bytes(&memory[address..address + size]),
+                    flag_string(self.flags[address]),
+                    instruction
+                );
+                address += size;
+                continue;
+            }
+            let flags = self.flags[address];
+            let mut len = 1;
+            while len < DATA_PER_LINE
+                && address + len < end
+                && !self.starts[address + len]
+                && self.flags[address + len] == flags
+            {
+                len += 1;
+            }
+            let _ = writeln!(
+                out,
+                "{:#05x}  {:<23}  {}",
+                address,
+                hex_bytes(&memory[address..address + len]),
+                flag_string(flags)
+            );
+            address += len;
+        }
+        out
+    }
+
+    /// Binary PPM with one 4x4 cell per ROM byte, 64 bytes per row.
+    pub fn write_image<W: Write>(&self, rom_len: usize, out: &mut W) -> io::Result<()> {
+        let rows = rom_len.div_ceil(IMAGE_COLUMNS).max(1);
+        let width = IMAGE_COLUMNS * IMAGE_SCALE;
+        let height = rows * IMAGE_SCALE;
+        write!(out, "P6\n{} {}\n255\n", width, height)?;
+        for y in 0..height {
+            for x in 0..width {
+                let offset = (y / IMAGE_SCALE) * IMAGE_COLUMNS + x / IMAGE_SCALE;
+                let colour = if offset < rom_len {
+                    colour(self.flags[START_ADDRESS + offset])
+                } else {
+                    [0, 0, 0]
+                };
+                out.write_all(&colour)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save_listing(&self, rom: &[u8], path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(self.listing(rom).as_bytes())?;
+        out.flush()
+    }
+
+    pub fn save_image(&self, rom_len: usize, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_image(rom_len, &mut out)?;
+        out.flush()
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    bytes.join(" ")
+}
+
+fn flag_string(flags: u8) -> String {
+    [
+        (EXECUTED, 'X'),
+        (SPRITE, 'S'),
+        (LOADED, 'L'),
+        (WRITTEN, 'W'),
+    ]
+    .iter()
+    .map(|(flag, c)| if flags & flag != 0 { *c } else { '-' })
+    .collect()
+}
+
+/// Self-modified code magenta, code green, sprites blue, written data red,
+/// `Fx65` tables yellow, untouched bytes grey.
+fn colour(flags: u8) -> [u8; 3] {
+    if flags & EXECUTED != 0 && flags & WRITTEN != 0 {
+        [0xE0, 0x40, 0xE0]
+    } else if flags & EXECUTED != 0 {
+        [0x40, 0xC0, 0x40]
+    } else if flags & SPRITE != 0 {
+        [0x40, 0x80, 0xF0]
+    } else if flags & WRITTEN != 0 {
+        [0xE0, 0x40, 0x40]
+    } else if flags & LOADED != 0 {
+        [0xE0, 0xD0, 0x40]
+    } else {
+        [0x30, 0x30, 0x30]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::decode;
+
+    #[test]
+    fn marks_and_listing() {
+        // LD I, 0x208; DRW V0, V0, 2; LD [I], V1; data F0 90
+        let rom = [0xA2, 0x08, 0xD0, 0x02, 0xF1, 0x55, 0x00, 0x00, 0xF0, 0x90];
+        let mut coverage = Coverage::new();
+        coverage.record(0x200, &decode(0xA208), 0);
+        coverage.record(0x202, &decode(0xD002), 0x208);
+        coverage.record(0x204, &decode(0xF155), 0x208);
+        assert_eq!(coverage.flags(0x200), EXECUTED);
+        assert_eq!(coverage.flags(0x208), SPRITE | WRITTEN);
+        assert_eq!(coverage.flags(0x20A), 0);
+
+        let listing = coverage.listing(&rom);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[1], "0x200  A2 08        X---  LD I, 0x208");
+        assert_eq!(lines[4], "0x206  00 00                    ----");
+        assert_eq!(lines[5], "0x208  F0 90                    -S-W");
+    }
+
+    #[test]
+    fn image() {
+        let mut coverage = Coverage::new();
+        coverage.record(0x200, &decode(0x1200), 0);
+        let mut ppm = Vec::new();
+        coverage.write_image(3, &mut ppm).unwrap();
+        let header = b"P6\n256 4\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 256 * 4 * 3);
+        assert_eq!(&ppm[header.len()..header.len() + 3], &[0x40, 0xC0, 0x40]);
+        let untouched = header.len() + 2 * IMAGE_SCALE * 3;
+        assert_eq!(&ppm[untouched..untouched + 3], &[0x30, 0x30, 0x30]);
+        let outside = header.len() + 3 * IMAGE_SCALE * 3;
+        assert_eq!(&ppm[outside..outside + 3], &[0, 0, 0]);
+    }
+}
@@ -0,0 +1,394 @@
+use crate::instruction::Instruction;
+use crate::platform::{Platform, Quirks, PLATFORMS};
+use crate::trace::Record;
+
+const ADDRESS_SPACE: usize = 0x1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Only log which profile looks right.
+    Suggest,
+    /// Log and switch to it.
+    Switch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quirk {
+    ShiftUsesVy,
+    MemoryIncrementsI,
+    ClipSprites,
+}
+
+impl Quirk {
+    fn name(&self) -> &'static str {
+        match self {
+            Quirk::ShiftUsesVy => "shift_uses_vy",
+            Quirk::MemoryIncrementsI => "memory_increments_i",
+            Quirk::ClipSprites => "clip_sprites",
+        }
+    }
+
+    fn get(&self, quirks: &Quirks) -> bool {
+        match self {
+            Quirk::ShiftUsesVy => quirks.shift_uses_vy,
+            Quirk::MemoryIncrementsI => quirks.memory_increments_i,
+            Quirk::ClipSprites => quirks.clip_sprites,
+        }
+    }
+
+    fn set(&self, quirks: &mut Quirks, value: bool) {
+        match self {
+            Quirk::ShiftUsesVy => quirks.shift_uses_vy = value,
+            Quirk::MemoryIncrementsI => quirks.memory_increments_i = value,
+            Quirk::ClipSprites => quirks.clip_sprites = value,
+        }
+    }
+}
+
+/// Outcome of a new piece of evidence: what to log, and the quirks to run
+/// with from now on when switching.
+#[derive(Debug, PartialEq)]
+pub struct Detection {
+    pub message: String,
+    pub quirks: Option<Quirks>,
+}
+
+/// Watches executed instructions for signs that the quirk settings do not
+/// match the ROM. Attach with `Chip::enable_quirk_detection`.
+pub struct Detector {
+    mode: Mode,
+    written_registers: u16,
+    written_memory: Vec<bool>,
+    i_advanced: bool,
+    required: Option<Platform>,
+    evidence: Vec<(Quirk, bool)>,
+    log: Vec<String>,
+}
+
+fn written_register(instruction: &Instruction) -> Option<u8> {
+    match *instruction {
+        Instruction::LoadByte(x, _)
+        | Instruction::AddByte(x, _)
+        | Instruction::LoadReg(x, _)
+        | Instruction::Or(x, _)
+        | Instruction::And(x, _)
+        | Instruction::Xor(x, _)
+        | Instruction::AddReg(x, _)
+        | Instruction::Sub(x, _)
+        | Instruction::ShiftRight(x, _)
+        | Instruction::SubN(x, _)
+        | Instruction::ShiftLeft(x, _)
+        | Instruction::Random(x, _)
+        | Instruction::LoadDelay(x)
+        | Instruction::WaitKey(x) => Some(x),
+        _ => None,
+    }
+}
+
+impl Detector {
+    pub fn new(mode: Mode) -> Detector {
+        Detector {
+            mode,
+            written_registers: 0,
+            written_memory: vec![false; ADDRESS_SPACE],
+            i_advanced: false,
+            required: None,
+            evidence: Vec::new(),
+            log: Vec::new(),
+        }
+    }
+
+    /// Everything reported so far.
+    pub fn log(&self) -> &[String] {
+        &self.log
+    }
+
+    /// Inspect `instruction`, about to execute in `state`. `rom_end` is the
+    /// first address past the loaded program and `resolution` the screen's
+    /// current width and height.
+    pub fn observe(
+        &mut self,
+        state: &Record,
+        instruction: &Instruction,
+        memory: &[u8],
+        rom_end: usize,
+        quirks: Quirks,
+        (width, height): (usize, usize),
+    ) -> Option<Detection> {
+        let i = state.index_register as usize;
+        let written = |mask: u16, reg: u8| mask & (1 << reg) != 0;
+        let mut found = None;
+
+        if instruction.is_xochip() && self.required != Some(Platform::XoChip) {
+            self.required = Some(Platform::XoChip);
+            found = Some(format!("XO-CHIP opcode {}", instruction));
+        } else if instruction.is_schip() && self.required.is_none() {
+            self.required = Some(Platform::SuperChip);
+            found = Some(format!("SUPER-CHIP opcode {}", instruction));
+        }
+        let mut quirk = None;
+
+        match *instruction {
+            Instruction::ShiftRight(x, y) | Instruction::ShiftLeft(x, y)
+                if x != y && state.registers[x as usize] != state.registers[y as usize] =>
+            {
+                let (x_set, y_set) = (
+                    written(self.written_registers, x),
+                    written(self.written_registers, y),
+                );
+                if quirks.shift_uses_vy && x_set && !y_set {
+                    quirk = Some((
+                        Quirk::ShiftUsesVy,
+                        false,
+                        format!("{} shifts V{:X}, which the ROM never set", instruction, y),
+                    ));
+                } else if !quirks.shift_uses_vy && !x_set && y_set {
+                    quirk = Some((
+                        Quirk::ShiftUsesVy,
+                        true,
+                        format!(
+                            "{} shifts V{:X} in place, but only V{:X} was set",
+                            instruction, x, y
+                        ),
+                    ));
+                }
+            }
+            Instruction::Draw(..) | Instruction::Load(_) if self.i_advanced => {
+                let len = match *instruction {
+                    Instruction::Draw(_, _, n) => n as usize,
+                    Instruction::Load(x) => x as usize + 1,
+                    _ => 0,
+                };
+                let unused = (i..i + len)
+                    .all(|a| a >= rom_end && a < ADDRESS_SPACE && !self.written_memory[a]);
+                if quirks.memory_increments_i && len > 0 && unused {
+                    quirk = Some((
+                        Quirk::MemoryIncrementsI,
+                        false,
+                        format!(
+                            "{} reads unused memory at {:#05x}, where FX55/FX65 left I",
+                            instruction, i
+                        ),
+                    ));
+                }
+            }
+            _ => (),
+        }
+
+        if let Instruction::Draw(x, y, n) = *instruction {
+            let x = state.registers[x as usize] as usize % width;
+            let y = state.registers[y as usize] as usize % height;
+            let crosses = (0..n as usize).any(|row| {
+                let byte = memory.get(i + row).copied().unwrap_or(0);
+                let right = x + 8 > width && byte << (width - x).min(7) != 0;
+                let below = y + row >= height && byte != 0;
+                right || below
+            });
+            if crosses && !quirks.clip_sprites && self.required != Some(Platform::XoChip) {
+                quirk = Some((
+                    Quirk::ClipSprites,
+                    true,
+                    format!("sprite at ({}, {}) wraps around the screen edge", x, y),
+                ));
+            }
+        }
+
+        self.track(instruction, i);
+
+        if let Some((quirk, value, reason)) = quirk {
+            if !self.evidence.iter().any(|(q, _)| *q == quirk) {
+                self.evidence.push((quirk, value));
+                found = Some(format!("{} suggests {} = {}", reason, quirk.name(), value));
+            }
+        }
+        found.map(|reason| self.detect(state.pc, reason, quirks))
+    }
+
+    fn track(&mut self, instruction: &Instruction, i: usize) {
+        if let Some(x) = written_register(instruction) {
+            self.written_registers |= 1 << x;
+        }
+        let mut mark = |from: usize, len: usize| {
+            for a in from..(from + len).min(ADDRESS_SPACE) {
+                self.written_memory[a] = true;
+            }
+        };
+        match *instruction {
+            Instruction::Store(x) => mark(i, x as usize + 1),
+            Instruction::Bcd(_) => mark(i, 3),
+            Instruction::Load(x) => self.written_registers |= u16::MAX >> (15 - x),
+            _ => (),
+        }
+        match instruction {
+            Instruction::Store(_) | Instruction::Load(_) => self.i_advanced = true,
+            Instruction::LoadI(_)
+            | Instruction::LoadILong(_)
+            | Instruction::LoadFont(_)
+            | Instruction::LoadBigFont(_) => self.i_advanced = false,
+            _ => (),
+        }
+    }
+
+    /// Pick the profile that fits all evidence so far and describe it.
+    fn detect(&mut self, pc: u16, reason: String, current: Quirks) -> Detection {
+        let fits = |quirks: &Quirks| self.evidence.iter().all(|(q, v)| q.get(quirks) == *v);
+        let platform = self
+            .required
+            .filter(|p| fits(&p.quirks()))
+            .or_else(|| PLATFORMS.iter().copied().find(|p| fits(&p.quirks())));
+
+        let mut quirks = platform.map_or(current, |p| p.quirks());
+        for (quirk, value) in self.evidence.iter() {
+            quirk.set(&mut quirks, *value);
+        }
+        let target = match platform {
+            Some(p) => format!("the {} profile", p),
+            None => "custom quirks".to_string(),
+        };
+        let action = match self.mode {
+            Mode::Suggest => format!("try {}", target),
+            Mode::Switch => format!("switching to {}", target),
+        };
+        let message = format!("quirk detection at {:#05x}: {}; {}", pc, reason, action);
+        self.log.push(message.clone());
+        Detection {
+            message,
+            quirks: match self.mode {
+                Mode::Switch if quirks != current => Some(quirks),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::decode;
+    use crate::video::{HIRES_HEIGHT, HIRES_WIDTH, VIDEO_HEIGHT, VIDEO_WIDTH};
+
+    const LORES: (usize, usize) = (VIDEO_WIDTH, VIDEO_HEIGHT);
+
+    fn state(registers: &[(u8, u8)], index_register: u16) -> Record {
+        let mut record = Record {
+            cycle: 0,
+            frame: 0,
+            pc: 0x200,
+            opcode: 0,
+            registers: [0; 16],
+            index_register,
+            sp: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+        };
+        for (reg, value) in registers {
+            record.registers[*reg as usize] = *value;
+        }
+        record
+    }
+
+    #[test]
+    fn shift_from_unset_register() {
+        let memory = vec![0u8; ADDRESS_SPACE];
+        let mut detector = Detector::new(Mode::Switch);
+        let vip = Platform::Chip8.quirks();
+        let s = state(&[(1, 4)], 0);
+        assert_eq!(
+            detector.observe(&s, &decode(0x6104), &memory, 0x210, vip, LORES),
+            None
+        );
+        let detection = detector
+            .observe(&s, &decode(0x8126), &memory, 0x210, vip, LORES)
+            .unwrap();
+        assert_eq!(detection.quirks, Some(Platform::SuperChip.quirks()));
+        assert!(detection
+            .message
+            .contains("shift_uses_vy = false; switching to the schip profile"));
+        // Reported once.
+        assert_eq!(
+            detector.observe(&s, &decode(0x8126), &memory, 0x210, vip, LORES),
+            None
+        );
+    }
+
+    #[test]
+    fn load_into_every_register() {
+        let memory = vec![0u8; ADDRESS_SPACE];
+        let mut detector = Detector::new(Mode::Switch);
+        let vip = Platform::Chip8.quirks();
+        let s = state(&[(0xF, 4)], 0x300);
+        assert_eq!(
+            detector.observe(&s, &decode(0xFF65), &memory, 0x210, vip, LORES),
+            None
+        );
+        // VF was loaded, so shifting from it is no evidence.
+        assert_eq!(
+            detector.observe(&s, &decode(0x81F6), &memory, 0x210, vip, LORES),
+            None
+        );
+    }
+
+    #[test]
+    fn index_runs_into_unused_memory() {
+        let memory = vec![0u8; ADDRESS_SPACE];
+        let mut detector = Detector::new(Mode::Suggest);
+        let vip = Platform::Chip8.quirks();
+        detector.observe(
+            &state(&[], 0x208),
+            &decode(0xF165),
+            &memory,
+            0x210,
+            vip,
+            LORES,
+        );
+        // I was advanced past the ROM, and DRW reads from there.
+        let detection = detector
+            .observe(
+                &state(&[], 0x20A),
+                &decode(0xD015),
+                &memory,
+                0x20A,
+                vip,
+                LORES,
+            )
+            .unwrap();
+        assert_eq!(detection.quirks, None);
+        assert!(detection
+            .message
+            .contains("memory_increments_i = false; try the schip profile"));
+        assert_eq!(detector.log().len(), 1);
+    }
+
+    #[test]
+    fn wrapping_sprite() {
+        let mut memory = vec![0u8; ADDRESS_SPACE];
+        memory[0x300] = 0xFF;
+        let mut detector = Detector::new(Mode::Switch);
+        let quirks = Quirks::default();
+        let s = state(&[(0, 60)], 0x300);
+        let detection = detector
+            .observe(&s, &decode(0xD011), &memory, 0x210, quirks, LORES)
+            .unwrap();
+        let switched = detection.quirks.unwrap();
+        assert!(switched.clip_sprites);
+        assert!(detection.message.contains("sprite at (60, 0) wraps around"));
+        // A sprite that is blank past the edge is fine.
+        memory[0x300] = 0xF0;
+        let mut detector = Detector::new(Mode::Switch);
+        assert_eq!(
+            detector.observe(&s, &decode(0xD011), &memory, 0x210, quirks, LORES),
+            None
+        );
+        // In high resolution only sprites near x = 128 wrap.
+        memory[0x300] = 0xFF;
+        let hires = (HIRES_WIDTH, HIRES_HEIGHT);
+        assert_eq!(
+            detector.observe(&s, &decode(0xD011), &memory, 0x210, quirks, hires),
+            None
+        );
+        let s = state(&[(0, 124)], 0x300);
+        assert!(detector
+            .observe(&s, &decode(0xD011), &memory, 0x210, quirks, hires)
+            .is_some());
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instruction::{decode_at, Instruction};
+use crate::memory::START_ADDRESS;
+
+const BYTES_PER_LINE: usize = 8;
+const COMMENT_COLUMN: usize = 28;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Syntax {
+    Cowgod,
+    Octo,
+}
+
+pub struct Options {
+    pub syntax: Syntax,
+    /// Print data one byte per line with its bitmap, instead of packed rows.
+    pub sprites: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            syntax: Syntax::Cowgod,
+            sprites: false,
+        }
+    }
+}
+
+/// Result of walking a ROM from the entry point: which offsets start an
+/// instruction and which bytes are covered by one.
+pub struct Analysis {
+    pub instructions: BTreeMap<u16, Instruction>,
+    covered: Vec<bool>,
+}
+
+impl Analysis {
+    pub fn new(rom: &[u8]) -> Analysis {
+        let start = START_ADDRESS as u16;
+        let end = start as usize + rom.len();
+        let mut instructions = BTreeMap::new();
+        let mut covered = vec![false; rom.len()];
+        let mut pending = vec![start];
+
+        while let Some(addr) = pending.pop() {
+            if (addr as usize) < START_ADDRESS
+                || addr as usize + 1 >= end
+                || instructions.contains_key(&addr)
+            {
+                continue;
+            }
+            let offset = addr as usize - START_ADDRESS;
+            let instruction = decode_at(rom, offset);
+            if let Instruction::Unknown(_) = instruction {
+                continue;
+            }
+            let size = instruction.size();
+            if addr as usize + size as usize > end {
+                continue;
+            }
+            instructions.insert(addr, instruction);
+            for c in &mut covered[offset..offset + size as usize] {
+                *c = true;
+            }
+
+            let next = addr + size;
+            if let Some(target) = instruction.target() {
+                pending.push(target);
+            }
+            if instruction.is_skip() && next as usize + 1 < end {
+                let skipped = decode_at(rom, next as usize - START_ADDRESS);
+                pending.push(next + skipped.size());
+            }
+            if !instruction.ends_flow() {
+                pending.push(next);
+            }
+        }
+
+        Analysis {
+            instructions,
+            covered,
+        }
+    }
+
+    pub fn is_code(&self, addr: u16) -> bool {
+        addr as usize >= START_ADDRESS
+            && self
+                .covered
+                .get(addr as usize - START_ADDRESS)
+                .copied()
+                .unwrap_or(false)
+    }
+
+    /// An address the listing can put a label on.
+    fn is_line_start(&self, addr: u16) -> bool {
+        let in_rom =
+            addr as usize >= START_ADDRESS && (addr as usize - START_ADDRESS) < self.covered.len();
+        in_rom && (self.instructions.contains_key(&addr) || !self.is_code(addr))
+    }
+
+    fn labels(&self) -> BTreeMap<u16, String> {
+        let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+        for instruction in self.instructions.values() {
+            let (addr, prefix) = match *instruction {
+                Instruction::Call(addr) => (addr, "sub"),
+                Instruction::Jump(addr) => (addr, "label"),
+                Instruction::JumpV0(addr) => (addr, "table"),
+                Instruction::LoadI(addr) | Instruction::LoadILong(addr) => (addr, "data"),
+                _ => continue,
+            };
+            if !self.is_line_start(addr) {
+                continue;
+            }
+            let name = format!("{}_{:03X}", prefix, addr);
+            // Calls win over jumps, and both over data references.
+            let replace = match labels.get(&addr) {
+                None => true,
+                Some(existing) => rank(prefix) < rank(existing.split('_').next().unwrap()),
+            };
+            if replace {
+                labels.insert(addr, name);
+            }
+        }
+        labels
+    }
+}
+
+fn rank(prefix: &str) -> u8 {
+    match prefix {
+        "sub" => 0,
+        "label" => 1,
+        "table" => 2,
+        _ => 3,
+    }
+}
+
+pub fn disassemble(rom: &[u8], options: &Options) -> String {
+    let analysis = Analysis::new(rom);
+    let labels = analysis.labels();
+    let comment = match options.syntax {
+        Syntax::Cowgod => ';',
+        Syntax::Octo => '#',
+    };
+
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let addr = (START_ADDRESS + offset) as u16;
+        if let Some(label) = labels.get(&addr) {
+            match options.syntax {
+                Syntax::Cowgod => writeln!(out, "{}:", label).unwrap(),
+                Syntax::Octo => writeln!(out, ": {}", label).unwrap(),
+            }
+        }
+
+        if let Some(instruction) = analysis.instructions.get(&addr) {
+            let text = match options.syntax {
+                Syntax::Cowgod => cowgod(instruction, &labels),
+                Syntax::Octo => octo(instruction, &labels),
+            };
+            let size = instruction.size() as usize;
+            let raw: String = rom[offset..offset + size]
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect();
+            push_line(
+                &mut out,
+                &text,
+                &format!("{} {:#05x}  {}", comment, addr, raw),
+            );
+            offset += size;
+            continue;
+        }
+
+        let mut len = 1;
+        while !options.sprites
+            && len < BYTES_PER_LINE
+            && offset + len < rom.len()
+            && !analysis.is_code(addr + len as u16)
+            && !labels.contains_key(&(addr + len as u16))
+        {
+            len += 1;
+        }
+        let bytes: Vec<String> = rom[offset..offset + len]
+            .iter()
+            .map(|b| format!("{:#04x}", b))
+            .collect();
+        let text = match options.syntax {
+            Syntax::Cowgod => format!("db {}", bytes.join(", ")),
+            Syntax::Octo => bytes.join(" "),
+        };
+        let note = if options.sprites {
+            format!("{} {:#05x}  {}", comment, addr, bitmap(rom[offset]))
+        } else {
+            format!("{} {:#05x}", comment, addr)
+        };
+        push_line(&mut out, &text, &note);
+        offset += len;
+    }
+    out
+}
+
+fn push_line(out: &mut String, text: &str, note: &str) {
+    writeln!(
+        out,
+        "    {:<width$} {}",
+        text,
+        note,
+        width = COMMENT_COLUMN - 1
+    )
+    .unwrap();
+}
+
+fn bitmap(byte: u8) -> String {
+    (0..8)
+        .map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' })
+        .collect()
+}
+
+fn target(addr: u16, labels: &BTreeMap<u16, String>) -> String {
+    labels
+        .get(&addr)
+        .cloned()
+        .unwrap_or_else(|| format!("{:#05x}", addr))
+}
+
+fn cowgod(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    match *instruction {
+        Instruction::Jump(a) => format!("JP {}", target(a, labels)),
+        Instruction::Call(a) => format!("CALL {}", target(a, labels)),
+        Instruction::LoadI(a) => format!("LD I, {}", target(a, labels)),
+        Instruction::JumpV0(a) => format!("JP V0, {}", target(a, labels)),
+        Instruction::LoadILong(a) => format!("LD I, LONG {}", target(a, labels)),
+        instruction => instruction.to_string(),
+    }
+}
+
+fn octo(instruction: &Instruction, labels: &BTreeMap<u16, String>) -> String {
+    match *instruction {
+        Instruction::Sys(a) => format!("{:#04x} {:#04x}", a >> 8, a & 0xFF),
+        Instruction::Cls => "clear".to_string(),
+        Instruction::Ret => "return".to_string(),
+        Instruction::Jump(a) => format!("jump {}", target(a, labels)),
+        Instruction::Call(a) => match labels.get(&a) {
+            Some(label) => label.clone(),
+            None => format!(":call {:#05x}", a),
+        },
+        Instruction::SkipEqByte(x, kk) => format!("if v{:x} != {:#04x} then", x, kk),
+        Instruction::SkipNeByte(x, kk) => format!("if v{:x} == {:#04x} then", x, kk),
+        Instruction::SkipEqReg(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        Instruction::LoadByte(x, kk) => format!("v{:x} := {:#04x}", x, kk),
+        Instruction::AddByte(x, kk) => format!("v{:x} += {:#04x}", x, kk),
+        Instruction::LoadReg(x, y) => format!("v{:x} := v{:x}", x, y),
+        Instruction::Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        Instruction::And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Instruction::Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Instruction::AddReg(x, y) => format!("v{:x} += v{:x}", x, y),
+        Instruction::Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+        Instruction::ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        Instruction::SubN(x, y) => format!("v{:x} =- v{:x}", x, y),
+        Instruction::ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        Instruction::SkipNeReg(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        Instruction::LoadI(a) => format!("i := {}", target(a, labels)),
+        Instruction::JumpV0(a) => format!("jump0 {}", target(a, labels)),
+        Instruction::Random(x, kk) => format!("v{:x} := random {:#04x}", x, kk),
+        Instruction::Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        Instruction::SkipKey(x) => format!("if v{:x} -key then", x),
+        Instruction::SkipNotKey(x) => format!("if v{:x} key then", x),
+        Instruction::LoadDelay(x) => format!("v{:x} := delay", x),
+        Instruction::WaitKey(x) => format!("v{:x} := key", x),
+        Instruction::SetDelay(x) => format!("delay := v{:x}", x),
+        Instruction::SetSound(x) => format!("buzzer := v{:x}", x),
+        Instruction::AddI(x) => format!("i += v{:x}", x),
+        Instruction::LoadFont(x) => format!("i := hex v{:x}", x),
+        Instruction::Bcd(x) => format!("bcd v{:x}", x),
+        Instruction::Store(x) => format!("save v{:x}", x),
+        Instruction::Load(x) => format!("load v{:x}", x),
+        Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+        Instruction::ScrollRight => "scroll-right".to_string(),
+        Instruction::ScrollLeft => "scroll-left".to_string(),
+        Instruction::Exit => "exit".to_string(),
+        Instruction::Lores => "lores".to_string(),
+        Instruction::Hires => "hires".to_string(),
+        Instruction::LoadBigFont(x) => format!("i := bighex v{:x}", x),
+        Instruction::StoreFlags(x) => format!("saveflags v{:x}", x),
+        Instruction::LoadFlags(x) => format!("loadflags v{:x}", x),
+        Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+        Instruction::StoreRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        Instruction::LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        Instruction::LoadILong(a) => format!("i := long {}", target(a, labels)),
+        Instruction::Plane(n) => format!("plane {}", n),
+        Instruction::Audio => "audio".to_string(),
+        Instruction::Pitch(x) => format!("pitch := v{:x}", x),
+        Instruction::Unknown(op) => format!("{:#04x} {:#04x}", op >> 8, op & 0xFF),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // CALL sub; JP self; sub: LD I, data; DRW V0, V0, 1; RET; data: 0x3C
+    const ROM: [u8; 11] = [
+        0x22, 0x04, 0x12, 0x02, 0xA2, 0x0A, 0xD0, 0x01, 0x00, 0xEE, 0x3C,
+    ];
+
+    #[test]
+    fn analysis_separates_code_and_data() {
+        let analysis = Analysis::new(&ROM);
+        assert_eq!(analysis.instructions.len(), 5);
+        assert!(analysis.is_code(0x209));
+        assert!(!analysis.is_code(0x20A));
+    }
+
+    #[test]
+    fn analysis_follows_skips() {
+        // SE V0, 0; JP 0x208; LD V1, 1; EXIT; LD V2, 2
+        let rom = [0x30, 0x00, 0x12, 0x08, 0x61, 0x01, 0x00, 0xFD, 0x62, 0x02];
+        let analysis = Analysis::new(&rom);
+        assert!(analysis.instructions.contains_key(&0x204));
+        assert!(analysis.instructions.contains_key(&0x208));
+    }
+
+    #[test]
+    fn cowgod_listing() {
+        let listing = disassemble(&ROM, &Options::default());
+        let lines: Vec<&str> = listing.lines().map(|l| l.trim_end()).collect();
+        assert_eq!(lines[0], "    CALL sub_204                ; 0x200  2204");
+        assert_eq!(lines[1], "label_202:");
+        assert_eq!(lines[2], "    JP label_202                ; 0x202  1202");
+        assert_eq!(lines[3], "sub_204:");
+        assert_eq!(lines[4], "    LD I, data_20A              ; 0x204  A20A");
+        assert_eq!(lines[8], "    db 0x3c                     ; 0x20a");
+    }
+
+    #[test]
+    fn octo_listing_with_sprites() {
+        let options = Options {
+            syntax: Syntax::Octo,
+            sprites: true,
+        };
+        let listing = disassemble(&ROM, &options);
+        assert!(listing.starts_with("    sub_204 "));
+        assert!(listing.contains(": sub_204\n    i := data_20A "));
+        assert!(listing.contains("    sprite v0 v0 1 "));
+        assert!(listing.contains("    0x3c                        # 0x20a  ..####..\n"));
+    }
+}
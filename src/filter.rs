@@ -0,0 +1,399 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// An image of 0xRRGGBB pixels, row by row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<u32>) -> Image {
+        assert_eq!(pixels.len(), width * height);
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// The pixel at (`x`, `y`), with coordinates off the image clamped to
+    /// its edges.
+    fn at(&self, x: isize, y: isize) -> u32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// Each output pixel of an image `factor` times larger, from the input
+    /// pixel it falls in and its position inside it.
+    fn scaled(&self, factor: usize, f: impl Fn(usize, usize, usize, usize) -> u32) -> Image {
+        let (width, height) = (self.width * factor, self.height * factor);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(f(x / factor, y / factor, x % factor, y % factor));
+            }
+        }
+        Image::new(width, height, pixels)
+    }
+}
+
+/// A software filter turning the screen into a larger, nicer looking image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Scale2x (EPX): doubles the size, rounding off diagonal steps.
+    Scale2x,
+    /// Scale3x (AdvMAME3x): as Scale2x, three times the size.
+    Scale3x,
+    /// Four times the size with edges smoothed in the spirit of hqx/xBR:
+    /// Scale2x twice, then every pixel on an edge blended with its
+    /// neighbours.
+    Smooth,
+    /// Three times the size with every third row darkened.
+    Scanlines,
+    /// Three times the size with dark lines between the pixels.
+    Grid,
+    /// Scanlines with a glow around lit pixels, like a CRT.
+    Crt,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "scale2x" | "epx" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "smooth" | "hqx" | "xbr" => Ok(Filter::Smooth),
+            "scanlines" => Ok(Filter::Scanlines),
+            "grid" => Ok(Filter::Grid),
+            "crt" | "bloom" => Ok(Filter::Crt),
+            _ => Err(format!(
+                "unknown filter `{}` (expected scale2x, scale3x, smooth, scanlines, grid or crt)",
+                s
+            )),
+        }
+    }
+}
+
+impl Filter {
+    pub fn apply(&self, image: &Image) -> Image {
+        match self {
+            Filter::Scale2x => scale2x(image),
+            Filter::Scale3x => scale3x(image),
+            Filter::Smooth => smooth(&scale2x(&scale2x(image))),
+            Filter::Scanlines => scanlines(image, 0.5),
+            Filter::Grid => image.scaled(3, |x, y, dx, dy| {
+                let pixel = image.pixels[y * image.width + x];
+                if dx == 2 || dy == 2 {
+                    dim(pixel, 0.6)
+                } else {
+                    pixel
+                }
+            }),
+            Filter::Crt => crt(image),
+        }
+    }
+}
+
+/// Filters applied one after the other, written `scale2x,scanlines`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Chain(pub Vec<Filter>);
+
+impl Chain {
+    pub fn apply(&self, image: Image) -> Image {
+        self.0
+            .iter()
+            .fold(image, |image, filter| filter.apply(&image))
+    }
+}
+
+impl FromStr for Chain {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Chain, String> {
+        if s.trim().is_empty() || s.eq_ignore_ascii_case("none") {
+            return Ok(Chain::default());
+        }
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Chain)
+    }
+}
+
+impl TryFrom<String> for Chain {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Chain, String> {
+        s.parse()
+    }
+}
+
+fn scale2x(image: &Image) -> Image {
+    image.scaled(2, |x, y, dx, dy| {
+        let (x, y) = (x as isize, y as isize);
+        let e = image.at(x, y);
+        let (b, d, f, h) = (
+            image.at(x, y - 1),
+            image.at(x - 1, y),
+            image.at(x + 1, y),
+            image.at(x, y + 1),
+        );
+        if b == h || d == f {
+            return e;
+        }
+        match (dx, dy) {
+            (0, 0) if d == b => d,
+            (1, 0) if b == f => f,
+            (0, 1) if d == h => d,
+            (1, 1) if h == f => f,
+            _ => e,
+        }
+    })
+}
+
+fn scale3x(image: &Image) -> Image {
+    image.scaled(3, |x, y, dx, dy| {
+        let (x, y) = (x as isize, y as isize);
+        let [a, b, c, d, e, f, g, h, i] = [
+            image.at(x - 1, y - 1),
+            image.at(x, y - 1),
+            image.at(x + 1, y - 1),
+            image.at(x - 1, y),
+            image.at(x, y),
+            image.at(x + 1, y),
+            image.at(x - 1, y + 1),
+            image.at(x, y + 1),
+            image.at(x + 1, y + 1),
+        ];
+        if b == h || d == f {
+            return e;
+        }
+        let pick = |condition: bool, colour: u32| if condition { colour } else { e };
+        match (dx, dy) {
+            (0, 0) => pick(d == b, d),
+            (1, 0) => pick((d == b && e != c) || (b == f && e != a), b),
+            (2, 0) => pick(b == f, f),
+            (0, 1) => pick((d == b && e != g) || (d == h && e != a), d),
+            (2, 1) => pick((b == f && e != i) || (h == f && e != c), f),
+            (0, 2) => pick(d == h, d),
+            (1, 2) => pick((d == h && e != i) || (h == f && e != g), h),
+            (2, 2) => pick(h == f, f),
+            _ => e,
+        }
+    })
+}
+
+/// Blend every pixel that differs from one of its neighbours with them,
+/// weighting it as much as all four together.
+fn smooth(image: &Image) -> Image {
+    let mut pixels = Vec::with_capacity(image.pixels.len());
+    for y in 0..image.height as isize {
+        for x in 0..image.width as isize {
+            let e = image.at(x, y);
+            let neighbours = [
+                image.at(x, y - 1),
+                image.at(x - 1, y),
+                image.at(x + 1, y),
+                image.at(x, y + 1),
+            ];
+            if neighbours.iter().all(|n| *n == e) {
+                pixels.push(e);
+            } else {
+                let weighted = [
+                    (e, 4),
+                    (neighbours[0], 1),
+                    (neighbours[1], 1),
+                    (neighbours[2], 1),
+                    (neighbours[3], 1),
+                ];
+                pixels.push(average(weighted.iter()));
+            }
+        }
+    }
+    Image::new(image.width, image.height, pixels)
+}
+
+fn scanlines(image: &Image, brightness: f32) -> Image {
+    image.scaled(3, |x, y, _, dy| {
+        let pixel = image.pixels[y * image.width + x];
+        if dy == 2 {
+            dim(pixel, brightness)
+        } else {
+            pixel
+        }
+    })
+}
+
+/// Light scanlines, with a blurred copy of the picture laid over them as
+/// a screen blend.
+fn crt(image: &Image) -> Image {
+    let base = scanlines(image, 0.7);
+    let glow = image.scaled(3, |x, y, _, _| image.pixels[y * image.width + x]);
+    let glow = blur(&blur(&glow, 1, 0, 3), 0, 1, 3);
+    let pixels = base
+        .pixels
+        .iter()
+        .zip(glow.pixels.iter())
+        .map(|(base, glow)| screen(*base, dim(*glow, 0.5)))
+        .collect();
+    Image::new(base.width, base.height, pixels)
+}
+
+/// Box blur of `radius` pixels along (`dx`, `dy`).
+fn blur(image: &Image, dx: isize, dy: isize, radius: isize) -> Image {
+    let mut pixels = Vec::with_capacity(image.pixels.len());
+    for y in 0..image.height as isize {
+        for x in 0..image.width as isize {
+            let samples: Vec<(u32, u32)> = (-radius..=radius)
+                .map(|r| (image.at(x + r * dx, y + r * dy), 1))
+                .collect();
+            pixels.push(average(samples.iter()));
+        }
+    }
+    Image::new(image.width, image.height, pixels)
+}
+
+fn channels(colour: u32) -> [u32; 3] {
+    [colour >> 16 & 0xFF, colour >> 8 & 0xFF, colour & 0xFF]
+}
+
+fn from_channels([r, g, b]: [u32; 3]) -> u32 {
+    r << 16 | g << 8 | b
+}
+
+fn dim(colour: u32, brightness: f32) -> u32 {
+    from_channels(channels(colour).map(|c| (c as f32 * brightness) as u32))
+}
+
+/// The weighted average of (colour, weight) pairs.
+fn average<'a>(colours: impl Iterator<Item = &'a (u32, u32)>) -> u32 {
+    let mut sums = [0; 3];
+    let mut total = 0;
+    for (colour, weight) in colours {
+        for (sum, channel) in sums.iter_mut().zip(channels(*colour).iter()) {
+            *sum += channel * weight;
+        }
+        total += weight;
+    }
+    from_channels(sums.map(|sum| sum / total))
+}
+
+/// Photographic screen blend: brightens `a` by `b`, never past white.
+fn screen(a: u32, b: u32) -> u32 {
+    let (a, b) = (channels(a), channels(b));
+    from_channels([0, 1, 2].map(|i| 255 - (255 - a[i]) * (255 - b[i]) / 255))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// A 2x2 square, a diagonal line and an orange dot on black.
+    const PATTERN: [&str; 6] = [
+        "........", //
+        ".##.....", ".##..#..", "....#...", "...#....", "......o.",
+    ];
+
+    fn pattern() -> Image {
+        let pixels = PATTERN
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|c| match c {
+                '#' => 0xFFFFFF,
+                'o' => 0xFF8000,
+                _ => 0x000000,
+            })
+            .collect();
+        Image::new(8, 6, pixels)
+    }
+
+    fn to_ppm(image: &Image) -> String {
+        let mut ppm = format!("P3\n{} {}\n255\n", image.width, image.height);
+        for row in image.pixels.chunks(image.width) {
+            let row: Vec<String> = row
+                .iter()
+                .map(|p| {
+                    let [r, g, b] = channels(*p);
+                    format!("{} {} {}", r, g, b)
+                })
+                .collect();
+            ppm.push_str(&row.join("  "));
+            ppm.push('\n');
+        }
+        ppm
+    }
+
+    /// Compare `filter` applied to the pattern with `tests/golden/<name>.ppm`,
+    /// or rewrite the file when `UPDATE_GOLDEN` is set.
+    fn check_golden(name: &str, filter: &str) {
+        let chain: Chain = filter.parse().unwrap();
+        let ppm = to_ppm(&chain.apply(pattern()));
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.ppm", name));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &ppm).unwrap();
+        }
+        let golden = fs::read_to_string(&path).unwrap();
+        assert!(ppm == golden, "{} differs from {}", filter, path.display());
+    }
+
+    #[test]
+    fn scale2x_rounds_diagonals() {
+        let image = scale2x(&pattern());
+        let rows: Vec<String> = image
+            .pixels
+            .chunks(image.width)
+            .map(|row| {
+                row.iter()
+                    .map(|p| if *p == 0xFFFFFF { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            rows[4..10],
+            [
+                "..####....##....",
+                "...##....###....",
+                "........###.....",
+                ".......###......",
+                "......###.......",
+                "......##........",
+            ]
+        );
+    }
+
+    #[test]
+    fn golden_images() {
+        check_golden("scale2x", "scale2x");
+        check_golden("scale3x", "scale3x");
+        check_golden("smooth", "smooth");
+        check_golden("scanlines", "scanlines");
+        check_golden("grid", "grid");
+        check_golden("crt", "crt");
+        check_golden("scale2x-scanlines", "scale2x,scanlines");
+    }
+
+    #[test]
+    fn chains() {
+        assert_eq!("none".parse(), Ok(Chain::default()));
+        assert_eq!(
+            "Scale2x, crt".parse(),
+            Ok(Chain(vec![Filter::Scale2x, Filter::Crt]))
+        );
+        assert!("scale2x,blur"
+            .parse::<Chain>()
+            .unwrap_err()
+            .contains("unknown filter"));
+    }
+}
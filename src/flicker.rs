@@ -0,0 +1,158 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::palette::Palette;
+
+/// Fraction of a pixel's brightness kept each frame after it goes out.
+pub const DEFAULT_DECAY: f32 = 0.6;
+
+/// How the frontend hides sprites flickering as games erase and redraw them
+/// with XOR.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Mode {
+    /// Show every frame as it is.
+    Off,
+    /// Light pixels at once but let them fade out over several frames, like
+    /// phosphor.
+    Fade,
+    /// Show the average of the last two frames.
+    Blend,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" | "none" => Ok(Mode::Off),
+            "fade" | "persistence" => Ok(Mode::Fade),
+            "blend" => Ok(Mode::Blend),
+            _ => Err(format!(
+                "unknown anti-flicker mode `{}` (expected off, fade or blend)",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Mode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Mode, String> {
+        s.parse()
+    }
+}
+
+/// Turns framebuffers into colours, remembering what earlier frames showed.
+pub struct Deflicker {
+    mode: Mode,
+    decay: f32,
+    /// Colours shown last, for `Fade`.
+    shown: Vec<u32>,
+    /// The previous framebuffer, for `Blend`.
+    last: Vec<u8>,
+}
+
+impl Deflicker {
+    pub fn new(mode: Mode, decay: f32) -> Deflicker {
+        Deflicker {
+            mode,
+            decay: decay.clamp(0.0, 1.0),
+            shown: Vec::new(),
+            last: Vec::new(),
+        }
+    }
+
+    /// Colours as 0xRRGGBB for this frame's `framebuffer`, into `out`. A
+    /// framebuffer of a different size (after a resolution switch) starts
+    /// afresh.
+    pub fn apply(&mut self, framebuffer: &[u8], palette: &Palette, out: &mut Vec<u32>) {
+        out.clear();
+        match self.mode {
+            Mode::Off => out.extend(framebuffer.iter().map(|p| palette.colour(*p))),
+            Mode::Fade => {
+                if self.shown.len() != framebuffer.len() {
+                    self.shown = framebuffer.iter().map(|p| palette.colour(*p)).collect();
+                }
+                for (pixel, shown) in framebuffer.iter().zip(self.shown.iter_mut()) {
+                    let colour = palette.colour(*pixel);
+                    *shown = if *pixel != 0 {
+                        colour
+                    } else {
+                        mix(colour, *shown, self.decay)
+                    };
+                }
+                out.extend_from_slice(&self.shown);
+            }
+            Mode::Blend => {
+                if self.last.len() != framebuffer.len() {
+                    self.last = framebuffer.to_vec();
+                }
+                out.extend(
+                    framebuffer
+                        .iter()
+                        .zip(self.last.iter())
+                        .map(|(now, last)| mix(palette.colour(*now), palette.colour(*last), 0.5)),
+                );
+                self.last.copy_from_slice(framebuffer);
+            }
+        }
+    }
+}
+
+/// `a` moved `t` of the way towards `b`, channel by channel. Rounding
+/// towards `a` lets a fade reach it.
+fn mix(a: u32, b: u32, t: f32) -> u32 {
+    (0..3).fold(0, |colour, channel| {
+        let shift = 8 * channel;
+        let (a, b) = ((a >> shift & 0xFF) as f32, (b >> shift & 0xFF) as f32);
+        colour | ((a + (b - a) * t) as u32) << shift
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PALETTE: Palette = Palette {
+        colours: [0x000000, 0xFFFFFF, 0xFFFFFF, 0xFFFFFF],
+    };
+
+    #[test]
+    fn fade_out() {
+        let mut deflicker = Deflicker::new(Mode::Fade, 0.5);
+        let mut out = Vec::new();
+        deflicker.apply(&[1, 0], &PALETTE, &mut out);
+        assert_eq!(out, [0xFFFFFF, 0x000000]);
+        deflicker.apply(&[0, 1], &PALETTE, &mut out);
+        assert_eq!(out, [0x7F7F7F, 0xFFFFFF]);
+        deflicker.apply(&[0, 1], &PALETTE, &mut out);
+        assert_eq!(out, [0x3F3F3F, 0xFFFFFF]);
+        for _ in 0..10 {
+            deflicker.apply(&[0, 1], &PALETTE, &mut out);
+        }
+        assert_eq!(out, [0x000000, 0xFFFFFF]);
+    }
+
+    #[test]
+    fn blend_last_two_frames() {
+        let mut deflicker = Deflicker::new(Mode::Blend, DEFAULT_DECAY);
+        let mut out = Vec::new();
+        deflicker.apply(&[1, 0], &PALETTE, &mut out);
+        assert_eq!(out, [0xFFFFFF, 0x000000]);
+        deflicker.apply(&[0, 0], &PALETTE, &mut out);
+        assert_eq!(out, [0x7F7F7F, 0x000000]);
+        // A resolution switch forgets the old frame.
+        deflicker.apply(&[0, 0, 1], &PALETTE, &mut out);
+        assert_eq!(out, [0x000000, 0x000000, 0xFFFFFF]);
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!("Blend".parse(), Ok(Mode::Blend));
+        assert!("smear".parse::<Mode>().is_err());
+    }
+}
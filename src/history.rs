@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::chip::KeyWait;
+use crate::trace::parse_address;
+
+/// A single value overwritten by an instruction, holding what was there before.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Register { reg: u8, old: u8 },
+    Memory { address: u16, old: u8 },
+    Pixel { index: usize, old: u8 },
+    Stack { slot: usize, old: u16 },
+}
+
+/// Something reverse execution should stop on when an instruction modified it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watchpoint {
+    Register(u8),
+    Memory(u16),
+}
+
+impl FromStr for Watchpoint {
+    type Err = String;
+
+    /// A register `v0`-`vf`, or a memory address in hex (`0x3A0`) or decimal.
+    fn from_str(s: &str) -> Result<Watchpoint, String> {
+        let s = s.trim();
+        let register = s.strip_prefix(|c| c == 'v' || c == 'V');
+        if let Some(reg) = register.and_then(|r| u8::from_str_radix(r, 16).ok()) {
+            if reg < 16 {
+                return Ok(Watchpoint::Register(reg));
+            }
+        }
+        parse_address(s).map(Watchpoint::Memory)
+    }
+}
+
+/// Undo record for one executed instruction.
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub pc: u16,
+    pub index_register: u16,
+    pub sp: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// Screen resolution before the instruction.
+    pub hires: bool,
+    /// Progress of an `Fx0A` wait before the instruction.
+    pub key_wait: KeyWait,
+    pub changes: Vec<Change>,
+}
+
+impl Step {
+    pub fn touches(&self, watchpoint: &Watchpoint) -> bool {
+        self.changes
+            .iter()
+            .any(|change| match (change, watchpoint) {
+                (Change::Register { reg, .. }, Watchpoint::Register(w)) => reg == w,
+                (Change::Memory { address, .. }, Watchpoint::Memory(w)) => address == w,
+                _ => false,
+            })
+    }
+}
+
+/// Bounded log of executed instructions, newest last.
+pub struct History {
+    steps: VecDeque<Step>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            steps: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, step: Step) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.steps.len() == self.capacity {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(step);
+    }
+
+    pub fn pop(&mut self) -> Option<Step> {
+        self.steps.pop_back()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn step(pc: u16) -> Step {
+        Step {
+            pc,
+            index_register: 0,
+            sp: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            hires: false,
+            key_wait: KeyWait::Idle,
+            changes: vec![],
+        }
+    }
+
+    #[test]
+    fn history_drops_oldest() {
+        let mut history = History::new(2);
+        history.push(step(1));
+        history.push(step(2));
+        history.push(step(3));
+        assert_eq!(history.pop().unwrap().pc, 3);
+        assert_eq!(history.pop().unwrap().pc, 2);
+        assert!(history.pop().is_none());
+    }
+
+    #[test]
+    fn parse_watchpoints() {
+        assert_eq!("vA".parse(), Ok(Watchpoint::Register(0xA)));
+        assert_eq!("0x3A0".parse(), Ok(Watchpoint::Memory(0x3A0)));
+        assert_eq!("512".parse(), Ok(Watchpoint::Memory(0x200)));
+        assert!("vg".parse::<Watchpoint>().is_err());
+    }
+
+    #[test]
+    fn step_touches_watchpoint() {
+        let mut s = step(0x200);
+        s.changes.push(Change::Register { reg: 3, old: 0 });
+        assert!(s.touches(&Watchpoint::Register(3)));
+        assert!(!s.touches(&Watchpoint::Register(4)));
+        assert!(!s.touches(&Watchpoint::Memory(3)));
+    }
+}
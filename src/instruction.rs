@@ -0,0 +1,341 @@
+use std::fmt;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    Sys(u16),
+    Cls,
+    Ret,
+    Jump(u16),
+    Call(u16),
+    SkipEqByte(u8, u8),
+    SkipNeByte(u8, u8),
+    SkipEqReg(u8, u8),
+    LoadByte(u8, u8),
+    AddByte(u8, u8),
+    LoadReg(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    AddReg(u8, u8),
+    Sub(u8, u8),
+    ShiftRight(u8, u8),
+    SubN(u8, u8),
+    ShiftLeft(u8, u8),
+    SkipNeReg(u8, u8),
+    LoadI(u16),
+    JumpV0(u16),
+    Random(u8, u8),
+    Draw(u8, u8, u8),
+    SkipKey(u8),
+    SkipNotKey(u8),
+    LoadDelay(u8),
+    WaitKey(u8),
+    SetDelay(u8),
+    SetSound(u8),
+    AddI(u8),
+    LoadFont(u8),
+    Bcd(u8),
+    Store(u8),
+    Load(u8),
+    // SUPER-CHIP
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    Exit,
+    Lores,
+    Hires,
+    LoadBigFont(u8),
+    StoreFlags(u8),
+    LoadFlags(u8),
+    // XO-CHIP
+    ScrollUp(u8),
+    StoreRange(u8, u8),
+    LoadRange(u8, u8),
+    LoadILong(u16),
+    Plane(u8),
+    Audio,
+    Pitch(u8),
+    Unknown(u16),
+}
+
+impl Instruction {
+    /// Size in bytes; only the XO-CHIP long `I` load takes two words.
+    pub fn size(&self) -> u16 {
+        match self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Address of a direct `JP`/`CALL` target, if any.
+    pub fn target(&self) -> Option<u16> {
+        match *self {
+            Instruction::Jump(addr) | Instruction::Call(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::SkipEqByte(..)
+                | Instruction::SkipNeByte(..)
+                | Instruction::SkipEqReg(..)
+                | Instruction::SkipNeReg(..)
+                | Instruction::SkipKey(_)
+                | Instruction::SkipNotKey(_)
+        )
+    }
+
+    /// True when execution never falls through to the next instruction.
+    pub fn ends_flow(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Jump(_) | Instruction::JumpV0(_) | Instruction::Ret | Instruction::Exit
+        )
+    }
+
+    /// True for opcodes that only exist on SUPER-CHIP or XO-CHIP.
+    pub fn is_extension(&self) -> bool {
+        self.is_schip() || self.is_xochip()
+    }
+
+    pub fn is_schip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::ScrollDown(_)
+                | Instruction::ScrollRight
+                | Instruction::ScrollLeft
+                | Instruction::Exit
+                | Instruction::Lores
+                | Instruction::Hires
+                | Instruction::LoadBigFont(_)
+                | Instruction::StoreFlags(_)
+                | Instruction::LoadFlags(_)
+                | Instruction::Draw(_, _, 0)
+        )
+    }
+
+    pub fn is_xochip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::ScrollUp(_)
+                | Instruction::StoreRange(..)
+                | Instruction::LoadRange(..)
+                | Instruction::LoadILong(_)
+                | Instruction::Plane(_)
+                | Instruction::Audio
+                | Instruction::Pitch(_)
+        )
+    }
+}
+
+/// Decode a single opcode. `F000` decodes as a long `I` load with a zero
+/// operand; use `decode_at` to read the following word as well.
+pub fn decode(opcode: u16) -> Instruction {
+    let x = decode_fst(opcode);
+    let y = decode_snd(opcode);
+    let n = decode_thrd(opcode);
+    let kk = decode_bytes(opcode);
+    let nnn = decode_all(opcode);
+
+    match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => Instruction::Cls,
+            0x00EE => Instruction::Ret,
+            0x00FB => Instruction::ScrollRight,
+            0x00FC => Instruction::ScrollLeft,
+            0x00FD => Instruction::Exit,
+            0x00FE => Instruction::Lores,
+            0x00FF => Instruction::Hires,
+            op if op & 0xFFF0 == 0x00C0 => Instruction::ScrollDown(n),
+            op if op & 0xFFF0 == 0x00D0 => Instruction::ScrollUp(n),
+            _ => Instruction::Sys(nnn),
+        },
+        0x1000 => Instruction::Jump(nnn),
+        0x2000 => Instruction::Call(nnn),
+        0x3000 => Instruction::SkipEqByte(x, kk),
+        0x4000 => Instruction::SkipNeByte(x, kk),
+        0x5000 => match n {
+            0x0 => Instruction::SkipEqReg(x, y),
+            0x2 => Instruction::StoreRange(x, y),
+            0x3 => Instruction::LoadRange(x, y),
+            _ => Instruction::Unknown(opcode),
+        },
+        0x6000 => Instruction::LoadByte(x, kk),
+        0x7000 => Instruction::AddByte(x, kk),
+        0x8000 => match n {
+            0x0 => Instruction::LoadReg(x, y),
+            0x1 => Instruction::Or(x, y),
+            0x2 => Instruction::And(x, y),
+            0x3 => Instruction::Xor(x, y),
+            0x4 => Instruction::AddReg(x, y),
+            0x5 => Instruction::Sub(x, y),
+            0x6 => Instruction::ShiftRight(x, y),
+            0x7 => Instruction::SubN(x, y),
+            0xE => Instruction::ShiftLeft(x, y),
+            _ => Instruction::Unknown(opcode),
+        },
+        0x9000 if n == 0 => Instruction::SkipNeReg(x, y),
+        0xA000 => Instruction::LoadI(nnn),
+        0xB000 => Instruction::JumpV0(nnn),
+        0xC000 => Instruction::Random(x, kk),
+        0xD000 => Instruction::Draw(x, y, n),
+        0xE000 => match kk {
+            0x9E => Instruction::SkipKey(x),
+            0xA1 => Instruction::SkipNotKey(x),
+            _ => Instruction::Unknown(opcode),
+        },
+        0xF000 => match kk {
+            0x00 if x == 0 => Instruction::LoadILong(0),
+            0x01 => Instruction::Plane(x),
+            0x02 if x == 0 => Instruction::Audio,
+            0x07 => Instruction::LoadDelay(x),
+            0x0A => Instruction::WaitKey(x),
+            0x15 => Instruction::SetDelay(x),
+            0x18 => Instruction::SetSound(x),
+            0x1E => Instruction::AddI(x),
+            0x29 => Instruction::LoadFont(x),
+            0x30 => Instruction::LoadBigFont(x),
+            0x33 => Instruction::Bcd(x),
+            0x3A => Instruction::Pitch(x),
+            0x55 => Instruction::Store(x),
+            0x65 => Instruction::Load(x),
+            0x75 => Instruction::StoreFlags(x),
+            0x85 => Instruction::LoadFlags(x),
+            _ => Instruction::Unknown(opcode),
+        },
+        _ => Instruction::Unknown(opcode),
+    }
+}
+
+/// Decode the instruction starting at `offset`, reading the extra word of
+/// `F000 nnnn` when present. Missing trailing bytes read as zero.
+pub fn decode_at(bytes: &[u8], offset: usize) -> Instruction {
+    let word = |at: usize| {
+        let hi = bytes.get(at).copied().unwrap_or(0) as u16;
+        let lo = bytes.get(at + 1).copied().unwrap_or(0) as u16;
+        (hi << 8) | lo
+    };
+    match decode(word(offset)) {
+        Instruction::LoadILong(_) => Instruction::LoadILong(word(offset + 2)),
+        instruction => instruction,
+    }
+}
+
+/// Classic (Cowgod) mnemonics.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Sys(a) => write!(f, "SYS {:#05x}", a),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jump(a) => write!(f, "JP {:#05x}", a),
+            Instruction::Call(a) => write!(f, "CALL {:#05x}", a),
+            Instruction::SkipEqByte(x, kk) => write!(f, "SE V{:X}, {:#04x}", x, kk),
+            Instruction::SkipNeByte(x, kk) => write!(f, "SNE V{:X}, {:#04x}", x, kk),
+            Instruction::SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LoadByte(x, kk) => write!(f, "LD V{:X}, {:#04x}", x, kk),
+            Instruction::AddByte(x, kk) => write!(f, "ADD V{:X}, {:#04x}", x, kk),
+            Instruction::LoadReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubN(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadI(a) => write!(f, "LD I, {:#05x}", a),
+            Instruction::JumpV0(a) => write!(f, "JP V0, {:#05x}", a),
+            Instruction::Random(x, kk) => write!(f, "RND V{:X}, {:#04x}", x, kk),
+            Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipNotKey(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LoadDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LoadFont(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Lores => write!(f, "LOW"),
+            Instruction::Hires => write!(f, "HIGH"),
+            Instruction::LoadBigFont(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::StoreRange(x, y) => write!(f, "SAVE V{:X}-V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X}-V{:X}", x, y),
+            Instruction::LoadILong(a) => write!(f, "LD I, LONG {:#06x}", a),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::Unknown(op) => write!(f, "DW {:#06x}", op),
+        }
+    }
+}
+
+pub fn decode_fst(n: u16) -> u8 {
+    ((n & 0x0F00) >> 8u16) as u8
+}
+
+pub fn decode_snd(n: u16) -> u8 {
+    ((n & 0x00F0) >> 4u16) as u8
+}
+
+pub fn decode_thrd(n: u16) -> u8 {
+    (n & 0x000F) as u8
+}
+
+pub fn decode_bytes(n: u16) -> u8 {
+    (n & 0x00FF) as u8
+}
+
+pub fn decode_all(n: u16) -> u16 {
+    n & 0x0FFF
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_chip8() {
+        assert_eq!(decode(0x00E0), Instruction::Cls);
+        assert_eq!(decode(0x1234), Instruction::Jump(0x234));
+        assert_eq!(decode(0x8AB6), Instruction::ShiftRight(0xA, 0xB));
+        assert_eq!(decode(0xD125), Instruction::Draw(1, 2, 5));
+        assert_eq!(decode(0xF365), Instruction::Load(3));
+        assert_eq!(decode(0x8008), Instruction::Unknown(0x8008));
+    }
+
+    #[test]
+    fn decode_extensions() {
+        assert_eq!(decode(0x00C4), Instruction::ScrollDown(4));
+        assert_eq!(decode(0x00FF), Instruction::Hires);
+        assert_eq!(decode(0x5232), Instruction::StoreRange(2, 3));
+        assert_eq!(decode(0xF201), Instruction::Plane(2));
+        assert_eq!(
+            decode_at(&[0xF0, 0x00, 0x12, 0x34], 0),
+            Instruction::LoadILong(0x1234)
+        );
+        assert!(decode(0xD120).is_schip());
+        assert!(!decode(0xD121).is_extension());
+    }
+
+    #[test]
+    fn display_cowgod() {
+        assert_eq!(decode(0x6A02).to_string(), "LD VA, 0x02");
+        assert_eq!(decode(0x2208).to_string(), "CALL 0x208");
+        assert_eq!(decode(0xF50A).to_string(), "LD V5, K");
+    }
+}
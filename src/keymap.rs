@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::romdb::sha1_hex;
+
+/// The usual layout: the left-hand 4x4 block of a QWERTY keyboard, indexed
+/// by CHIP-8 key.
+const DEFAULT: [&str; 16] = [
+    "X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V",
+];
+
+/// Controller buttons by CHIP-8 key: the D-pad on 2/4/6/8 as most games
+/// use them for directions, face buttons on the keys commonly used to fire
+/// or confirm.
+const DEFAULT_BUTTONS: [(u8, &str); 12] = [
+    (0x2, "dpup"),
+    (0x8, "dpdown"),
+    (0x4, "dpleft"),
+    (0x6, "dpright"),
+    (0x5, "a"),
+    (0x0, "b"),
+    (0x1, "x"),
+    (0x3, "y"),
+    (0x7, "leftshoulder"),
+    (0x9, "rightshoulder"),
+    (0xE, "back"),
+    (0xF, "start"),
+];
+
+/// One host key name or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HostKeys {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl HostKeys {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            HostKeys::One(name) => vec![name],
+            HostKeys::Many(names) => names,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    keys: BTreeMap<String, HostKeys>,
+    #[serde(default)]
+    buttons: BTreeMap<String, HostKeys>,
+    #[serde(default)]
+    rom: HashMap<String, RomSection>,
+}
+
+/// `[rom.<sha1>]`: key bindings, plus controller buttons in `buttons`.
+#[derive(Deserialize)]
+struct RomSection {
+    #[serde(default)]
+    buttons: BTreeMap<String, HostKeys>,
+    #[serde(flatten)]
+    keys: BTreeMap<String, HostKeys>,
+}
+
+/// Parse a CHIP-8 key written as a hex digit.
+pub fn parse_key(key: &str) -> Result<u8, String> {
+    u8::from_str_radix(key, 16)
+        .ok()
+        .filter(|k| *k < 16)
+        .ok_or_else(|| format!("invalid CHIP-8 key `{}`", key))
+}
+
+/// Host inputs bound to each CHIP-8 key, compared without regard to case.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Bindings([Vec<String>; 16]);
+
+impl Bindings {
+    fn bind(&mut self, key: u8, hosts: Vec<String>) {
+        for bound in self.0.iter_mut() {
+            bound.retain(|b| !hosts.iter().any(|h| h.eq_ignore_ascii_case(b)));
+        }
+        self.0[key as usize] = hosts;
+    }
+
+    fn add(&mut self, key: u8, host: &str) {
+        for bound in self.0.iter_mut() {
+            bound.retain(|b| !host.eq_ignore_ascii_case(b));
+        }
+        self.0[key as usize].push(host.to_string());
+    }
+
+    fn key_for(&self, name: &str) -> Option<u8> {
+        self.0
+            .iter()
+            .position(|names| names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+            .map(|k| k as u8)
+    }
+}
+
+/// Host keys and controller buttons bound to each CHIP-8 key. Host keys are
+/// SDL key names such as `Q`, `Up` or `Keypad 5`; buttons are SDL game
+/// controller button names such as `a`, `start` or `dpup`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    keys: Bindings,
+    buttons: Bindings,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keys = Bindings::default();
+        for (key, name) in keys.0.iter_mut().zip(DEFAULT.iter()) {
+            key.push(name.to_string());
+        }
+        let mut buttons = Bindings::default();
+        for (key, name) in DEFAULT_BUTTONS.iter() {
+            buttons.0[*key as usize].push(name.to_string());
+        }
+        Keymap { keys, buttons }
+    }
+}
+
+impl Keymap {
+    /// The default layout with the ROM database's `rom_keys` added, then the
+    /// global keymap file (`path`, or the user's if there is one) and its
+    /// section for `rom` applied, so that the user's own bindings win.
+    pub fn load(
+        path: Option<&Path>,
+        rom: &[u8],
+        rom_keys: &[(u8, &str)],
+    ) -> Result<Keymap, String> {
+        let mut keymap = Keymap::default();
+        for (key, host) in rom_keys {
+            keymap.add(*key, host);
+        }
+        let path = path
+            .map(Path::to_path_buf)
+            .or_else(|| user_path().filter(|p| p.exists()));
+        if let Some(path) = path {
+            let text = fs::read_to_string(&path)
+                .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            keymap
+                .merge_str(&text, &sha1_hex(rom))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(keymap)
+    }
+
+    /// Apply the `[keys]` and `[buttons]` tables of a keymap file, then the
+    /// `[rom.<sha1>]` table matching `sha1`.
+    pub fn merge_str(&mut self, toml: &str, sha1: &str) -> Result<(), String> {
+        let file: File = toml::from_str(toml).map_err(|e| e.to_string())?;
+        let rom = file
+            .rom
+            .into_iter()
+            .find(|(hash, _)| hash.eq_ignore_ascii_case(sha1))
+            .map(|(_, section)| (section.keys, section.buttons));
+        for (keys, buttons) in std::iter::once((file.keys, file.buttons)).chain(rom) {
+            for (key, hosts) in keys {
+                self.keys.bind(parse_key(&key)?, hosts.into_vec());
+            }
+            for (key, hosts) in buttons {
+                self.buttons.bind(parse_key(&key)?, hosts.into_vec());
+            }
+        }
+        Ok(())
+    }
+
+    /// Make `hosts` the only host keys for `key`, taking them away from
+    /// whatever CHIP-8 keys they were bound to before.
+    pub fn bind(&mut self, key: u8, hosts: Vec<String>) {
+        self.keys.bind(key, hosts);
+    }
+
+    /// Bind host key `host` to `key` as well as the keys already bound to it,
+    /// taking it away from whatever CHIP-8 key it was bound to before.
+    pub fn add(&mut self, key: u8, host: &str) {
+        self.keys.add(key, host);
+    }
+
+    /// As `bind`, for controller buttons.
+    pub fn bind_buttons(&mut self, key: u8, buttons: Vec<String>) {
+        self.buttons.bind(key, buttons);
+    }
+
+    /// Host keys bound to CHIP-8 key `key`.
+    pub fn bindings(&self, key: u8) -> &[String] {
+        &self.keys.0[key as usize]
+    }
+
+    /// Controller buttons bound to CHIP-8 key `key`.
+    pub fn button_bindings(&self, key: u8) -> &[String] {
+        &self.buttons.0[key as usize]
+    }
+
+    /// The CHIP-8 key host key `name` is bound to.
+    pub fn key_for(&self, name: &str) -> Option<u8> {
+        self.keys.key_for(name)
+    }
+
+    /// The CHIP-8 key controller button `name` is bound to.
+    pub fn key_for_button(&self, name: &str) -> Option<u8> {
+        self.buttons.key_for(name)
+    }
+}
+
+/// `keymap.toml` in `config::dir()`.
+pub fn user_path() -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("keymap.toml"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_layout() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.key_for("x"), Some(0x0));
+        assert_eq!(keymap.key_for("4"), Some(0xC));
+        assert_eq!(keymap.key_for("V"), Some(0xF));
+        assert_eq!(keymap.key_for("Up"), None);
+        assert_eq!(keymap.key_for_button("DPUP"), Some(0x2));
+        assert_eq!(keymap.key_for_button("guide"), None);
+    }
+
+    #[test]
+    fn global_and_rom_sections() {
+        let mut keymap = Keymap::default();
+        keymap
+            .merge_str(
+                r#"
+                [keys]
+                5 = ["Up", "Z"]
+                8 = "Down"
+
+                [buttons]
+                5 = ["dpup", "a"]
+
+                [rom.A9993E364706816ABA3E25717850C26C9CD0D89D]
+                8 = ["S", "Keypad 2"]
+                buttons = { 8 = "dpdown", c = "a" }
+
+                [rom.0000000000000000000000000000000000000000]
+                0 = "Up"
+                "#,
+                &sha1_hex(b"abc"),
+            )
+            .unwrap();
+        assert_eq!(keymap.bindings(5), ["Up", "Z"]);
+        // Z moved from A to 5.
+        assert!(keymap.bindings(0xA).is_empty());
+        assert_eq!(keymap.key_for("keypad 2"), Some(8));
+        assert_eq!(keymap.key_for("S"), Some(8));
+        assert_eq!(keymap.key_for("Down"), None);
+        assert_eq!(keymap.key_for("X"), Some(0));
+        assert_eq!(keymap.key_for_button("dpup"), Some(5));
+        assert_eq!(keymap.key_for_button("a"), Some(0xC));
+        assert_eq!(keymap.button_bindings(5), ["dpup"]);
+        assert!(keymap.button_bindings(0x2).is_empty());
+    }
+
+    #[test]
+    fn keymap_file_beats_rom_database() {
+        let mut keymap = Keymap::default();
+        // What `load` does with ROM database keys.
+        keymap.add(5, "Up");
+        keymap.add(8, "Down");
+        assert_eq!(keymap.bindings(5), ["W", "Up"]);
+        keymap
+            .merge_str(
+                r#"
+                [keys]
+                2 = "Up"
+
+                [rom.A9993E364706816ABA3E25717850C26C9CD0D89D]
+                4 = "Down"
+                "#,
+                &sha1_hex(b"abc"),
+            )
+            .unwrap();
+        assert_eq!(keymap.key_for("Up"), Some(2));
+        assert_eq!(keymap.key_for("Down"), Some(4));
+        assert_eq!(keymap.bindings(5), ["W"]);
+        assert_eq!(keymap.bindings(8), ["S"]);
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut keymap = Keymap::default();
+        assert!(keymap.merge_str("[keys]\nG = \"Up\"", "").is_err());
+        assert!(keymap.merge_str("[layout]\n", "").is_err());
+        assert!(keymap.merge_str("[keys]\n1 = 2", "").is_err());
+    }
+}
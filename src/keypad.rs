@@ -1,32 +1,19 @@
 const KEYS_NUM: usize = 16;
 
+/// State of the 16 CHIP-8 keys, addressed by their hex value, with the
+/// press and release edges seen since the end of the last frame.
 pub struct Keypad {
     keys: [bool; KEYS_NUM],
-}
-
-pub enum Key {
-    KeyX = 0,
-    Key1 = 1,
-    Key2 = 2,
-    Key3 = 3,
-    KeyQ = 4,
-    KeyW = 5,
-    KeyE = 6,
-    KeyA = 7,
-    KeyS = 8,
-    KeyD = 9,
-    KeyZ = 10,
-    KeyC = 11,
-    Key4 = 12,
-    KeyR = 13,
-    KeyF = 14,
-    KeyV = 15,
+    pressed: u16,
+    released: u16,
 }
 
 impl Keypad {
     pub fn new() -> Keypad {
         Keypad {
             keys: [false; KEYS_NUM],
+            pressed: 0,
+            released: 0,
         }
     }
 
@@ -34,19 +21,56 @@ impl Keypad {
         self.keys[key as usize]
     }
 
-    pub fn get_pressed(&self) -> Option<usize> {
-        self.keys
-            .iter()
-            .enumerate()
-            .find(|(_, k)| **k)
-            .map(|(i, _)| i)
+    /// Keys pressed during this frame, one bit per key.
+    pub fn pressed_edges(&self) -> u16 {
+        self.pressed
+    }
+
+    /// Keys released during this frame, one bit per key.
+    pub fn released_edges(&self) -> u16 {
+        self.released
+    }
+
+    /// Press CHIP-8 key `key` (0x0-0xF). Repeated presses of a held key
+    /// are not new edges.
+    pub fn press(&mut self, key: u8) {
+        if !self.keys[key as usize] {
+            self.pressed |= 1 << key;
+        }
+        self.keys[key as usize] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        if self.keys[key as usize] {
+            self.released |= 1 << key;
+        }
+        self.keys[key as usize] = false;
     }
 
-    pub fn set_pressed(&mut self, k: Key) {
-        self.keys[k as usize] = true;
+    /// Forget this frame's edges.
+    pub fn end_frame(&mut self) {
+        self.pressed = 0;
+        self.released = 0;
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    pub fn set_unpressed(&mut self, k: Key) {
-        self.keys[k as usize] = false;
+    #[test]
+    fn edges() {
+        let mut keypad = Keypad::new();
+        keypad.press(0xA);
+        keypad.press(0xA);
+        keypad.press(0x1);
+        assert_eq!(keypad.pressed_edges(), 1 << 0xA | 1 << 0x1);
+        keypad.end_frame();
+        assert_eq!(keypad.pressed_edges(), 0);
+        assert!(keypad.is_pressed(0xA));
+        keypad.release(0xA);
+        keypad.release(0x3);
+        assert_eq!(keypad.released_edges(), 1 << 0xA);
+        assert!(!keypad.is_pressed(0xA));
     }
 }
@@ -1,40 +1,101 @@
-mod chip;
+pub mod asm;
+pub mod cfg;
+pub mod chip;
+mod cli;
+pub mod config;
+pub mod coverage;
+pub mod detect;
+pub mod disasm;
+pub mod filter;
+pub mod flicker;
+pub mod history;
+pub mod instruction;
+pub mod keymap;
 mod keypad;
+pub mod lint;
 mod memory;
+pub mod octo;
+pub mod palette;
+pub mod platform;
+pub mod profile;
 mod random;
+pub mod recording;
 mod registers;
+pub mod romdb;
+pub mod screenshot;
 mod stack;
+pub mod timeline;
+pub mod trace;
+pub mod trace_diff;
 mod video;
 
 extern crate sdl2;
 
-use std::{thread, time::Duration};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    process, thread,
+    time::{Duration, Instant, SystemTime},
+};
 
-use chip::Chip;
 use sdl2::{
+    audio::{AudioCallback, AudioDevice, AudioSpecDesired},
+    controller::{Button, GameController},
     pixels::PixelFormatEnum,
     render::{Canvas, Texture},
-    video::Window,
-    Sdl,
+    video::{FullscreenType, Window},
+    GameControllerSubsystem, Sdl,
+};
+
+use crate::filter::Image;
+use crate::flicker::Deflicker;
+use crate::keymap::Keymap;
+use crate::keypad::Keypad;
+use crate::palette::Palette;
+use crate::profile::Profiler;
+use crate::recording::GifRecorder;
+use crate::timeline::Timeline;
+use sdl2::{
+    event::Event,
+    keyboard::{Keycode, Mod},
+    EventPump,
 };
 
-use crate::keypad::{Key, Keypad};
-use sdl2::{event::Event, keyboard::Keycode, EventPump};
+use video::{VIDEO_HEIGHT, VIDEO_WIDTH};
 
-use video::{PITCH, VIDEO_HEIGHT, VIDEO_WIDTH};
+const FRAME: Duration = Duration::from_micros(16_667);
+const BEEP_FREQUENCY: f32 = 440.0;
 
-fn make_canvas(context: &Sdl) -> Canvas<Window> {
-    let width = (10 * VIDEO_WIDTH) as u32;
-    let height = (10 * VIDEO_HEIGHT) as u32;
+/// A resizable window `scale` times the low resolution screen. The canvas
+/// keeps the screen's aspect ratio whatever the window size, with black
+/// bars around it.
+fn make_canvas(context: &Sdl, title: &str, scale: u32, fullscreen: bool) -> Canvas<Window> {
+    let width = scale * VIDEO_WIDTH as u32;
+    let height = scale * VIDEO_HEIGHT as u32;
 
-    let window = context
-        .video()
-        .unwrap()
-        .window("Chip-8 Emulator", width, height)
-        .position_centered()
-        .build()
+    let mut window = context.video().unwrap().window(title, width, height);
+    window.position_centered().resizable();
+    if fullscreen {
+        window.fullscreen_desktop();
+    }
+    let mut canvas = window.build().unwrap().into_canvas().build().unwrap();
+    canvas
+        .set_logical_size(VIDEO_WIDTH as u32, VIDEO_HEIGHT as u32)
         .unwrap();
-    window.into_canvas().build().unwrap()
+    canvas
+}
+
+fn toggle_fullscreen(canvas: &mut Canvas<Window>) {
+    let window = canvas.window_mut();
+    let state = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    if let Err(e) = window.set_fullscreen(state) {
+        eprintln!("Cannot switch fullscreen: {}", e);
+    }
 }
 
 fn update_texture<'a>(
@@ -55,88 +116,529 @@ fn update_texture<'a>(
     Ok(())
 }
 
-fn process_input(keypad: &mut Keypad, event_pump: &mut EventPump) -> bool {
-    let mut quit = false;
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. } => {
-                quit = true;
-            }
-            Event::KeyDown {
-                keycode: Some(k), ..
-            } => match k {
-                Keycode::Escape => {
-                    quit = true;
+/// Something the user asked the frontend to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Quit,
+    /// F11 or Alt+Enter.
+    ToggleFullscreen,
+    /// F12 for a PNG, Shift+F12 for a PBM.
+    Screenshot {
+        raw: bool,
+    },
+    /// F9 starts or stops recording a GIF.
+    ToggleRecording,
+    /// F6 pauses or resumes.
+    TogglePause,
+    /// F7 undoes one instruction, Shift+F7 runs back to a breakpoint or
+    /// watchpoint. Both pause.
+    StepBack {
+        to_break: bool,
+    },
+    /// F8 executes one instruction and pauses.
+    StepForward,
+}
+
+/// A held host key or controller button.
+#[derive(Clone, Copy, Hash, PartialEq, Eq)]
+enum Source {
+    Key(Keycode),
+    /// Button on the controller with this joystick instance id.
+    Button(u32, Button),
+}
+
+/// Host keys and buttons currently held, so that a CHIP-8 key bound to
+/// several of them is released only when the last one is, plus the open
+/// game controllers.
+struct Input {
+    keymap: Keymap,
+    held: HashSet<Source>,
+    subsystem: Option<GameControllerSubsystem>,
+    controllers: Vec<GameController>,
+}
+
+impl Input {
+    fn new(keymap: Keymap, context: &Sdl) -> Input {
+        for key in 0..16 {
+            for name in keymap.bindings(key) {
+                if Keycode::from_name(name).is_none() {
+                    eprintln!("Ignoring unknown key name `{}`", name);
+                }
+            }
+            for name in keymap.button_bindings(key) {
+                if Button::from_string(name).is_none() {
+                    eprintln!("Ignoring unknown controller button `{}`", name);
+                }
+            }
+        }
+        // Controllers already plugged in are reported as added on the first
+        // poll, just like the ones plugged in later.
+        let subsystem = context
+            .game_controller()
+            .map_err(|e| eprintln!("Controllers disabled: {}", e))
+            .ok();
+        Input {
+            keymap,
+            held: HashSet::new(),
+            subsystem,
+            controllers: Vec::new(),
+        }
+    }
+
+    fn key_for(&self, source: &Source) -> Option<u8> {
+        match source {
+            Source::Key(k) => self.keymap.key_for(&k.name()),
+            Source::Button(_, b) => self.keymap.key_for_button(&b.string()),
+        }
+    }
+
+    fn press(&mut self, keypad: &mut Keypad, source: Source) {
+        if let Some(key) = self.key_for(&source) {
+            self.held.insert(source);
+            keypad.press(key);
+        }
+    }
+
+    fn release(&mut self, keypad: &mut Keypad, source: Source) {
+        if let Some(key) = self.key_for(&source) {
+            self.held.remove(&source);
+            if !self.held.iter().any(|h| self.key_for(h) == Some(key)) {
+                keypad.release(key);
+            }
+        }
+    }
+
+    fn add_controller(&mut self, index: u32) {
+        let subsystem = match &self.subsystem {
+            Some(subsystem) => subsystem,
+            None => return,
+        };
+        match subsystem.open(index) {
+            Ok(controller) => {
+                eprintln!("Controller connected: {}", controller.name());
+                self.controllers.push(controller);
+            }
+            Err(e) => eprintln!("Cannot open controller {}: {}", index, e),
+        }
+    }
+
+    fn remove_controller(&mut self, keypad: &mut Keypad, id: u32) {
+        let held: Vec<Source> = self
+            .held
+            .iter()
+            .copied()
+            .filter(|h| matches!(h, Source::Button(which, _) if *which == id))
+            .collect();
+        for source in held {
+            self.release(keypad, source);
+        }
+        if let Some(index) = self.controllers.iter().position(|c| c.instance_id() == id) {
+            eprintln!(
+                "Controller disconnected: {}",
+                self.controllers[index].name()
+            );
+            self.controllers.remove(index);
+        }
+    }
+
+    /// Apply pending events to `keypad` and return the frontend actions
+    /// requested with hotkeys.
+    fn process(&mut self, keypad: &mut Keypad, event_pump: &mut EventPump) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => actions.push(Action::Quit),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => actions.push(Action::ToggleFullscreen),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => actions.push(Action::ToggleRecording),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => actions.push(Action::TogglePause),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    keymod,
+                    ..
+                } => actions.push(Action::StepBack {
+                    to_break: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+                }),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => actions.push(Action::StepForward),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => actions.push(Action::Screenshot {
+                    raw: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+                }),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => {
+                    actions.push(Action::ToggleFullscreen)
+                }
+                Event::KeyDown {
+                    keycode: Some(k), ..
+                } => self.press(keypad, Source::Key(k)),
+                Event::KeyUp {
+                    keycode: Some(k), ..
+                } => self.release(keypad, Source::Key(k)),
+                Event::ControllerButtonDown { which, button, .. } => {
+                    self.press(keypad, Source::Button(which, button))
+                }
+                Event::ControllerButtonUp { which, button, .. } => {
+                    self.release(keypad, Source::Button(which, button))
+                }
+                Event::ControllerDeviceAdded { which, .. } => self.add_controller(which),
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.remove_controller(keypad, which)
                 }
-                Keycode::X => keypad.set_pressed(Key::KeyX),
-                Keycode::Num1 => keypad.set_pressed(Key::Key1),
-                Keycode::Num2 => keypad.set_pressed(Key::Key2),
-                Keycode::Num3 => keypad.set_pressed(Key::Key3),
-                Keycode::Q => keypad.set_pressed(Key::KeyQ),
-                Keycode::W => keypad.set_pressed(Key::KeyW),
-                Keycode::E => keypad.set_pressed(Key::KeyE),
-                Keycode::A => keypad.set_pressed(Key::KeyA),
-                Keycode::S => keypad.set_pressed(Key::KeyS),
-                Keycode::D => keypad.set_pressed(Key::KeyD),
-                Keycode::Z => keypad.set_pressed(Key::KeyZ),
-                Keycode::C => keypad.set_pressed(Key::KeyC),
-                Keycode::Num4 => keypad.set_pressed(Key::Key4),
-                Keycode::R => keypad.set_pressed(Key::KeyR),
-                Keycode::F => keypad.set_pressed(Key::KeyF),
-                Keycode::V => keypad.set_pressed(Key::KeyV),
-                _ => (),
-            },
-
-            Event::KeyUp {
-                keycode: Some(k), ..
-            } => match k {
-                Keycode::X => keypad.set_unpressed(Key::KeyX),
-                Keycode::Num1 => keypad.set_unpressed(Key::Key1),
-                Keycode::Num2 => keypad.set_unpressed(Key::Key2),
-                Keycode::Num3 => keypad.set_unpressed(Key::Key3),
-                Keycode::Q => keypad.set_unpressed(Key::KeyQ),
-                Keycode::W => keypad.set_unpressed(Key::KeyW),
-                Keycode::E => keypad.set_unpressed(Key::KeyE),
-                Keycode::A => keypad.set_unpressed(Key::KeyA),
-                Keycode::S => keypad.set_unpressed(Key::KeyS),
-                Keycode::D => keypad.set_unpressed(Key::KeyD),
-                Keycode::Z => keypad.set_unpressed(Key::KeyZ),
-                Keycode::C => keypad.set_unpressed(Key::KeyC),
-                Keycode::Num4 => keypad.set_unpressed(Key::Key4),
-                Keycode::R => keypad.set_unpressed(Key::KeyR),
-                Keycode::F => keypad.set_unpressed(Key::KeyF),
-                Keycode::V => keypad.set_unpressed(Key::KeyV),
                 _ => (),
-            },
-            _ => (),
+            }
+        }
+        actions
+    }
+}
+
+/// An animated GIF being recorded, and where it goes.
+type Recording = (PathBuf, GifRecorder<BufWriter<File>>);
+
+fn start_recording(
+    path: PathBuf,
+    palette: &Palette,
+    resolution: (usize, usize),
+    scale: u32,
+) -> Option<Recording> {
+    match GifRecorder::create(&path, palette, resolution, scale as usize) {
+        Ok(recorder) => {
+            println!("Recording {}", path.display());
+            Some((path, recorder))
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            None
         }
     }
-    quit
+}
+
+/// Print the next instruction and the machine state, as in traces.
+fn show_state(chip8: &chip::Chip) {
+    let state = chip8.state();
+    let instruction = instruction::decode_at(chip8.memory(), state.pc as usize);
+    println!("{}", state.to_text(&instruction));
+}
+
+fn stop_recording((path, recorder): Recording) {
+    match recorder.finish() {
+        Ok(_) => println!("Saved {}", path.display()),
+        Err(e) => eprintln!("Cannot write {}: {}", path.display(), e),
+    }
+}
+
+/// Square wave played while the sound timer is non-zero.
+struct Beep {
+    phase: f32,
+    step: f32,
+    volume: f32,
+}
+
+impl AudioCallback for Beep {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = if self.phase < 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.step) % 1.0;
+        }
+    }
+}
+
+fn open_audio(context: &Sdl, volume: f32) -> Result<AudioDevice<Beep>, String> {
+    let desired = AudioSpecDesired {
+        freq: Some(44_100),
+        channels: Some(1),
+        samples: None,
+    };
+    context.audio()?.open_playback(None, &desired, |spec| Beep {
+        phase: 0.0,
+        step: BEEP_FREQUENCY / spec.freq as f32,
+        volume,
+    })
 }
 
 pub fn run(args: Vec<String>) {
-    let mut chip8 = Chip::new();
-    chip8.load_rom(&args[1]);
+    let rest = args.get(2..).unwrap_or(&[]);
+    let options = match args.get(1).map(String::as_str) {
+        Some("-h") | Some("--help") | Some("help") => return println!("{}", cli::USAGE),
+        None => {
+            eprintln!("{}", cli::USAGE);
+            process::exit(2);
+        }
+        Some("run") => cli::RunOptions::parse_or_exit(rest),
+        Some("disasm") => return cli::disasm(rest),
+        Some("asm") => return cli::asm(rest),
+        Some("info") => return cli::info(rest),
+        Some("lint") => return cli::lint(rest),
+        Some("bench") => return cli::bench(rest),
+        Some("test") => return cli::test(rest),
+        Some("trace-diff") => return cli::trace_diff(rest),
+        Some(_) => cli::RunOptions::parse_or_exit(&args[1..]),
+    };
+    let (mut chip8, settings, entry) = cli::load(
+        &options.rom,
+        options.settings,
+        options.config.as_deref(),
+        options.romdb.as_deref(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if let Some(trace) = options.trace {
+        match trace.create() {
+            Ok(tracer) => chip8.set_tracer(tracer),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    if options.profile || options.profile_csv.is_some() || options.profile_folded.is_some() {
+        chip8.set_profiler(Profiler::new());
+    }
+    if let Some(mode) = options.quirk_detection {
+        chip8.enable_quirk_detection(mode);
+    }
+    if let Some(capacity) = options.history {
+        chip8.enable_history(capacity);
+    }
+    if options.coverage.is_some() || options.coverage_image.is_some() {
+        chip8.enable_coverage();
+    }
+    if let Some(path) = &options.timeline {
+        match Timeline::create(path) {
+            Ok(timeline) => chip8.set_timeline(timeline),
+            Err(e) => {
+                eprintln!("Cannot create {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let tick_rate = settings.ipf();
+    let rom_keys = entry.key_overrides().unwrap_or_else(|e| {
+        eprintln!("Ignoring ROM key mapping: {}", e);
+        Vec::new()
+    });
+    let keymap =
+        Keymap::load(settings.keymap.as_deref(), chip8.rom(), &rom_keys).unwrap_or_else(|e| {
+            eprintln!("Ignoring keymap file: {}", e);
+            let mut keymap = Keymap::default();
+            for (key, host) in rom_keys.iter() {
+                keymap.add(*key, host);
+            }
+            keymap
+        });
+    let title = match entry.display_name() {
+        Some(name) => format!("Chip-8 Emulator - {}", name),
+        None => "Chip-8 Emulator".to_string(),
+    };
+    let palette = settings.palette();
+    let mut deflicker = Deflicker::new(settings.anti_flicker(), settings.decay());
+    let filters = settings.filter.clone().unwrap_or_default();
+    let vblank = settings.vblank();
+
     let context = sdl2::init().unwrap();
-    let mut canvas = make_canvas(&context);
+    let mut input = Input::new(keymap, &context);
+    let mut canvas = make_canvas(
+        &context,
+        &title,
+        settings.scale(),
+        settings.fullscreen.unwrap_or(false),
+    );
     let texture_creator = canvas.texture_creator();
 
-    let mut texture = texture_creator
-        .create_texture_streaming(
-            PixelFormatEnum::RGBA8888,
-            VIDEO_WIDTH as u32,
-            VIDEO_HEIGHT as u32,
-        )
-        .unwrap();
+    let new_texture = |(width, height): (usize, usize)| {
+        texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB888, width as u32, height as u32)
+            .unwrap()
+    };
+    let mut resolution = chip8.resolution();
+    let mut texture_size = resolution;
+    let mut texture = new_texture(texture_size);
 
+    let audio = if settings.mute.unwrap_or(false) {
+        None
+    } else {
+        open_audio(&context, settings.volume())
+            .map_err(|e| eprintln!("Sound disabled: {}", e))
+            .ok()
+    };
     let mut event_pump = context.event_pump().unwrap();
+    let mut recording = options.record_gif.as_ref().and_then(|path| {
+        start_recording(
+            PathBuf::from(path),
+            &palette,
+            chip8.resolution(),
+            settings.scale(),
+        )
+    });
+
+    let mut paused = false;
+    'frames: loop {
+        let started = Instant::now();
+        for action in input.process(chip8.get_keypad(), &mut event_pump) {
+            match action {
+                Action::Quit => break 'frames,
+                Action::ToggleFullscreen => toggle_fullscreen(&mut canvas),
+                Action::Screenshot { raw } => {
+                    let extension = if raw { "pbm" } else { "png" };
+                    let path = screenshot::next_path(
+                        &settings.save_dir(),
+                        &options.rom,
+                        SystemTime::now(),
+                        extension,
+                    );
+                    match chip8.save_screenshot(&path, &palette, settings.scale() as usize) {
+                        Ok(()) => println!("Saved {}", path.display()),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                }
+                Action::ToggleRecording => match recording.take() {
+                    Some(recording) => stop_recording(recording),
+                    None => {
+                        let path = screenshot::next_path(
+                            &settings.save_dir(),
+                            &options.rom,
+                            SystemTime::now(),
+                            "gif",
+                        );
+                        recording =
+                            start_recording(path, &palette, chip8.resolution(), settings.scale());
+                    }
+                },
+                Action::TogglePause => {
+                    paused = !paused;
+                    println!("{}", if paused { "Paused" } else { "Resumed" });
+                }
+                Action::StepBack { .. } if options.history.is_none() => {
+                    eprintln!("Run with --history <n> to step back");
+                }
+                Action::StepBack { to_break } => {
+                    paused = true;
+                    let stopped = if to_break {
+                        chip8.reverse_continue(&options.breakpoints, &options.watchpoints)
+                    } else {
+                        chip8.step_back()
+                    };
+                    if !stopped {
+                        println!("Reached the start of the history");
+                    }
+                    show_state(&chip8);
+                }
+                Action::StepForward => {
+                    paused = true;
+                    chip8.step();
+                    show_state(&chip8);
+                }
+            }
+        }
+        let mut present = |chip8: &chip::Chip| {
+            if chip8.resolution() != resolution {
+                resolution = chip8.resolution();
+                canvas
+                    .set_logical_size(resolution.0 as u32, resolution.1 as u32)
+                    .unwrap();
+            }
+            let mut pixels = Vec::new();
+            deflicker.apply(chip8.framebuffer(), &palette, &mut pixels);
+            let image = filters.apply(Image::new(resolution.0, resolution.1, pixels));
+            if (image.width, image.height) != texture_size {
+                texture_size = (image.width, image.height);
+                texture = new_texture(texture_size);
+            }
+            update_texture(&mut texture, &image.pixels, 4 * image.width, &mut canvas).unwrap();
+        };
+        if !paused {
+            for _ in 0..tick_rate {
+                chip8.step();
+                if !vblank {
+                    present(&chip8);
+                }
+            }
+            chip8.tick_timers();
+            if let Some((_, recorder)) = &mut recording {
+                if let Err(e) = recorder.frame(chip8.framebuffer(), chip8.resolution()) {
+                    eprintln!("Recording stopped: {}", e);
+                    recording = None;
+                }
+            }
+        }
+        if paused || vblank {
+            present(&chip8);
+        }
+        if let Some(audio) = &audio {
+            if !paused && chip8.state().sound_timer > 0 {
+                audio.resume();
+            } else {
+                audio.pause();
+            }
+        }
+        if let Some(rest) = FRAME.checked_sub(started.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+    if let Some(recording) = recording {
+        stop_recording(recording);
+    }
+
+    if let Some(coverage) = chip8.coverage() {
+        if let Some(path) = &options.coverage {
+            if let Err(e) = coverage.save_listing(chip8.rom(), path) {
+                eprintln!("Cannot write {}: {}", path, e);
+            }
+        }
+        if let Some(path) = &options.coverage_image {
+            if let Err(e) = coverage.save_image(chip8.rom().len(), path) {
+                eprintln!("Cannot write {}: {}", path, e);
+            }
+        }
+    }
 
-    loop {
-        if process_input(chip8.get_keypad(), &mut event_pump) {
-            break;
+    if let Some(profiler) = chip8.profiler() {
+        if options.profile {
+            print!("{}", profiler.report());
+        }
+        if let Some(path) = &options.profile_csv {
+            if let Err(e) = profiler.save_csv(path) {
+                eprintln!("Cannot write {}: {}", path, e);
+            }
+        }
+        if let Some(path) = &options.profile_folded {
+            if let Err(e) = profiler.save_folded(path) {
+                eprintln!("Cannot write {}: {}", path, e);
+            }
         }
-        chip8.cycle();
-        update_texture(&mut texture, chip8.get_video().buffer(), PITCH, &mut canvas).unwrap();
-        thread::sleep(Duration::from_millis(10u64));
     }
 }
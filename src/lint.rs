@@ -0,0 +1,325 @@
+use std::fmt;
+
+use crate::disasm::Analysis;
+use crate::instruction::Instruction;
+use crate::platform::Platform;
+use crate::video::{HIRES_HEIGHT, HIRES_WIDTH, VIDEO_HEIGHT, VIDEO_WIDTH};
+
+/// Instructions read after `Fx55`/`Fx65` before deciding I is not relied on.
+const LOOKAHEAD: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// `8xy6`/`8xyE` with X≠Y.
+    Shift,
+    /// `Fx55`/`Fx65` followed by an instruction using I before it is set.
+    MemoryThenI,
+    /// `Bnnn` where the high nibble of nnn is not zero.
+    JumpHighNibble,
+    /// Sprite drawn at a constant position that crosses the screen edge.
+    EdgeSprite,
+    /// `0nnn` call into machine code.
+    MachineCode,
+    SuperChipOpcode,
+    XoChipOpcode,
+}
+
+impl Kind {
+    fn explanation(&self) -> &'static str {
+        match self {
+            Kind::Shift => "shifts VY on COSMAC VIP and XO-CHIP, VX in place on SUPER-CHIP",
+            Kind::MemoryThenI => {
+                "relies on I after a register load/store; it advances on COSMAC VIP and XO-CHIP only"
+            }
+            Kind::JumpHighNibble => "jumps to V0 + nnn on COSMAC VIP, to VX + nn on SUPER-CHIP",
+            Kind::EdgeSprite => "sprite crosses the screen edge; clipped on VIP/SUPER-CHIP, wrapped on XO-CHIP",
+            Kind::MachineCode => "calls RCA 1802 machine code, which cannot be emulated",
+            Kind::SuperChipOpcode => "SUPER-CHIP opcode",
+            Kind::XoChipOpcode => "XO-CHIP opcode",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub at: u16,
+    pub instruction: Instruction,
+    pub kind: Kind,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#05x}: {:<20} {}",
+            self.at,
+            self.instruction.to_string(),
+            self.kind.explanation()
+        )
+    }
+}
+
+pub struct Report {
+    pub findings: Vec<Finding>,
+    pub platform: Platform,
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in self.findings.iter() {
+            writeln!(f, "{}", finding)?;
+        }
+        if !self.findings.is_empty() {
+            writeln!(f)?;
+        }
+        writeln!(
+            f,
+            "Suggested platform: {} ({})",
+            self.platform,
+            self.platform.description()
+        )?;
+        for reason in self.reasons.iter() {
+            writeln!(f, "    {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+fn uses_i(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Draw(..)
+            | Instruction::Store(_)
+            | Instruction::Load(_)
+            | Instruction::Bcd(_)
+            | Instruction::AddI(_)
+            | Instruction::StoreRange(..)
+            | Instruction::LoadRange(..)
+            | Instruction::Audio
+    )
+}
+
+fn sets_i(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::LoadI(_)
+            | Instruction::LoadILong(_)
+            | Instruction::LoadFont(_)
+            | Instruction::LoadBigFont(_)
+    )
+}
+
+/// Report platform-dependent instructions in the code reachable from 0x200
+/// and suggest the platform the ROM was most likely written for.
+pub fn lint(rom: &[u8]) -> Report {
+    let analysis = Analysis::new(rom);
+    let code: Vec<(u16, Instruction)> = analysis
+        .instructions
+        .iter()
+        .map(|(a, i)| (*a, *i))
+        .collect();
+    let mut findings = Vec::new();
+    // The resolution is not known statically: take the one set by the
+    // last `HIGH`/`LOW` before each sprite in address order.
+    let mut resolution = (VIDEO_WIDTH, VIDEO_HEIGHT);
+    // Register values known from `LD Vx, kk` earlier in straight-line code.
+    let mut known: [Option<u8>; 16] = [None; 16];
+    let mut next_address = None;
+
+    for (index, (at, instruction)) in code.iter().enumerate() {
+        if next_address != Some(*at) {
+            known = [None; 16];
+        }
+        next_address = Some(*at + instruction.size());
+        let mut report = |kind| {
+            findings.push(Finding {
+                at: *at,
+                instruction: *instruction,
+                kind,
+            })
+        };
+        if instruction.is_xochip() {
+            report(Kind::XoChipOpcode);
+        } else if instruction.is_schip() {
+            report(Kind::SuperChipOpcode);
+        }
+        match *instruction {
+            Instruction::ShiftRight(x, y) | Instruction::ShiftLeft(x, y) if x != y => {
+                report(Kind::Shift)
+            }
+            Instruction::JumpV0(addr) if addr & 0xF00 != 0 => report(Kind::JumpHighNibble),
+            Instruction::Sys(_) => report(Kind::MachineCode),
+            Instruction::Store(_) | Instruction::Load(_) => {
+                let following = code[index + 1..]
+                    .iter()
+                    .take(LOOKAHEAD)
+                    .take_while(|(_, i)| !sets_i(i));
+                for (_, next) in following {
+                    if uses_i(next) {
+                        report(Kind::MemoryThenI);
+                        break;
+                    }
+                    if next.target().is_some() || next.ends_flow() {
+                        break;
+                    }
+                }
+            }
+            Instruction::Draw(x, y, n) if n > 0 => {
+                if let (Some(vx), Some(vy)) = (known[x as usize], known[y as usize]) {
+                    let (width, height) = resolution;
+                    let vx = vx as usize % width;
+                    let vy = vy as usize % height;
+                    if vx + 8 > width || vy + n as usize > height {
+                        report(Kind::EdgeSprite);
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        match *instruction {
+            Instruction::LoadByte(x, kk) => known[x as usize] = Some(kk),
+            Instruction::AddByte(x, kk) => {
+                known[x as usize] = known[x as usize].map(|v| v.wrapping_add(kk))
+            }
+            Instruction::LoadDelay(x)
+            | Instruction::WaitKey(x)
+            | Instruction::Random(x, _)
+            | Instruction::LoadReg(x, _)
+            | Instruction::Or(x, _)
+            | Instruction::And(x, _)
+            | Instruction::Xor(x, _)
+            | Instruction::AddReg(x, _)
+            | Instruction::Sub(x, _)
+            | Instruction::SubN(x, _)
+            | Instruction::ShiftRight(x, _)
+            | Instruction::ShiftLeft(x, _) => {
+                known[x as usize] = None;
+                known[0xF] = None;
+            }
+            Instruction::Draw(..) => known[0xF] = None,
+            Instruction::Hires => resolution = (HIRES_WIDTH, HIRES_HEIGHT),
+            Instruction::Lores => resolution = (VIDEO_WIDTH, VIDEO_HEIGHT),
+            Instruction::Load(_)
+            | Instruction::LoadRange(..)
+            | Instruction::LoadFlags(_)
+            | Instruction::Call(_) => known = [None; 16],
+            _ => (),
+        }
+        // Whatever follows a branch can also be reached from elsewhere.
+        if instruction.target().is_some() || instruction.is_skip() || instruction.ends_flow() {
+            known = [None; 16];
+        }
+    }
+
+    let (platform, reasons) = suggest(&findings);
+    Report {
+        findings,
+        platform,
+        reasons,
+    }
+}
+
+fn suggest(findings: &[Finding]) -> (Platform, Vec<String>) {
+    let count = |kind| findings.iter().filter(|f| f.kind == kind).count();
+    let mut reasons = Vec::new();
+    let mut note = |n: usize, what: &str| {
+        if n > 0 {
+            reasons.push(format!("{} {}", n, what));
+        }
+    };
+
+    let xo = count(Kind::XoChipOpcode);
+    let schip = count(Kind::SuperChipOpcode);
+    let shifts = count(Kind::Shift);
+    let memory = count(Kind::MemoryThenI);
+    let jumps = count(Kind::JumpHighNibble);
+    note(xo, "XO-CHIP opcode(s)");
+    note(schip, "SUPER-CHIP opcode(s)");
+    note(
+        shifts,
+        "shift(s) with X≠Y, written for VY-shifting interpreters",
+    );
+    note(memory, "register load/store(s) relying on I advancing");
+    note(jumps, "Bnnn jump(s) with a non-zero X nibble");
+    note(
+        count(Kind::EdgeSprite),
+        "sprite(s) drawn across the screen edge",
+    );
+    note(count(Kind::MachineCode), "machine code call(s)");
+
+    let platform = if xo > 0 {
+        Platform::XoChip
+    } else if schip > 0 || (jumps > 0 && shifts == 0 && memory == 0) {
+        Platform::SuperChip
+    } else {
+        Platform::Chip8
+    };
+    if reasons.is_empty() {
+        reasons.push("no platform-specific instructions found".to_string());
+    }
+    (platform, reasons)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(rom: &[u8]) -> Vec<(u16, Kind)> {
+        lint(rom).findings.iter().map(|f| (f.at, f.kind)).collect()
+    }
+
+    #[test]
+    fn vip_rom() {
+        // SHR V1, V2; LD I, 0x300; LD [I], V1; DRW V0, V0, 1; SYS 0x123
+        let rom = [0x81, 0x26, 0xA3, 0x00, 0xF1, 0x55, 0xD0, 0x01, 0x01, 0x23];
+        assert_eq!(
+            kinds(&rom),
+            vec![
+                (0x200, Kind::Shift),
+                (0x204, Kind::MemoryThenI),
+                (0x208, Kind::MachineCode)
+            ]
+        );
+        let report = lint(&rom);
+        assert_eq!(report.platform, Platform::Chip8);
+        assert!(report
+            .to_string()
+            .contains("Suggested platform: chip8 (CHIP-8 (COSMAC VIP))"));
+    }
+
+    #[test]
+    fn edge_sprite_and_extensions() {
+        // LD V0, 60; LD V1, 0; DRW V0, V1, 5; HIGH; JP V0, 0x300
+        let rom = [0x60, 0x3C, 0x61, 0x00, 0xD0, 0x15, 0x00, 0xFF, 0xB3, 0x00];
+        assert_eq!(
+            kinds(&rom),
+            vec![
+                (0x204, Kind::EdgeSprite),
+                (0x206, Kind::SuperChipOpcode),
+                (0x208, Kind::JumpHighNibble)
+            ]
+        );
+        assert_eq!(lint(&rom).platform, Platform::SuperChip);
+
+        // HIGH; LD V0, 60; DRW V0, V0, 1: inside the 128x64 screen.
+        let rom = [0x00, 0xFF, 0x60, 0x3C, 0xD0, 0x01];
+        assert!(!kinds(&rom).contains(&(0x204, Kind::EdgeSprite)));
+
+        // PLANE 1
+        assert_eq!(lint(&[0xF1, 0x01]).platform, Platform::XoChip);
+    }
+
+    #[test]
+    fn clean_rom() {
+        // LD V0, 1; JP 0x202
+        let report = lint(&[0x60, 0x01, 0x12, 0x02]);
+        assert!(report.findings.is_empty());
+        assert_eq!(report.platform, Platform::Chip8);
+        assert_eq!(
+            report.reasons,
+            vec!["no platform-specific instructions found"]
+        );
+    }
+}
@@ -1,6 +1,6 @@
-use std::{fs, usize};
 const MEMORY_SIZE: usize = 4096;
-const START_ADDRESS: usize = 0x200;
+pub const START_ADDRESS: usize = 0x200;
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - START_ADDRESS;
 const FONTSET_START_ADDRESS: usize = 0x50;
 
 const FONTSET: [u8; 80] = [
@@ -48,18 +48,12 @@ impl Memory {
         opcode
     }
 
-    // TODO: move out of module this function
-    pub fn load_rom(&mut self, filename: &str) {
-        let rom_data = fs::read(filename).expect("Error loading ROM file");
-        self.load_instructions(&rom_data);
-    }
-
     pub fn load_instructions(&mut self, data: &[u8]) {
         if data.len() > self.buf.len() - START_ADDRESS {
             panic!("ROM file exeeds max allowed size")
         }
 
-        self.buf[START_ADDRESS..START_ADDRESS + data.len()].copy_from_slice(&data);
+        self.buf[START_ADDRESS..START_ADDRESS + data.len()].copy_from_slice(data);
     }
 
     pub fn set_address(&mut self, addr: u16) {
@@ -82,6 +76,14 @@ impl Memory {
         &self.buf[from..to]
     }
 
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        self.buf[address as usize] = value;
+    }
+
     pub fn set_index_register_to_font_no(&mut self, font_no: u8) {
         self.index_register = (FONTSET_START_ADDRESS + (5 * (font_no as usize))) as u16;
     }
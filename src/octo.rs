@@ -0,0 +1,1135 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+
+use crate::asm::Error;
+use crate::memory::{MAX_ROM_SIZE, START_ADDRESS};
+
+/// Compiled Octo program, loaded at 0x200. `source_map` maps the address of
+/// every emitted instruction or data byte to its source line.
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub source_map: BTreeMap<u16, usize>,
+}
+
+pub fn compile(source: &str) -> Result<Program, Error> {
+    Compiler::new("<input>", source)?.run()
+}
+
+pub fn compile_file(path: &Path) -> Result<Program, Error> {
+    let file = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|e| Error {
+        file: file.clone(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    Compiler::new(&file, &source)?.run()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+#[derive(Clone, Copy)]
+enum Patch {
+    Addr12,
+    Long,
+    Unpack(u8),
+}
+
+struct Fixup {
+    at: u16,
+    patch: Patch,
+    label: Token,
+}
+
+enum Control {
+    If(u16),
+    Else(u16),
+    Loop(u16, Vec<u16>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Key,
+    NotKey,
+}
+
+impl Cmp {
+    fn negate(self) -> Cmp {
+        match self {
+            Cmp::Eq => Cmp::Ne,
+            Cmp::Ne => Cmp::Eq,
+            Cmp::Lt => Cmp::Ge,
+            Cmp::Ge => Cmp::Lt,
+            Cmp::Gt => Cmp::Le,
+            Cmp::Le => Cmp::Gt,
+            Cmp::Key => Cmp::NotKey,
+            Cmp::NotKey => Cmp::Key,
+        }
+    }
+}
+
+enum Rhs {
+    Reg(u8),
+    Byte(u8),
+}
+
+struct Compiler {
+    file: String,
+    tokens: VecDeque<Token>,
+    rom: Vec<u8>,
+    here: u16,
+    /// Where the statement being compiled starts.
+    line: usize,
+    column: usize,
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    control: Vec<(Control, Token)>,
+    source_map: BTreeMap<u16, usize>,
+}
+
+impl Compiler {
+    fn new(file: &str, source: &str) -> Result<Compiler, Error> {
+        let mut compiler = Compiler {
+            file: file.to_string(),
+            tokens: VecDeque::new(),
+            rom: Vec::new(),
+            here: START_ADDRESS as u16,
+            line: 1,
+            column: 1,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            control: Vec::new(),
+            source_map: BTreeMap::new(),
+        };
+        compiler.tokens = compiler.lex(source)?;
+        Ok(compiler)
+    }
+
+    fn lex(&self, source: &str) -> Result<VecDeque<Token>, Error> {
+        let mut tokens = VecDeque::new();
+        for (n, text) in source.lines().enumerate() {
+            let chars: Vec<char> = text.chars().collect();
+            let mut i = 0;
+            while i < chars.len() {
+                if chars[i] == '#' {
+                    break;
+                }
+                if chars[i].is_whitespace() {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                if chars[i] == '"' {
+                    i += 1;
+                    while i < chars.len() && chars[i] != '"' {
+                        i += 1;
+                    }
+                    if i == chars.len() {
+                        return Err(self.error_at(n + 1, start + 1, "unterminated string"));
+                    }
+                    i += 1;
+                } else if chars[i] == '{' || chars[i] == '}' {
+                    i += 1;
+                } else {
+                    while i < chars.len()
+                        && !chars[i].is_whitespace()
+                        && chars[i] != '{'
+                        && chars[i] != '}'
+                    {
+                        i += 1;
+                    }
+                }
+                tokens.push_back(Token {
+                    text: chars[start..i].iter().collect(),
+                    line: n + 1,
+                    column: start + 1,
+                });
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn error_at(&self, line: usize, column: usize, message: impl Into<String>) -> Error {
+        Error {
+            file: self.file.clone(),
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    fn error(&self, token: &Token, message: impl Into<String>) -> Error {
+        self.error_at(token.line, token.column, message)
+    }
+
+    fn run(mut self) -> Result<Program, Error> {
+        let starts_with_main = self.tokens.front().map(|t| t.text.as_str()) == Some(":")
+            && self.tokens.get(1).map(|t| t.text.as_str()) == Some("main");
+        if !starts_with_main {
+            let main = Token {
+                text: "main".to_string(),
+                line: 1,
+                column: 1,
+            };
+            self.emit_address(0x1000, main)?;
+        }
+
+        while let Some(token) = self.tokens.pop_front() {
+            self.line = token.line;
+            self.column = token.column;
+            self.statement(token)?;
+        }
+
+        if let Some((_, token)) = self.control.last() {
+            let message = format!("`{}` is never closed", token.text);
+            return Err(self.error(token, message));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let addr = match self.labels.get(&fixup.label.text) {
+                Some(addr) => *addr,
+                None => {
+                    let message = format!("undefined label `{}`", fixup.label.text);
+                    return Err(self.error(&fixup.label, message));
+                }
+            };
+            let at = (fixup.at as usize) - START_ADDRESS;
+            match fixup.patch {
+                Patch::Addr12 => {
+                    if addr > 0xFFF {
+                        let message = format!("`{}` is beyond 0xFFF", fixup.label.text);
+                        return Err(self.error(&fixup.label, message));
+                    }
+                    self.rom[at] |= (addr >> 8) as u8;
+                    self.rom[at + 1] = addr as u8;
+                }
+                Patch::Long => {
+                    self.rom[at] = (addr >> 8) as u8;
+                    self.rom[at + 1] = addr as u8;
+                }
+                Patch::Unpack(nibble) => {
+                    self.rom[at + 1] = (nibble << 4).wrapping_add((addr >> 8) as u8);
+                    self.rom[at + 3] = addr as u8;
+                }
+            }
+        }
+
+        Ok(Program {
+            rom: self.rom,
+            source_map: self.source_map,
+        })
+    }
+
+    fn next(&mut self, after: &Token) -> Result<Token, Error> {
+        self.tokens.pop_front().ok_or_else(|| {
+            self.error(
+                after,
+                format!("unexpected end of input after `{}`", after.text),
+            )
+        })
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().map(|t| t.text == text).unwrap_or(false)
+    }
+
+    fn expect(&mut self, after: &Token, text: &str) -> Result<Token, Error> {
+        let token = self.next(after)?;
+        if token.text != text {
+            let message = format!("expected `{}`, found `{}`", text, token.text);
+            return Err(self.error(&token, message));
+        }
+        Ok(token)
+    }
+
+    /// Emit `byte` at `here`, which must be free memory.
+    fn emit_byte(&mut self, byte: u8) -> Result<(), Error> {
+        let offset = self.here as usize - START_ADDRESS;
+        if offset >= MAX_ROM_SIZE {
+            return Err(self.error_at(self.line, self.column, "program does not fit in memory"));
+        }
+        if self.source_map.contains_key(&self.here) {
+            let message = format!("address {:#05x} is already used", self.here);
+            return Err(self.error_at(self.line, self.column, message));
+        }
+        if self.rom.len() <= offset {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.source_map.insert(self.here, self.line);
+        self.here += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, opcode: u16) -> Result<(), Error> {
+        self.emit_byte((opcode >> 8) as u8)?;
+        self.emit_byte(opcode as u8)
+    }
+
+    /// Emit `opcode | addr`, patching it later if `token` is a forward label.
+    fn emit_address(&mut self, opcode: u16, token: Token) -> Result<(), Error> {
+        match self.known_value(&token)? {
+            Some(value) => {
+                let addr = self.ranged(&token, value, 0, 0xFFF)?;
+                self.emit(opcode | addr)?;
+            }
+            None => {
+                self.fixups.push(Fixup {
+                    at: self.here,
+                    patch: Patch::Addr12,
+                    label: token,
+                });
+                self.emit(opcode)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, token: &Token, addr: u16) -> Result<(), Error> {
+        if self.labels.contains_key(&token.text) || self.consts.contains_key(&token.text) {
+            let message = format!("`{}` is already defined", token.text);
+            return Err(self.error(token, message));
+        }
+        self.labels.insert(token.text.clone(), addr);
+        Ok(())
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), Error> {
+        if let Some(m) = self.macros.get(&token.text) {
+            let names = m.args.clone();
+            let body = m.body.clone();
+            let mut args = HashMap::new();
+            for name in names {
+                let value = self.next(&token)?;
+                args.insert(name, value.text);
+            }
+            // Expanded tokens report the invocation site in errors and the source map.
+            for t in body.into_iter().rev() {
+                let text = args.get(&t.text).cloned().unwrap_or(t.text);
+                self.tokens.push_front(Token {
+                    text,
+                    ..token.clone()
+                });
+            }
+            return Ok(());
+        }
+
+        if let Some(x) = self.register(&token) {
+            return self.register_statement(x, &token);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.next(&token)?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.next(&token)?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":const" => {
+                let name = self.next(&token)?;
+                let value_token = self.next(&name)?;
+                let value = self.value(&value_token)?;
+                self.consts.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.next(&token)?;
+                let open = self.expect(&name, "{")?;
+                let value = self.calc(&open)?;
+                self.consts.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.next(&token)?;
+                let reg = self.next(&name)?;
+                let x = self.expect_register(&reg)?;
+                self.aliases.insert(name.text, x);
+            }
+            ":macro" => {
+                let name = self.next(&token)?;
+                let mut args = Vec::new();
+                loop {
+                    let arg = self.next(&name)?;
+                    if arg.text == "{" {
+                        break;
+                    }
+                    args.push(arg.text);
+                }
+                let mut body = Vec::new();
+                let mut depth = 1;
+                loop {
+                    let t = self.next(&name)?;
+                    match t.text.as_str() {
+                        "{" => depth += 1,
+                        "}" => depth -= 1,
+                        _ => (),
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                    body.push(t);
+                }
+                self.macros.insert(name.text, Macro { args, body });
+            }
+            ":org" => {
+                let value_token = self.next(&token)?;
+                let value = self.value(&value_token)?;
+                self.here = self.ranged(&value_token, value, START_ADDRESS as i64, 0xFFFF)?;
+            }
+            ":byte" => {
+                let value_token = self.next(&token)?;
+                let byte = self.byte(&value_token)?;
+                self.emit_byte(byte)?;
+            }
+            ":call" => {
+                let target = self.next(&token)?;
+                self.emit_address(0x2000, target)?;
+            }
+            ":unpack" => {
+                // `:unpack long label` puts the whole high byte in v0.
+                let first = self.next(&token)?;
+                let label = self.next(&first)?;
+                let nibble = if first.text == "long" {
+                    0
+                } else {
+                    let value = self.value(&first)?;
+                    self.ranged(&first, value, 0, 0xF)? as u8
+                };
+                match self.known_value(&label)? {
+                    Some(value) => {
+                        let addr = self.ranged(&label, value, 0, 0xFFFF)?;
+                        let hi = ((nibble as u16) << 4) | (addr >> 8);
+                        self.emit(0x6000 | (hi & 0xFF))?;
+                        self.emit(0x6100 | (addr & 0xFF))?;
+                    }
+                    None => {
+                        self.fixups.push(Fixup {
+                            at: self.here,
+                            patch: Patch::Unpack(nibble),
+                            label,
+                        });
+                        self.emit(0x6000)?;
+                        self.emit(0x6100)?;
+                    }
+                }
+            }
+            ":breakpoint" => {
+                self.next(&token)?;
+            }
+            ":monitor" => {
+                let addr = self.next(&token)?;
+                self.next(&addr)?;
+            }
+            ";" | "return" => self.emit(0x00EE)?,
+            "clear" => self.emit(0x00E0)?,
+            "exit" => self.emit(0x00FD)?,
+            "lores" => self.emit(0x00FE)?,
+            "hires" => self.emit(0x00FF)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "scroll-right" => self.emit(0x00FB)?,
+            "audio" => self.emit(0xF002)?,
+            "scroll-down" | "scroll-up" | "plane" => {
+                let value_token = self.next(&token)?;
+                let value = self.value(&value_token)?;
+                let n = self.ranged(&value_token, value, 0, 0xF)?;
+                self.emit(match token.text.as_str() {
+                    "scroll-down" => 0x00C0 | n,
+                    "scroll-up" => 0x00D0 | n,
+                    _ => 0xF001 | (n << 8),
+                })?;
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let reg = self.next(&token)?;
+                let x = self.expect_register(&reg)? as u16;
+                self.emit(
+                    match token.text.as_str() {
+                        "bcd" => 0xF033,
+                        "saveflags" => 0xF075,
+                        _ => 0xF085,
+                    } | (x << 8),
+                )?;
+            }
+            "save" | "load" => {
+                let reg = self.next(&token)?;
+                let x = self.expect_register(&reg)? as u16;
+                if self.peek_is("-") {
+                    let dash = self.next(&reg)?;
+                    let last = self.next(&dash)?;
+                    let y = self.expect_register(&last)? as u16;
+                    let base = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.emit(base | (x << 8) | (y << 4))?;
+                } else {
+                    let base = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.emit(base | (x << 8))?;
+                }
+            }
+            "sprite" => {
+                let vx = self.next(&token)?;
+                let x = self.expect_register(&vx)? as u16;
+                let vy = self.next(&vx)?;
+                let y = self.expect_register(&vy)? as u16;
+                let height = self.next(&vy)?;
+                let value = self.value(&height)?;
+                let n = self.ranged(&height, value, 0, 0xF)?;
+                self.emit(0xD000 | (x << 8) | (y << 4) | n)?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.next(&token)?;
+                let opcode = match token.text.as_str() {
+                    "jump" => 0x1000,
+                    "jump0" => 0xB000,
+                    _ => 0x0000,
+                };
+                self.emit_address(opcode, target)?;
+            }
+            "i" => {
+                let op = self.next(&token)?;
+                match op.text.as_str() {
+                    ":=" => {
+                        let rhs = self.next(&op)?;
+                        match rhs.text.as_str() {
+                            "hex" | "bighex" => {
+                                let reg = self.next(&rhs)?;
+                                let x = self.expect_register(&reg)? as u16;
+                                let base = if rhs.text == "hex" { 0xF029 } else { 0xF030 };
+                                self.emit(base | (x << 8))?;
+                            }
+                            "long" => {
+                                let target = self.next(&rhs)?;
+                                self.emit(0xF000)?;
+                                match self.known_value(&target)? {
+                                    Some(value) => {
+                                        let addr = self.ranged(&target, value, 0, 0xFFFF)?;
+                                        self.emit(addr)?;
+                                    }
+                                    None => {
+                                        self.fixups.push(Fixup {
+                                            at: self.here,
+                                            patch: Patch::Long,
+                                            label: target,
+                                        });
+                                        self.emit(0x0000)?;
+                                    }
+                                }
+                            }
+                            _ => self.emit_address(0xA000, rhs)?,
+                        }
+                    }
+                    "+=" => {
+                        let reg = self.next(&op)?;
+                        let x = self.expect_register(&reg)? as u16;
+                        self.emit(0xF01E | (x << 8))?;
+                    }
+                    _ => return Err(self.error(&op, format!("unexpected `{}` after `i`", op.text))),
+                }
+            }
+            "delay" | "buzzer" | "pitch" => {
+                let op = self.expect(&token, ":=")?;
+                let reg = self.next(&op)?;
+                let x = self.expect_register(&reg)? as u16;
+                self.emit(
+                    match token.text.as_str() {
+                        "delay" => 0xF015,
+                        "buzzer" => 0xF018,
+                        _ => 0xF03A,
+                    } | (x << 8),
+                )?;
+            }
+            "if" => self.conditional(&token)?,
+            "else" => match self.control.pop() {
+                Some((Control::If(at), _)) => {
+                    let jump = self.here;
+                    self.emit(0x1000)?;
+                    self.patch_jump(at);
+                    self.control.push((Control::Else(jump), token));
+                }
+                _ => return Err(self.error(&token, "`else` without `if ... begin`")),
+            },
+            "end" => match self.control.pop() {
+                Some((Control::If(at), _)) | Some((Control::Else(at), _)) => self.patch_jump(at),
+                _ => return Err(self.error(&token, "`end` without `if ... begin`")),
+            },
+            "loop" => self
+                .control
+                .push((Control::Loop(self.here, Vec::new()), token)),
+            "while" => {
+                let cmp = self.condition(&token)?;
+                let at = self.here + self.skip_size(&cmp);
+                self.emit_skip(cmp)?;
+                self.emit(0x1000)?;
+                match self
+                    .control
+                    .iter_mut()
+                    .rev()
+                    .find(|(c, _)| matches!(c, Control::Loop(..)))
+                {
+                    Some((Control::Loop(_, breaks), _)) => breaks.push(at),
+                    _ => return Err(self.error(&token, "`while` outside of `loop`")),
+                }
+            }
+            "again" => match self.control.pop() {
+                Some((Control::Loop(start, breaks), _)) => {
+                    self.emit(0x1000 | start)?;
+                    for at in breaks {
+                        self.patch_jump(at);
+                    }
+                }
+                _ => return Err(self.error(&token, "`again` without `loop`")),
+            },
+            "{" => {
+                let value = self.calc(&token)?;
+                let byte = self.ranged(&token, value, -128, 0xFF)? as u8;
+                self.emit_byte(byte)?;
+            }
+            _ if parse_number(&token.text).is_some() || self.consts.contains_key(&token.text) => {
+                let byte = self.byte(&token)?;
+                self.emit_byte(byte)?;
+            }
+            _ if token.text.starts_with(':') || token.text.starts_with('"') => {
+                let message = format!("unsupported directive `{}`", token.text);
+                return Err(self.error(&token, message));
+            }
+            _ => self.emit_address(0x2000, token)?,
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8, token: &Token) -> Result<(), Error> {
+        let op = self.next(token)?;
+        let rhs = self.next(&op)?;
+        let x = (x as u16) << 8;
+        let opcode = match (op.text.as_str(), rhs.text.as_str()) {
+            (":=", "random") => {
+                let mask_token = self.next(&rhs)?;
+                0xC000 | x | self.byte(&mask_token)? as u16
+            }
+            (":=", "key") => 0xF00A | x,
+            (":=", "delay") => 0xF007 | x,
+            _ => {
+                let operand = match self.register(&rhs) {
+                    Some(y) => Rhs::Reg(y),
+                    None => Rhs::Byte(self.byte(&rhs)?),
+                };
+                match (op.text.as_str(), operand) {
+                    (":=", Rhs::Reg(y)) => 0x8000 | x | (y as u16) << 4,
+                    (":=", Rhs::Byte(b)) => 0x6000 | x | b as u16,
+                    ("+=", Rhs::Reg(y)) => 0x8004 | x | (y as u16) << 4,
+                    ("+=", Rhs::Byte(b)) => 0x7000 | x | b as u16,
+                    ("-=", Rhs::Reg(y)) => 0x8005 | x | (y as u16) << 4,
+                    ("-=", Rhs::Byte(b)) => 0x7000 | x | b.wrapping_neg() as u16,
+                    ("|=", Rhs::Reg(y)) => 0x8001 | x | (y as u16) << 4,
+                    ("&=", Rhs::Reg(y)) => 0x8002 | x | (y as u16) << 4,
+                    ("^=", Rhs::Reg(y)) => 0x8003 | x | (y as u16) << 4,
+                    (">>=", Rhs::Reg(y)) => 0x8006 | x | (y as u16) << 4,
+                    ("=-", Rhs::Reg(y)) => 0x8007 | x | (y as u16) << 4,
+                    ("<<=", Rhs::Reg(y)) => 0x800E | x | (y as u16) << 4,
+                    ("|=" | "&=" | "^=" | ">>=" | "=-" | "<<=", _) => {
+                        let message = format!("`{}` needs a register operand", op.text);
+                        return Err(self.error(&rhs, message));
+                    }
+                    _ => {
+                        let message = format!("unknown operator `{}`", op.text);
+                        return Err(self.error(&op, message));
+                    }
+                }
+            }
+        };
+        self.emit(opcode)?;
+        Ok(())
+    }
+
+    /// Parse `vx <op> rhs`, or `vx key` / `vx -key`, after `if` or `while`.
+    fn condition(&mut self, keyword: &Token) -> Result<(u8, Cmp, Option<Rhs>), Error> {
+        let lhs = self.next(keyword)?;
+        let x = self.expect_register(&lhs)?;
+        let op = self.next(&lhs)?;
+        let cmp = match op.text.as_str() {
+            "==" => Cmp::Eq,
+            "!=" => Cmp::Ne,
+            "<" => Cmp::Lt,
+            ">" => Cmp::Gt,
+            "<=" => Cmp::Le,
+            ">=" => Cmp::Ge,
+            "key" => return Ok((x, Cmp::Key, None)),
+            "-key" => return Ok((x, Cmp::NotKey, None)),
+            _ => return Err(self.error(&op, format!("unknown comparison `{}`", op.text))),
+        };
+        let rhs = self.next(&op)?;
+        let operand = match self.register(&rhs) {
+            Some(y) => Rhs::Reg(y),
+            None => Rhs::Byte(self.byte(&rhs)?),
+        };
+        Ok((x, cmp, Some(operand)))
+    }
+
+    fn conditional(&mut self, token: &Token) -> Result<(), Error> {
+        let (x, cmp, rhs) = self.condition(token)?;
+        let word = self.next(token)?;
+        match word.text.as_str() {
+            "then" => self.emit_skip((x, cmp.negate(), rhs))?,
+            "begin" => {
+                let cond = (x, cmp, rhs);
+                let at = self.here + self.skip_size(&cond);
+                self.emit_skip(cond)?;
+                self.emit(0x1000)?;
+                self.control.push((Control::If(at), token.clone()));
+            }
+            _ => {
+                let message = format!("expected `then` or `begin`, found `{}`", word.text);
+                return Err(self.error(&word, message));
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_size(&self, cond: &(u8, Cmp, Option<Rhs>)) -> u16 {
+        match cond.1 {
+            Cmp::Lt | Cmp::Gt | Cmp::Le | Cmp::Ge => 6,
+            _ => 2,
+        }
+    }
+
+    /// Emit code that skips the next instruction when the condition holds.
+    fn emit_skip(&mut self, (x, cmp, rhs): (u8, Cmp, Option<Rhs>)) -> Result<(), Error> {
+        let x16 = (x as u16) << 8;
+        match (cmp, rhs) {
+            (Cmp::Key, _) => self.emit(0xE09E | x16),
+            (Cmp::NotKey, _) => self.emit(0xE0A1 | x16),
+            (Cmp::Eq, Some(Rhs::Byte(b))) => self.emit(0x3000 | x16 | b as u16),
+            (Cmp::Ne, Some(Rhs::Byte(b))) => self.emit(0x4000 | x16 | b as u16),
+            (Cmp::Eq, Some(Rhs::Reg(y))) => self.emit(0x5000 | x16 | (y as u16) << 4),
+            (Cmp::Ne, Some(Rhs::Reg(y))) => self.emit(0x9000 | x16 | (y as u16) << 4),
+            (cmp, rhs) => {
+                // vf := rhs; then `vf =- vx` flags vx >= rhs, `vf -= vx` flags rhs >= vx.
+                match rhs {
+                    Some(Rhs::Reg(y)) => self.emit(0x8F00 | (y as u16) << 4),
+                    Some(Rhs::Byte(b)) => self.emit(0x6F00 | b as u16),
+                    None => unreachable!(),
+                }?;
+                let (sub, flag) = match cmp {
+                    Cmp::Ge => (0x8F07, 1),
+                    Cmp::Lt => (0x8F07, 0),
+                    Cmp::Le => (0x8F05, 1),
+                    _ => (0x8F05, 0),
+                };
+                self.emit(sub | (x as u16) << 4)?;
+                self.emit(0x3F00 | flag)
+            }
+        }
+    }
+
+    fn patch_jump(&mut self, at: u16) {
+        let offset = at as usize - START_ADDRESS;
+        self.rom[offset] = 0x10 | (self.here >> 8) as u8;
+        self.rom[offset + 1] = self.here as u8;
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        if let Some(x) = self.aliases.get(&token.text) {
+            return Some(*x);
+        }
+        let mut chars = token.text.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v' | 'V'), Some(d), None) => d.to_digit(16).map(|d| d as u8),
+            _ => None,
+        }
+    }
+
+    fn expect_register(&self, token: &Token) -> Result<u8, Error> {
+        self.register(token).ok_or_else(|| {
+            self.error(
+                token,
+                format!("expected a register, found `{}`", token.text),
+            )
+        })
+    }
+
+    /// A numeric value known right now: literal, constant, `{ calc }` or defined label.
+    fn known_value(&mut self, token: &Token) -> Result<Option<f64>, Error> {
+        if token.text == "{" {
+            return self.calc(token).map(Some);
+        }
+        if let Some(n) = parse_number(&token.text) {
+            return Ok(Some(n as f64));
+        }
+        if let Some(v) = self.consts.get(&token.text) {
+            return Ok(Some(*v));
+        }
+        Ok(self.labels.get(&token.text).map(|a| *a as f64))
+    }
+
+    fn value(&mut self, token: &Token) -> Result<f64, Error> {
+        self.known_value(token)?
+            .ok_or_else(|| self.error(token, format!("undefined name `{}`", token.text)))
+    }
+
+    fn byte(&mut self, token: &Token) -> Result<u8, Error> {
+        let value = self.value(token)?;
+        Ok(self.ranged(token, value, -128, 0xFF)? as u8)
+    }
+
+    fn ranged(&self, token: &Token, value: f64, min: i64, max: i64) -> Result<u16, Error> {
+        let v = value.floor() as i64;
+        if v < min || v > max {
+            let message = format!("value {} is out of range {}..={}", v, min, max);
+            return Err(self.error(token, message));
+        }
+        Ok((v & 0xFFFF) as u16)
+    }
+
+    /// Evaluate a `{ ... }` expression. Like Octo, binary operators have no
+    /// precedence and group to the right; use parentheses.
+    fn calc(&mut self, open: &Token) -> Result<f64, Error> {
+        let mut tokens = Vec::new();
+        loop {
+            let t = self.next(open)?;
+            if t.text == "}" {
+                break;
+            }
+            tokens.extend(split_calc(&t));
+        }
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos, open)?;
+        if let Some(t) = tokens.get(pos) {
+            return Err(self.error(t, format!("unexpected `{}` in expression", t.text)));
+        }
+        Ok(value)
+    }
+
+    fn calc_expr(&self, tokens: &[Token], pos: &mut usize, open: &Token) -> Result<f64, Error> {
+        let lhs = self.calc_unary(tokens, pos, open)?;
+        let op = match tokens.get(*pos) {
+            Some(t) if t.text != ")" => t.clone(),
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.calc_expr(tokens, pos, open)?;
+        let (l, r) = (lhs as i64, rhs as i64);
+        Ok(match op.text.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (l & r) as f64,
+            "|" => (l | r) as f64,
+            "^" => (l ^ r) as f64,
+            "<<" => l.wrapping_shl(r as u32) as f64,
+            ">>" => l.wrapping_shr(r as u32) as f64,
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "pow" => lhs.powf(rhs),
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return Err(self.error(&op, format!("unknown operator `{}`", op.text))),
+        })
+    }
+
+    fn calc_unary(&self, tokens: &[Token], pos: &mut usize, open: &Token) -> Result<f64, Error> {
+        let t = match tokens.get(*pos) {
+            Some(t) => t.clone(),
+            None => return Err(self.error(open, "incomplete expression")),
+        };
+        *pos += 1;
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| -> Result<f64, Error> {
+            self.calc_unary(tokens, pos, open).map(f)
+        };
+        match t.text.as_str() {
+            "(" => {
+                let v = self.calc_expr(tokens, pos, open)?;
+                match tokens.get(*pos) {
+                    Some(c) if c.text == ")" => *pos += 1,
+                    _ => return Err(self.error(&t, "unbalanced `(`")),
+                }
+                Ok(v)
+            }
+            "-" => unary(|v| -v, pos),
+            "~" => unary(|v| !(v as i64) as f64, pos),
+            "!" => unary(|v| (v == 0.0) as i64 as f64, pos),
+            "floor" => unary(f64::floor, pos),
+            "ceil" => unary(f64::ceil, pos),
+            "abs" => unary(f64::abs, pos),
+            "sqrt" => unary(f64::sqrt, pos),
+            "sin" => unary(f64::sin, pos),
+            "cos" => unary(f64::cos, pos),
+            "log" => unary(f64::ln, pos),
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => {
+                if let Some(n) = parse_number(text) {
+                    Ok(n as f64)
+                } else if let Some(v) = self.consts.get(text) {
+                    Ok(*v)
+                } else if let Some(a) = self.labels.get(text) {
+                    Ok(*a as f64)
+                } else {
+                    Err(self.error(&t, format!("undefined name `{}`", text)))
+                }
+            }
+        }
+    }
+}
+
+/// Split parentheses off a calc token, so `(1+` style spacing is not required
+/// around them.
+fn split_calc(token: &Token) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut start = token.column;
+    for (i, c) in token.text.chars().enumerate() {
+        if c == '(' || c == ')' {
+            if !current.is_empty() {
+                out.push(Token {
+                    text: std::mem::take(&mut current),
+                    line: token.line,
+                    column: start,
+                });
+            }
+            out.push(Token {
+                text: c.to_string(),
+                line: token.line,
+                column: token.column + i,
+            });
+            start = token.column + i + 1;
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        out.push(Token {
+            text: current,
+            line: token.line,
+            column: start,
+        });
+    }
+    out
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        match compile(source) {
+            Ok(program) => program.rom,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn error(source: &str) -> Error {
+        compile(source).err().unwrap()
+    }
+
+    #[test]
+    fn comparisons_run_on_chip() {
+        use crate::chip::Chip;
+
+        let cases = [(3, 5), (5, 3), (5, 5)];
+        for op in ["<", ">", "<=", ">=", "==", "!="].iter() {
+            for (a, b) in cases.iter() {
+                let source = format!(
+                    ": main v0 := {} v1 := {} v2 := 0 if v0 {} v1 then v2 := 1 loop again",
+                    a, b, op
+                );
+                let mut chip = Chip::new();
+                chip.load_program(&rom(&source));
+                for _ in 0..20 {
+                    chip.step();
+                }
+                let expected = match *op {
+                    "<" => a < b,
+                    ">" => a > b,
+                    "<=" => a <= b,
+                    ">=" => a >= b,
+                    "==" => a == b,
+                    _ => a != b,
+                };
+                assert_eq!(
+                    chip.state().registers[2],
+                    expected as u8,
+                    "{} {} {}",
+                    a,
+                    op,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn main_first_needs_no_jump() {
+        assert_eq!(
+            rom(": main v0 := 5 v1 += 2 loop again"),
+            vec![0x60, 0x05, 0x71, 0x02, 0x12, 0x04]
+        );
+    }
+
+    #[test]
+    fn jump_to_main_and_data() {
+        let source = "
+            : ball 0x3C 0b01111110
+            : main
+                clear
+                i := ball
+                sprite v0 v1 2
+                draw-done
+                ;
+            : draw-done return
+        ";
+        assert_eq!(
+            rom(source),
+            vec![
+                0x12, 0x04, 0x3C, 0x7E, 0x00, 0xE0, 0xA2, 0x02, 0xD0, 0x12, 0x22, 0x0E, 0x00, 0xEE,
+                0x00, 0xEE
+            ]
+        );
+    }
+
+    #[test]
+    fn assignments() {
+        let source = ": main
+            v1 := v2  v1 |= v2  v1 &= v2  v1 ^= v2  v1 += v2  v1 -= v2
+            v1 >>= v2  v1 =- v2  v1 <<= v2  v3 -= 1  v4 := random 0x0F
+            v5 := key  v6 := delay  delay := v6  buzzer := v7  i += v8
+            i := hex v9  i := bighex va  i := long 0x1234  bcd vb  save vc  load vd
+            save v1 - v4  pitch := v0  scroll-down 3  plane 2";
+        assert_eq!(
+            rom(source),
+            vec![
+                0x81, 0x20, 0x81, 0x21, 0x81, 0x22, 0x81, 0x23, 0x81, 0x24, 0x81, 0x25, 0x81, 0x26,
+                0x81, 0x27, 0x81, 0x2E, 0x73, 0xFF, 0xC4, 0x0F, 0xF5, 0x0A, 0xF6, 0x07, 0xF6, 0x15,
+                0xF7, 0x18, 0xF8, 0x1E, 0xF9, 0x29, 0xFA, 0x30, 0xF0, 0x00, 0x12, 0x34, 0xFB, 0x33,
+                0xFC, 0x55, 0xFD, 0x65, 0x51, 0x42, 0xF0, 0x3A, 0x00, 0xC3, 0xF2, 0x01
+            ]
+        );
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = ": main
+            if v0 == 5 then v1 := 1
+            if v0 != v2 then v1 := 2
+            if v3 key then v1 := 3
+            if v0 < 8 then v1 := 4
+            if v0 == 1 begin v1 := 5 else v1 := 6 end";
+        assert_eq!(
+            rom(source),
+            vec![
+                0x40, 0x05, 0x61, 0x01, 0x50, 0x20, 0x61, 0x02, 0xE3, 0xA1, 0x61, 0x03, 0x6F, 0x08,
+                0x8F, 0x07, 0x3F, 0x01, 0x61, 0x04, 0x30, 0x01, 0x12, 0x1C, 0x61, 0x05, 0x12, 0x1E,
+                0x61, 0x06
+            ]
+        );
+    }
+
+    #[test]
+    fn loops() {
+        let source = ": main
+            loop
+                v0 += 1
+                while v0 != 10
+            again";
+        assert_eq!(
+            rom(source),
+            vec![0x70, 0x01, 0x40, 0x0A, 0x12, 0x08, 0x12, 0x00]
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let source = "
+            :const SPEED 3
+            :alias px v4
+            :calc DOUBLE { SPEED * ( 1 + 1 ) }
+            :macro add-to reg amount { reg += amount }
+            : main
+                px := SPEED
+                add-to px DOUBLE
+                :unpack 0xA data
+                :next patch v0 := 0
+                :byte { 2 + 3 * 2 }
+                :org 0x220
+            : data
+                :call main
+        ";
+        let program = compile(source).unwrap();
+        assert_eq!(
+            program.rom[..14].to_vec(),
+            vec![0x12, 0x02, 0x64, 0x03, 0x74, 0x06, 0x60, 0xA2, 0x61, 0x20, 0x60, 0x00, 0x08, 0]
+        );
+        assert_eq!(program.rom[0x20..].to_vec(), vec![0x22, 0x02]);
+        assert_eq!(program.source_map[&0x202], 7);
+        assert_eq!(program.source_map[&0x204], 8);
+        assert_eq!(program.source_map[&0x220], 14);
+    }
+
+    #[test]
+    fn errors() {
+        let e = error(": main\n  v0 := 300");
+        assert_eq!((e.line, e.column), (2, 9));
+        assert_eq!(e.message, "value 300 is out of range -128..=255");
+        assert_eq!(
+            error(": main jump nowhere").message,
+            "undefined label `nowhere`"
+        );
+        assert_eq!(error("v0 := 1").message, "undefined label `main`");
+        assert_eq!(
+            error(": main loop v0 += 1").message,
+            "`loop` is never closed"
+        );
+        assert_eq!(error(": main sprite v0 5 1").column, 18);
+        let e = error(": main clear clear\n:org 0x202 return");
+        assert_eq!((e.line, e.column), (2, 12));
+        assert_eq!(e.message, "address 0x202 is already used");
+        assert_eq!(
+            error(": main\n:org 0xFFE clear :byte 1").message,
+            "program does not fit in memory"
+        );
+        assert!(compile(": main\n:org 0xFFE clear").is_ok());
+    }
+}
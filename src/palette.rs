@@ -0,0 +1,156 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// Colours for the four pixel values, as 0xRRGGBB: background, first plane,
+/// second plane and both planes. Only XO-CHIP uses the last two.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Palette {
+    pub colours: [u32; 4],
+}
+
+pub const THEMES: [(&str, Palette); 6] = [
+    (
+        "classic",
+        Palette {
+            colours: [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555],
+        },
+    ),
+    (
+        "amber",
+        Palette {
+            colours: [0x140C00, 0xFFB000, 0xA06E00, 0xFFD780],
+        },
+    ),
+    (
+        "green",
+        Palette {
+            colours: [0x001400, 0x33FF66, 0x1E9940, 0xA0FFB8],
+        },
+    ),
+    (
+        "lcd",
+        Palette {
+            colours: [0x9BBC0F, 0x0F380F, 0x306230, 0x8BAC0F],
+        },
+    ),
+    (
+        "octo",
+        Palette {
+            colours: [0x996600, 0xFFCC00, 0xFF6600, 0x662200],
+        },
+    ),
+    (
+        "high-contrast",
+        Palette {
+            colours: [0x000000, 0xFFFFFF, 0xFFFF00, 0x00FFFF],
+        },
+    ),
+];
+
+impl Default for Palette {
+    fn default() -> Palette {
+        THEMES[0].1
+    }
+}
+
+impl Palette {
+    /// Colour of pixel value `pixel` as 0xRRGGBB.
+    pub fn colour(&self, pixel: u8) -> u32 {
+        self.colours[pixel as usize & 3]
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match THEMES.iter().find(|(_, p)| p == self) {
+            Some((name, _)) => f.write_str(name),
+            None => {
+                let colours: Vec<String> =
+                    self.colours.iter().map(|c| format!("{:06x}", c)).collect();
+                f.write_str(&colours.join(","))
+            }
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// A theme name, or two or four colours as RRGGBB hex in pixel value
+    /// order. With two, both XO-CHIP planes use the second colour.
+    fn from_str(s: &str) -> Result<Palette, String> {
+        if let Some((_, palette)) = THEMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Ok(*palette);
+        }
+        if !s.contains(',') {
+            let names: Vec<&str> = THEMES.iter().map(|(name, _)| *name).collect();
+            return Err(format!(
+                "unknown theme `{}` (expected {} or colours)",
+                s,
+                names.join(", ")
+            ));
+        }
+        let colours = s
+            .split(',')
+            .map(|c| {
+                let hex = c.trim().trim_start_matches('#');
+                match u32::from_str_radix(hex, 16) {
+                    Ok(rgb) if hex.len() == 6 => Ok(rgb),
+                    _ => Err(format!("invalid colour `{}` (expected RRGGBB)", c)),
+                }
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        match colours[..] {
+            [background, foreground] => Ok(Palette {
+                colours: [background, foreground, foreground, foreground],
+            }),
+            [a, b, c, d] => Ok(Palette {
+                colours: [a, b, c, d],
+            }),
+            _ => Err(format!("expected 2 or 4 colours, got {}", colours.len())),
+        }
+    }
+}
+
+impl TryFrom<String> for Palette {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Palette, String> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn themes_and_colours() {
+        let octo: Palette = "Octo".parse().unwrap();
+        assert_eq!(octo.colour(1), 0xFFCC00);
+        assert_eq!(octo.to_string(), "octo");
+        let custom: Palette = "#202020,ffb000".parse().unwrap();
+        assert_eq!(custom.colours, [0x202020, 0xFFB000, 0xFFB000, 0xFFB000]);
+        let xo: Palette = "000000,ff0000,00ff00,0000ff".parse().unwrap();
+        assert_eq!(xo.colour(3), 0x0000FF);
+        assert_eq!(xo.to_string(), "000000,ff0000,00ff00,0000ff");
+        assert_eq!(Palette::default().colour(1), 0xFFFFFF);
+    }
+
+    #[test]
+    fn invalid_palettes() {
+        assert!("sepia"
+            .parse::<Palette>()
+            .unwrap_err()
+            .contains("unknown theme"));
+        assert!("fff,000".parse::<Palette>().is_err());
+        assert!("000000,111111,222222"
+            .parse::<Palette>()
+            .unwrap_err()
+            .contains("expected 2 or 4"));
+    }
+}
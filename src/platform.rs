@@ -0,0 +1,161 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// Behaviours that differ between CHIP-8 implementations. The default is
+/// what this emulator has always done: shifts and loads/stores touch only
+/// VX and leave I alone, `Bnnn` adds V0 and sprites wrap around. `Fx0A`
+/// waits for a key to be pressed and released on every platform.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift Vy into Vx instead of shifting Vx in place.
+    pub shift_uses_vy: bool,
+    /// `Fx55`/`Fx65` leave I pointing past the last register.
+    pub memory_increments_i: bool,
+    /// `Bnnn` behaves as `Bxnn`, jumping to Vx + nnn.
+    pub jump_uses_vx: bool,
+    /// `Dxyn` clips sprites at the screen edges instead of wrapping them.
+    pub clip_sprites: bool,
+    /// `8xy1`/`8xy2`/`8xy3` reset VF to 0.
+    pub logic_resets_vf: bool,
+    /// `Fx0A` completes as soon as a key is pressed, instead of when it is
+    /// released again as on the COSMAC VIP.
+    pub wait_key_on_press: bool,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Platform {
+    /// The original COSMAC VIP interpreter.
+    Chip8,
+    /// SUPER-CHIP 1.1 on the HP-48.
+    SuperChip,
+    /// Octo's XO-CHIP.
+    XoChip,
+}
+
+pub const PLATFORMS: [Platform; 3] = [Platform::Chip8, Platform::SuperChip, Platform::XoChip];
+
+impl Platform {
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks {
+                shift_uses_vy: true,
+                memory_increments_i: true,
+                jump_uses_vx: false,
+                clip_sprites: true,
+                logic_resets_vf: true,
+                wait_key_on_press: false,
+            },
+            Platform::SuperChip => Quirks {
+                shift_uses_vy: false,
+                memory_increments_i: false,
+                jump_uses_vx: true,
+                clip_sprites: true,
+                logic_resets_vf: false,
+                wait_key_on_press: false,
+            },
+            Platform::XoChip => Quirks {
+                shift_uses_vy: true,
+                memory_increments_i: true,
+                jump_uses_vx: false,
+                clip_sprites: false,
+                logic_resets_vf: false,
+                wait_key_on_press: false,
+            },
+        }
+    }
+
+    /// Name used on the command line and in configuration files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8 (COSMAC VIP)",
+            Platform::SuperChip => "SUPER-CHIP 1.1",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" | "vip" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" | "octo" => Ok(Platform::XoChip),
+            _ => Err(format!(
+                "unknown platform `{}` (expected chip8, schip or xochip)",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Platform {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Platform, String> {
+        s.parse()
+    }
+}
+
+/// Individual quirk settings layered over a platform's, as found in the ROM
+/// database and configuration files.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QuirkOverrides {
+    pub shift_uses_vy: Option<bool>,
+    pub memory_increments_i: Option<bool>,
+    pub jump_uses_vx: Option<bool>,
+    pub clip_sprites: Option<bool>,
+    pub logic_resets_vf: Option<bool>,
+    pub wait_key_on_press: Option<bool>,
+}
+
+impl QuirkOverrides {
+    /// These overrides, with the quirks they leave unset taken from
+    /// `fallback`.
+    pub fn or(self, fallback: QuirkOverrides) -> QuirkOverrides {
+        QuirkOverrides {
+            shift_uses_vy: self.shift_uses_vy.or(fallback.shift_uses_vy),
+            memory_increments_i: self.memory_increments_i.or(fallback.memory_increments_i),
+            jump_uses_vx: self.jump_uses_vx.or(fallback.jump_uses_vx),
+            clip_sprites: self.clip_sprites.or(fallback.clip_sprites),
+            logic_resets_vf: self.logic_resets_vf.or(fallback.logic_resets_vf),
+            wait_key_on_press: self.wait_key_on_press.or(fallback.wait_key_on_press),
+        }
+    }
+
+    pub fn apply(&self, quirks: &mut Quirks) {
+        let fields = [
+            (self.shift_uses_vy, &mut quirks.shift_uses_vy),
+            (self.memory_increments_i, &mut quirks.memory_increments_i),
+            (self.jump_uses_vx, &mut quirks.jump_uses_vx),
+            (self.clip_sprites, &mut quirks.clip_sprites),
+            (self.logic_resets_vf, &mut quirks.logic_resets_vf),
+            (self.wait_key_on_press, &mut quirks.wait_key_on_press),
+        ];
+        for (value, field) in fields {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::instruction::{decode, decode_at, Instruction};
+
+const ADDRESS_SPACE: usize = 0x1000;
+/// Longest loop body, in instructions, still considered a busy-wait.
+const MAX_WAIT_LOOP: usize = 8;
+const HOT_SPOTS: usize = 20;
+
+/// A backward jump whose body only polls the delay timer or the keypad.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaitLoop {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Counts executions per address and opcode class, and instructions per
+/// frame. Attach with `Chip::set_profiler`.
+pub struct Profiler {
+    counts: Vec<u64>,
+    opcodes: Vec<u16>,
+    classes: [u64; 16],
+    frames: Vec<u32>,
+    current_frame: u32,
+    wait_loops: Vec<WaitLoop>,
+    checked_jumps: Vec<bool>,
+    calls: Vec<u16>,
+    call_counts: BTreeMap<u16, u64>,
+    paths: HashMap<Vec<u16>, usize>,
+    path_counts: Vec<(Vec<u16>, u64)>,
+    current_path: usize,
+}
+
+/// Instruction counts for one subroutine. Inclusive counts include every
+/// callee, exclusive counts only the subroutine's own instructions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Subroutine {
+    pub address: u16,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            counts: vec![0; ADDRESS_SPACE],
+            opcodes: vec![0; ADDRESS_SPACE],
+            classes: [0; 16],
+            frames: Vec::new(),
+            current_frame: 0,
+            wait_loops: Vec::new(),
+            checked_jumps: vec![false; ADDRESS_SPACE],
+            calls: Vec::new(),
+            call_counts: BTreeMap::new(),
+            paths: HashMap::new(),
+            path_counts: vec![(Vec::new(), 0)],
+            current_path: 0,
+        }
+    }
+
+    /// Count the instruction at `pc`, about to execute with `memory` as is.
+    pub fn record(&mut self, pc: u16, opcode: u16, memory: &[u8]) {
+        let slot = pc as usize % ADDRESS_SPACE;
+        self.counts[slot] += 1;
+        self.opcodes[slot] = opcode;
+        self.classes[(opcode >> 12) as usize] += 1;
+        self.current_frame += 1;
+        self.path_counts[self.current_path].1 += 1;
+
+        match decode(opcode) {
+            Instruction::Jump(target) if target <= pc && !self.checked_jumps[slot] => {
+                self.checked_jumps[slot] = true;
+                if let Some(wait) = wait_loop(memory, target, pc) {
+                    self.wait_loops.push(wait);
+                }
+            }
+            Instruction::Call(target) => {
+                self.calls.push(target);
+                *self.call_counts.entry(target).or_insert(0) += 1;
+                self.enter_path();
+            }
+            Instruction::Ret if !self.calls.is_empty() => {
+                self.calls.pop();
+                self.enter_path();
+            }
+            _ => (),
+        }
+    }
+
+    fn enter_path(&mut self) {
+        if self.calls.is_empty() {
+            self.current_path = 0;
+            return;
+        }
+        let counts = &mut self.path_counts;
+        let calls = &self.calls;
+        self.current_path = *self.paths.entry(calls.clone()).or_insert_with(|| {
+            counts.push((calls.clone(), 0));
+            counts.len() - 1
+        });
+    }
+
+    /// Subroutines currently being executed, outermost first.
+    pub fn call_stack(&self) -> &[u16] {
+        &self.calls
+    }
+
+    /// Per-subroutine counts, highest inclusive count first.
+    pub fn subroutines(&self) -> Vec<Subroutine> {
+        let mut subroutines: BTreeMap<u16, Subroutine> = self
+            .call_counts
+            .iter()
+            .map(|(address, calls)| {
+                let sub = Subroutine {
+                    address: *address,
+                    calls: *calls,
+                    ..Subroutine::default()
+                };
+                (*address, sub)
+            })
+            .collect();
+        for (path, count) in self.path_counts.iter() {
+            if let Some(sub) = path.last().and_then(|a| subroutines.get_mut(a)) {
+                sub.exclusive += count;
+            }
+            let mut seen: Vec<u16> = Vec::new();
+            for address in path.iter() {
+                if !seen.contains(address) {
+                    seen.push(*address);
+                    if let Some(sub) = subroutines.get_mut(address) {
+                        sub.inclusive += count;
+                    }
+                }
+            }
+        }
+        let mut subroutines: Vec<Subroutine> = subroutines.into_values().collect();
+        subroutines.sort_by(|a, b| {
+            b.inclusive
+                .cmp(&a.inclusive)
+                .then(a.address.cmp(&b.address))
+        });
+        subroutines
+    }
+
+    /// Folded stacks (`main;sub_2A0;sub_300 123`), one line per call path,
+    /// as read by flamegraph.pl, inferno and speedscope.
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut lines: Vec<String> = self
+            .path_counts
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(path, count)| {
+                let mut line = String::from("main");
+                for address in path.iter() {
+                    let _ = write!(line, ";sub_{:03X}", address);
+                }
+                format!("{} {}", line, count)
+            })
+            .collect();
+        lines.sort();
+        for line in lines {
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_folded(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_folded(&mut out)?;
+        out.flush()
+    }
+
+    /// Close the current frame; called on every timer tick.
+    pub fn end_frame(&mut self) {
+        self.frames.push(self.current_frame);
+        self.current_frame = 0;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.classes.iter().sum()
+    }
+
+    pub fn count(&self, pc: u16) -> u64 {
+        self.counts[pc as usize % ADDRESS_SPACE]
+    }
+
+    pub fn class_counts(&self) -> &[u64; 16] {
+        &self.classes
+    }
+
+    pub fn instructions_per_frame(&self) -> &[u32] {
+        &self.frames
+    }
+
+    pub fn wait_loops(&self) -> &[WaitLoop] {
+        &self.wait_loops
+    }
+
+    /// Instructions executed inside `wait`.
+    pub fn wait_cycles(&self, wait: &WaitLoop) -> u64 {
+        self.counts[wait.start as usize..=wait.end as usize]
+            .iter()
+            .sum()
+    }
+
+    /// Executed addresses, most executed first.
+    pub fn hot_spots(&self) -> Vec<(u16, u64)> {
+        let mut spots: Vec<(u16, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(pc, count)| (pc as u16, *count))
+            .collect();
+        spots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// Human readable summary.
+    pub fn report(&self) -> String {
+        let total = self.total().max(1);
+        let percent = |n: u64| 100.0 * n as f64 / total as f64;
+        let mut out = String::new();
+
+        let _ = writeln!(out, "Instructions: {}", self.total());
+        if !self.frames.is_empty() {
+            let sum: u64 = self.frames.iter().map(|n| *n as u64).sum();
+            let _ = writeln!(
+                out,
+                "Frames: {}, instructions per frame avg {:.1} min {} max {}",
+                self.frames.len(),
+                sum as f64 / self.frames.len() as f64,
+                self.frames.iter().min().unwrap(),
+                self.frames.iter().max().unwrap()
+            );
+        }
+
+        let _ = writeln!(out, "\nHot spots:");
+        for (pc, count) in self.hot_spots().into_iter().take(HOT_SPOTS) {
+            let opcode = self.opcodes[pc as usize];
+            let _ = writeln!(
+                out,
+                "    {:#05x}  {:04X}  {:>10}  {:5.1}%  {}",
+                pc,
+                opcode,
+                count,
+                percent(count),
+                decode(opcode)
+            );
+        }
+
+        let _ = writeln!(out, "\nOpcode classes:");
+        for (class, count) in self.classes.iter().enumerate().filter(|(_, c)| **c > 0) {
+            let _ = writeln!(
+                out,
+                "    {:X}xxx  {:>10}  {:5.1}%",
+                class,
+                count,
+                percent(*count)
+            );
+        }
+
+        let subroutines = self.subroutines();
+        if !subroutines.is_empty() {
+            let _ = writeln!(
+                out,
+                "\nSubroutines:        calls   inclusive           exclusive"
+            );
+            for sub in subroutines.iter().take(HOT_SPOTS) {
+                let _ = writeln!(
+                    out,
+                    "    sub_{:03X}  {:>10}  {:>10} {:5.1}%  {:>10} {:5.1}%",
+                    sub.address,
+                    sub.calls,
+                    sub.inclusive,
+                    percent(sub.inclusive),
+                    sub.exclusive,
+                    percent(sub.exclusive)
+                );
+            }
+        }
+
+        if !self.wait_loops.is_empty() {
+            let _ = writeln!(out, "\nBusy-wait loops:");
+            let mut waiting = 0;
+            for wait in self.wait_loops.iter() {
+                let cycles = self.wait_cycles(wait);
+                waiting += cycles;
+                let _ = writeln!(
+                    out,
+                    "    {:#05x}-{:#05x}  {:>10}  {:5.1}%",
+                    wait.start,
+                    wait.end,
+                    cycles,
+                    percent(cycles)
+                );
+            }
+            let _ = writeln!(
+                out,
+                "    total       {:>10}  {:5.1}%",
+                waiting,
+                percent(waiting)
+            );
+        }
+        out
+    }
+
+    /// `pc,opcode,class,count,instruction` for every executed address.
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "pc,opcode,class,count,instruction")?;
+        for (pc, count) in self.counts.iter().enumerate().filter(|(_, c)| **c > 0) {
+            let opcode = self.opcodes[pc];
+            writeln!(
+                out,
+                "0x{:03X},{:04X},{:X},{},\"{}\"",
+                pc,
+                opcode,
+                opcode >> 12,
+                count,
+                decode(opcode)
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn save_csv(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_csv(&mut out)?;
+        out.flush()
+    }
+}
+
+/// The loop `start..=end` (ending in a jump back to `start`) is a busy-wait
+/// if its body only reads the delay timer or tests keys and registers.
+fn wait_loop(memory: &[u8], start: u16, end: u16) -> Option<WaitLoop> {
+    let mut pc = start;
+    let mut polls = start == end;
+    for _ in 0..MAX_WAIT_LOOP {
+        if pc == end {
+            return if polls {
+                Some(WaitLoop { start, end })
+            } else {
+                None
+            };
+        }
+        match decode_at(memory, pc as usize) {
+            Instruction::LoadDelay(_) | Instruction::SkipKey(_) | Instruction::SkipNotKey(_) => {
+                polls = true
+            }
+            i if i.is_skip() => (),
+            _ => return None,
+        }
+        pc += 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(program: &[u8], steps: usize) -> Profiler {
+        let mut memory = vec![0u8; ADDRESS_SPACE];
+        memory[0x200..0x200 + program.len()].copy_from_slice(program);
+        let mut profiler = Profiler::new();
+        let mut pc = 0x200u16;
+        let mut stack = Vec::new();
+        for _ in 0..steps {
+            let opcode = ((memory[pc as usize] as u16) << 8) | memory[pc as usize + 1] as u16;
+            profiler.record(pc, opcode, &memory);
+            pc = match decode(opcode) {
+                Instruction::Jump(target) => target,
+                Instruction::Call(target) => {
+                    stack.push(pc + 2);
+                    target
+                }
+                Instruction::Ret => stack.pop().unwrap(),
+                _ => pc + 2,
+            };
+        }
+        profiler
+    }
+
+    #[test]
+    fn counts_and_classes() {
+        // LD V0, 1; ADD V0, 1; JP 0x202
+        let profiler = run(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02], 7);
+        assert_eq!(profiler.total(), 7);
+        assert_eq!(profiler.count(0x200), 1);
+        assert_eq!(profiler.count(0x202), 3);
+        assert_eq!(profiler.class_counts()[0x1], 3);
+        assert_eq!(profiler.hot_spots()[0], (0x202, 3));
+        assert!(profiler.wait_loops().is_empty());
+    }
+
+    #[test]
+    fn detects_delay_timer_poll() {
+        // LD V0, DT; SE V0, 0; JP 0x200 (skip never taken in this model)
+        let profiler = run(&[0xF0, 0x07, 0x30, 0x00, 0x12, 0x00], 9);
+        assert_eq!(
+            profiler.wait_loops(),
+            &[WaitLoop {
+                start: 0x200,
+                end: 0x204
+            }]
+        );
+        assert_eq!(profiler.wait_cycles(&profiler.wait_loops()[0]), 9);
+        assert!(profiler.report().contains("Busy-wait loops"));
+    }
+
+    #[test]
+    fn frames_and_csv() {
+        let mut profiler = run(&[0x12, 0x00], 3);
+        profiler.end_frame();
+        profiler.end_frame();
+        assert_eq!(profiler.instructions_per_frame(), &[3, 0]);
+        let mut csv = Vec::new();
+        profiler.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "pc,opcode,class,count,instruction\n0x200,1200,1,3,\"JP 0x200\"\n"
+        );
+    }
+
+    #[test]
+    fn call_graph() {
+        // 200: CALL 206; 202: JP 202; 206: CALL 20C; RET; 20C: LD V0, 1; RET
+        let program = [
+            0x22, 0x06, 0x12, 0x02, 0x12, 0x04, 0x22, 0x0C, 0x00, 0xEE, 0x00, 0x00, 0x60, 0x01,
+            0x00, 0xEE,
+        ];
+        let profiler = run(&program, 8);
+        assert!(profiler.call_stack().is_empty());
+        let subs = profiler.subroutines();
+        assert_eq!(
+            subs,
+            vec![
+                Subroutine {
+                    address: 0x206,
+                    calls: 1,
+                    inclusive: 4,
+                    exclusive: 2
+                },
+                Subroutine {
+                    address: 0x20C,
+                    calls: 1,
+                    inclusive: 2,
+                    exclusive: 2
+                },
+            ]
+        );
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main 4\nmain;sub_206 2\nmain;sub_206;sub_20C 2\n"
+        );
+    }
+}
@@ -1,12 +1,12 @@
 use rand::{
     distributions::Standard,
-    prelude::{Distribution, ThreadRng},
-    Rng,
+    prelude::{Distribution, StdRng},
+    Rng, SeedableRng,
 };
 use std::marker::PhantomData;
 
 pub struct Generator<T> {
-    rng: ThreadRng,
+    rng: StdRng,
     phantom_data: PhantomData<T>,
 }
 
@@ -16,7 +16,15 @@ where
 {
     pub fn new() -> Generator<T> {
         Generator {
-            rng: rand::thread_rng(),
+            rng: StdRng::from_entropy(),
+            phantom_data: PhantomData,
+        }
+    }
+
+    /// A generator that always produces the same sequence for `seed`.
+    pub fn with_seed(seed: u64) -> Generator<T> {
+        Generator {
+            rng: StdRng::seed_from_u64(seed),
             phantom_data: PhantomData,
         }
     }
@@ -0,0 +1,223 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use gif::{DisposalMethod, Encoder, Frame, Repeat};
+
+use crate::palette::Palette;
+
+/// GIF delays are in hundredths of a second, and viewers slow down frames
+/// shorter than this.
+const MIN_DELAY: u32 = 2;
+
+/// Records the screen, one call to `frame` per 60 Hz frame, as an animated
+/// GIF indexed by the palette's four colours. A screen shown for several
+/// frames is written once, each frame covers only the area that changed,
+/// and screens shown for less than `MIN_DELAY` are merged into the next.
+pub struct GifRecorder<W: Write> {
+    encoder: Encoder<W>,
+    width: usize,
+    height: usize,
+    /// Frames seen so far.
+    frames: u32,
+    /// The latest screen, scaled, waiting for a different one to know how
+    /// long it lasted.
+    pending: Vec<u8>,
+    /// The screen as the GIF shows it so far, and where its time ends.
+    written: Vec<u8>,
+    written_until: u32,
+}
+
+impl GifRecorder<BufWriter<File>> {
+    /// Start recording to `path`. Frames are scaled to `resolution` times
+    /// `scale` pixels, whatever resolution they come in later.
+    pub fn create(
+        path: &Path,
+        palette: &Palette,
+        resolution: (usize, usize),
+        scale: usize,
+    ) -> Result<Self, String> {
+        let file =
+            File::create(path).map_err(|e| format!("Cannot write {}: {}", path.display(), e))?;
+        GifRecorder::new(BufWriter::new(file), palette, resolution, scale)
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(
+        out: W,
+        palette: &Palette,
+        (width, height): (usize, usize),
+        scale: usize,
+    ) -> Result<GifRecorder<W>, String> {
+        let (width, height) = (width * scale.max(1), height * scale.max(1));
+        let colours: Vec<u8> = palette
+            .colours
+            .iter()
+            .flat_map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8])
+            .collect();
+        let mut encoder =
+            Encoder::new(out, width as u16, height as u16, &colours).map_err(|e| e.to_string())?;
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(|e| e.to_string())?;
+        Ok(GifRecorder {
+            encoder,
+            width,
+            height,
+            frames: 0,
+            pending: Vec::new(),
+            written: Vec::new(),
+            written_until: 0,
+        })
+    }
+
+    /// Add the screen at the end of a 60 Hz frame.
+    pub fn frame(&mut self, framebuffer: &[u8], resolution: (usize, usize)) -> Result<(), String> {
+        let screen = self.scale(framebuffer, resolution);
+        if self.frames > 0 && screen != self.pending {
+            self.write_pending(false)?;
+        }
+        self.pending = screen;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Write the last screen and the end of the file.
+    pub fn finish(mut self) -> Result<W, String> {
+        if self.frames > 0 {
+            self.write_pending(true)?;
+        }
+        let mut out = self.encoder.into_inner().map_err(|e| e.to_string())?;
+        out.flush().map_err(|e| e.to_string())?;
+        Ok(out)
+    }
+
+    /// Nearest-neighbour scaling of the screen to the GIF's size.
+    fn scale(&self, framebuffer: &[u8], (width, height): (usize, usize)) -> Vec<u8> {
+        let mut screen = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            let row = &framebuffer[y * height / self.height * width..][..width];
+            screen.extend((0..self.width).map(|x| row[x * width / self.width] & 3));
+        }
+        screen
+    }
+
+    /// Write the pending screen, lasting until now, as the changed area of
+    /// what the GIF shows. Unless `last`, a screen too short to show is
+    /// dropped and its time given to the next.
+    fn write_pending(&mut self, last: bool) -> Result<(), String> {
+        // Hundredths of a second, rounded, so that delays do not drift.
+        let until = (self.frames * 100 + 30) / 60;
+        let delay = until.saturating_sub(self.written_until);
+        if delay < MIN_DELAY && !last {
+            return Ok(());
+        }
+        let (left, top, right, bottom) = self.changed_area();
+        let buffer: Vec<u8> = (top..bottom)
+            .flat_map(|y| &self.pending[y * self.width + left..y * self.width + right])
+            .copied()
+            .collect();
+        let frame = Frame {
+            delay: delay.max(MIN_DELAY) as u16,
+            dispose: DisposalMethod::Keep,
+            left: left as u16,
+            top: top as u16,
+            width: (right - left) as u16,
+            height: (bottom - top) as u16,
+            buffer: Cow::Owned(buffer),
+            ..Frame::default()
+        };
+        self.encoder
+            .write_frame(&frame)
+            .map_err(|e| e.to_string())?;
+        self.written = self.pending.clone();
+        self.written_until = until;
+        Ok(())
+    }
+
+    /// The smallest rectangle, as left, top, right and bottom, holding every
+    /// pixel of the pending screen that differs from the written one. A
+    /// single pixel if none does, as frames cannot be empty.
+    fn changed_area(&self) -> (usize, usize, usize, usize) {
+        if self.written.is_empty() {
+            return (0, 0, self.width, self.height);
+        }
+        let (mut left, mut top, mut right, mut bottom) = (self.width, self.height, 0, 0);
+        for (i, _) in self
+            .pending
+            .iter()
+            .zip(self.written.iter())
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+        {
+            let (x, y) = (i % self.width, i / self.width);
+            left = left.min(x);
+            right = right.max(x + 1);
+            top = top.min(y);
+            bottom = bottom.max(y + 1);
+        }
+        if right == 0 {
+            (0, 0, 1, 1)
+        } else {
+            (left, top, right, bottom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// (left, top, width, height, delay) of every frame in a GIF.
+    fn frames(gif: &[u8]) -> Vec<(u16, u16, u16, u16, u16)> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((
+                frame.left,
+                frame.top,
+                frame.width,
+                frame.height,
+                frame.delay,
+            ));
+        }
+        frames
+    }
+
+    #[test]
+    fn deduplicated_frames() {
+        let mut recorder = GifRecorder::new(Vec::new(), &Palette::default(), (4, 2), 2).unwrap();
+        let blank = [0; 8];
+        let mut dot = blank;
+        dot[6] = 1;
+        for _ in 0..30 {
+            recorder.frame(&blank, (4, 2)).unwrap();
+        }
+        for _ in 0..61 {
+            recorder.frame(&dot, (4, 2)).unwrap();
+        }
+        // Too short to show: merged into the next frame.
+        recorder.frame(&blank, (4, 2)).unwrap();
+        for _ in 0..6 {
+            recorder.frame(&dot, (4, 2)).unwrap();
+        }
+        // A high resolution screen is scaled down to the same size.
+        let mut hires = [0; 32];
+        hires[0] = 1;
+        recorder.frame(&hires, (8, 4)).unwrap();
+        let gif = recorder.finish().unwrap();
+        assert_eq!(
+            frames(&gif),
+            [
+                (0, 0, 8, 4, 50),
+                (4, 2, 2, 2, 102),
+                (0, 0, 1, 1, 11),
+                (0, 0, 6, 4, 2),
+            ]
+        );
+    }
+}
@@ -15,29 +15,29 @@ impl Registers {
         self.regs[n as usize]
     }
 
+    pub fn values(&self) -> &[u8] {
+        &self.regs[..]
+    }
+
     pub fn set(&mut self, i: u8, n: u8) {
         self.regs[i as usize] = n;
     }
 
+    // The arithmetic below writes VF last, so that the flag survives when
+    // VF is also the destination.
+
     pub fn add_inplace(&mut self, lhs: u8, rhs: u8) {
-        let sum = self.regs[lhs as usize] as u16 + self.regs[rhs as usize] as u16;
-        if sum > u8::MAX as u16 {
-            self.vx_set(1);
-        } else {
-            self.vx_set(0);
-        }
-        self.regs[lhs as usize] = (sum & 0xFF) as u8;
+        let (sum, carry) = self.regs[lhs as usize].overflowing_add(self.regs[rhs as usize]);
+        self.regs[lhs as usize] = sum;
+        self.vx_set(carry as u8);
     }
 
+    /// Vx -= Vy, with VF set to 1 when there is no borrow (Vx >= Vy).
     pub fn sub_inplace(&mut self, lhs: u8, rhs: u8) {
         let vx = self.regs[lhs as usize];
         let vy = self.regs[rhs as usize];
-        if vx > vy {
-            self.vx_set(1);
-        } else {
-            self.vx_set(0);
-        }
-        self.regs[lhs as usize] = self.regs[lhs as usize].wrapping_sub(vy);
+        self.regs[lhs as usize] = vx.wrapping_sub(vy);
+        self.vx_set((vx >= vy) as u8);
     }
 
     pub fn or(&self, reg_1: u8, reg_2: u8) -> u8 {
@@ -53,23 +53,23 @@ impl Registers {
     }
 
     pub fn shitf_right_inplace(&mut self, reg: u8) {
-        self.vx_set(self.regs[reg as usize] & 0x1);
-        self.regs[reg as usize] >>= 1;
+        let value = self.regs[reg as usize];
+        self.regs[reg as usize] = value >> 1;
+        self.vx_set(value & 0x1);
     }
 
+    /// Vx = Vy - Vx, with VF set to 1 when there is no borrow (Vy >= Vx).
     pub fn sub_n(&mut self, reg_1: u8, reg_2: u8) {
-        if self.regs[reg_2 as usize] > self.regs[reg_1 as usize] {
-            self.vx_set(1);
-        } else {
-            self.vx_set(0);
-        }
-        self.regs[reg_1 as usize] =
-            u8::wrapping_sub(self.regs[reg_2 as usize], self.regs[reg_1 as usize]);
+        let vx = self.regs[reg_1 as usize];
+        let vy = self.regs[reg_2 as usize];
+        self.regs[reg_1 as usize] = vy.wrapping_sub(vx);
+        self.vx_set((vy >= vx) as u8);
     }
 
     pub fn shift_left_inplace(&mut self, reg: u8) {
-        self.vx_set((self.regs[reg as usize] & 0x80) >> 7);
-        self.regs[reg as usize] <<= 1;
+        let value = self.regs[reg as usize];
+        self.regs[reg as usize] = value << 1;
+        self.vx_set(value >> 7);
     }
 
     pub fn vx_set(&mut self, val: u8) {
@@ -127,4 +127,37 @@ mod test {
             assert_eq!(registers.regs[REGISTERS_NUM - 1], 1);
         }
     }
+
+    #[test]
+    fn flag_written_last() {
+        let mut registers = Registers::new();
+        registers.regs[0] = 5;
+        registers.regs[0xF] = 5;
+        registers.sub_inplace(0xF, 0);
+        assert_eq!(registers.regs[0xF], 1);
+        registers.regs[0xF] = 3;
+        registers.sub_n(0xF, 0);
+        assert_eq!(registers.regs[0xF], 1);
+        registers.regs[0xF] = 6;
+        registers.sub_inplace(0xF, 0);
+        assert_eq!(registers.regs[0xF], 1);
+        registers.regs[0xF] = 4;
+        registers.sub_inplace(0xF, 0);
+        assert_eq!(registers.regs[0xF], 0);
+        registers.regs[0xF] = 0x81;
+        registers.shift_left_inplace(0xF);
+        assert_eq!(registers.regs[0xF], 1);
+    }
+
+    #[test]
+    fn no_borrow_when_equal() {
+        let mut registers = Registers::new();
+        registers.regs[1] = 5;
+        registers.regs[2] = 5;
+        registers.sub_inplace(1, 2);
+        assert_eq!((registers.regs[1], registers.regs[0xF]), (0, 1));
+        registers.regs[1] = 5;
+        registers.sub_n(1, 2);
+        assert_eq!((registers.regs[1], registers.regs[0xF]), (0, 1));
+    }
 }
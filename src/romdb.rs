@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::config;
+use crate::keymap::parse_key;
+use crate::platform::{Platform, QuirkOverrides, Quirks};
+
+const BUILTIN: &str = include_str!("../data/roms.toml");
+
+/// What is known about one ROM. Every field is optional.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub platform: Option<Platform>,
+    #[serde(default)]
+    pub quirks: QuirkOverrides,
+    /// Instructions per 60 Hz frame.
+    pub tick_rate: Option<u32>,
+    /// CHIP-8 key (hex digit) to SDL key name, e.g. `"5" = "Up"`.
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>,
+    pub description: Option<String>,
+}
+
+impl Entry {
+    /// The platform's quirks with the entry's overrides applied, or
+    /// `default` with them when no platform is given.
+    pub fn quirks(&self, default: Quirks) -> Quirks {
+        let mut quirks = self.platform.map_or(default, |p| p.quirks());
+        self.quirks.apply(&mut quirks);
+        quirks
+    }
+
+    /// `Title by Author`, or whichever of the two is known.
+    pub fn display_name(&self) -> Option<String> {
+        match (&self.title, &self.author) {
+            (Some(title), Some(author)) => Some(format!("{} by {}", title, author)),
+            (Some(title), None) => Some(title.clone()),
+            _ => None,
+        }
+    }
+
+    /// Key overrides as (CHIP-8 key, host key name) pairs.
+    pub fn key_overrides(&self) -> Result<Vec<(u8, &str)>, String> {
+        self.keys
+            .iter()
+            .map(|(key, host)| parse_key(key).map(|k| (k, host.as_str())))
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    rom: HashMap<String, Entry>,
+}
+
+/// ROM metadata keyed by lowercase hex SHA-1.
+#[derive(Default)]
+pub struct Database {
+    entries: HashMap<String, Entry>,
+}
+
+impl Database {
+    /// The database shipped with the emulator.
+    pub fn builtin() -> Database {
+        let mut db = Database::default();
+        db.merge_str(BUILTIN)
+            .expect("the shipped ROM database is valid");
+        db
+    }
+
+    /// The shipped database extended by the user's: `path`, or the one in
+    /// the default location if there is one.
+    pub fn with_user(path: Option<&Path>) -> Result<Database, String> {
+        let mut db = Database::builtin();
+        let path = path
+            .map(Path::to_path_buf)
+            .or_else(|| user_path().filter(|p| p.exists()));
+        if let Some(path) = path {
+            db.merge_file(&path)?;
+        }
+        Ok(db)
+    }
+
+    /// Add or replace entries from a TOML document with `[rom.<sha1>]` tables.
+    pub fn merge_str(&mut self, toml: &str) -> Result<(), String> {
+        let file: File = toml::from_str(toml).map_err(|e| e.to_string())?;
+        for (hash, entry) in file.rom {
+            self.entries.insert(hash.to_ascii_lowercase(), entry);
+        }
+        Ok(())
+    }
+
+    pub fn merge_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        self.merge_str(&text)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn get(&self, sha1: &str) -> Option<&Entry> {
+        self.entries.get(&sha1.to_ascii_lowercase())
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&Entry> {
+        self.get(&sha1_hex(rom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// `roms.toml` in `config::dir()`.
+pub fn user_path() -> Option<PathBuf> {
+    config::dir().map(|dir| dir.join("roms.toml"))
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(40);
+    for byte in Sha1::digest(data).iter() {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn user_entries() {
+        let mut db = Database::builtin();
+        db.merge_str(
+            r#"
+            [rom.A9993E364706816ABA3E25717850C26C9CD0D89D]
+            title = "Test"
+            author = "Me"
+            platform = "schip"
+            tick_rate = 20
+            quirks = { clip_sprites = false }
+            keys = { "5" = "Up", "a" = "Space" }
+            "#,
+        )
+        .unwrap();
+        let entry = db.lookup(b"abc").unwrap();
+        assert_eq!(entry.display_name().unwrap(), "Test by Me");
+        assert_eq!(entry.tick_rate, Some(20));
+        let quirks = entry.quirks(Quirks::default());
+        assert!(quirks.jump_uses_vx);
+        assert!(!quirks.clip_sprites);
+        assert_eq!(
+            entry.key_overrides().unwrap(),
+            vec![(5, "Up"), (0xA, "Space")]
+        );
+        assert!(db.lookup(b"abd").is_none());
+    }
+
+    #[test]
+    fn invalid_entries() {
+        let mut db = Database::default();
+        assert!(db.merge_str("[rom.abc]\nplatform = \"nes\"").is_err());
+        assert!(db.merge_str("[rom.abc]\nspeed = 3").is_err());
+        db.merge_str("[rom.abc]\nkeys = { \"G\" = \"Up\" }")
+            .unwrap();
+        assert!(db.get("abc").unwrap().key_overrides().is_err());
+        let missing = Database::with_user(Some(Path::new("/nonexistent/roms.toml")));
+        assert!(missing
+            .err()
+            .unwrap()
+            .starts_with("Cannot read /nonexistent/roms.toml"));
+    }
+}
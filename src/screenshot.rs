@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::palette::Palette;
+
+/// Write the screen to `path` in the format its extension names: `png` for
+/// the palette's colours with every pixel `scale` pixels wide, `pbm` for
+/// one bit per pixel, `pgm` for the raw pixel values (0 to 3).
+pub fn save(
+    path: &Path,
+    framebuffer: &[u8],
+    (width, height): (usize, usize),
+    palette: &Palette,
+    scale: usize,
+) -> Result<(), String> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let write_error = |e: io::Error| format!("Cannot write {}: {}", path.display(), e);
+    let bytes = match extension.as_str() {
+        "png" => png(framebuffer, (width, height), palette, scale.max(1))?,
+        "pbm" => pbm(framebuffer, (width, height)),
+        "pgm" => pgm(framebuffer, (width, height)),
+        _ => {
+            return Err(format!(
+                "Unknown screenshot format `{}` (expected png, pbm or pgm)",
+                path.display()
+            ))
+        }
+    };
+    let mut out = BufWriter::new(File::create(path).map_err(write_error)?);
+    out.write_all(&bytes).map_err(write_error)?;
+    out.flush().map_err(write_error)
+}
+
+/// An indexed PNG using the palette's four colours.
+fn png(
+    framebuffer: &[u8],
+    (width, height): (usize, usize),
+    palette: &Palette,
+    scale: usize,
+) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(width * height * scale * scale);
+    for row in framebuffer.chunks(width) {
+        let mut scaled = Vec::with_capacity(width * scale);
+        for p in row {
+            scaled.resize(scaled.len() + scale, p & 3);
+        }
+        for _ in 0..scale {
+            data.extend_from_slice(&scaled);
+        }
+    }
+    let colours: Vec<u8> = palette
+        .colours
+        .iter()
+        .flat_map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8])
+        .collect();
+
+    let mut bytes = Vec::new();
+    let mut encoder =
+        png::Encoder::new(&mut bytes, (width * scale) as u32, (height * scale) as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(colours);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&data).map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// Binary PBM, lit pixels as 1 (which viewers show black), rows padded to
+/// whole bytes.
+fn pbm(framebuffer: &[u8], (width, height): (usize, usize)) -> Vec<u8> {
+    let mut bytes = format!("P4\n{} {}\n", width, height).into_bytes();
+    for row in framebuffer.chunks(width) {
+        for byte in row.chunks(8) {
+            let bits = byte
+                .iter()
+                .enumerate()
+                .fold(0u8, |bits, (i, p)| bits | ((*p != 0) as u8) << (7 - i));
+            bytes.push(bits);
+        }
+    }
+    bytes
+}
+
+/// Binary PGM of the pixel values, with 3 as white.
+fn pgm(framebuffer: &[u8], (width, height): (usize, usize)) -> Vec<u8> {
+    let mut bytes = format!("P5\n{} {}\n3\n", width, height).into_bytes();
+    bytes.extend(framebuffer.iter().map(|p| p & 3));
+    bytes
+}
+
+/// `<dir>/<rom>-YYYYMMDD-HHMMSS.<extension>` for the ROM file `rom` at
+/// `time` (UTC), with `-2`, `-3`... added if that file already exists.
+pub fn next_path(dir: &Path, rom: &str, time: SystemTime, extension: &str) -> PathBuf {
+    let stem = Path::new(rom)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "chip8".to_string());
+    let base = format!("{}-{}", stem, timestamp(time));
+    let mut path = dir.join(format!("{}.{}", base, extension));
+    let mut n = 2;
+    while path.exists() {
+        path = dir.join(format!("{}-{}.{}", base, n, extension));
+        n += 1;
+    }
+    path
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as i64;
+    let (days, rest) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant.
+    let z = days + 719_468;
+    let (era, doe) = (z.div_euclid(146_097), z.rem_euclid(146_097));
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn netpbm() {
+        let mut framebuffer = vec![0; 10 * 2];
+        framebuffer[0] = 1;
+        framebuffer[9] = 1;
+        framebuffer[12] = 3;
+        assert_eq!(
+            pbm(&framebuffer, (10, 2)),
+            b"P4\n10 2\n\x80\x40\x20\x00".to_vec()
+        );
+        let pgm = pgm(&framebuffer, (10, 2));
+        assert!(pgm.starts_with(b"P5\n10 2\n3\n\x01\x00"));
+        assert_eq!(pgm.len(), 10 + 20);
+    }
+
+    #[test]
+    fn scaled_png() {
+        let framebuffer = [0, 1, 1, 0];
+        let palette: Palette = "amber".parse().unwrap();
+        let bytes = png(&framebuffer, (2, 2), &palette, 3).unwrap();
+        let mut reader = png::Decoder::new(io::Cursor::new(bytes))
+            .read_info()
+            .unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (6, 6));
+        assert_eq!(&info.palette.as_ref().unwrap()[3..6], [0xFF, 0xB0, 0x00]);
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(data[..6], [0, 0, 0, 1, 1, 1]);
+        assert_eq!(data[30..], [1, 1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn file_names() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_792_412_345);
+        let dir = Path::new("/nonexistent");
+        assert_eq!(
+            next_path(dir, "roms/Pong (1 player).ch8", time, "png"),
+            dir.join("Pong (1 player)-20261019-121905.png")
+        );
+        assert_eq!(timestamp(UNIX_EPOCH), "19700101-000000");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "20000229-000000"
+        );
+    }
+}
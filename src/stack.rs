@@ -27,6 +27,27 @@ impl Stack {
         self.sp -= 1;
         v
     }
+
+    pub fn depth(&self) -> usize {
+        self.sp
+    }
+
+    /// Return addresses currently pushed, oldest first.
+    pub fn frames(&self) -> &[u16] {
+        &self.buf[..self.sp]
+    }
+
+    pub fn entries(&self) -> &[u16] {
+        &self.buf[..]
+    }
+
+    pub fn set_depth(&mut self, sp: usize) {
+        self.sp = sp;
+    }
+
+    pub fn set_entry(&mut self, slot: usize, address: u16) {
+        self.buf[slot] = address;
+    }
 }
 
 #[cfg(test)]
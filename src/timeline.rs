@@ -0,0 +1,230 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::instruction::{decode, Instruction};
+use crate::trace::Record;
+
+const PROCESS: u32 = 1;
+const FRAMES: u32 = 1;
+const CALLS: u32 = 2;
+const VIDEO: u32 = 3;
+const INPUT: u32 = 4;
+
+/// Writes a Chrome trace-event JSON file (chrome://tracing, Perfetto,
+/// speedscope). Time is counted in executed instructions: one instruction
+/// is shown as one microsecond, so frame lengths read as instructions per
+/// frame.
+pub struct Timeline {
+    out: Box<dyn Write>,
+    first: bool,
+    frame: u32,
+    frame_start: u64,
+    call_depth: usize,
+    key_wait: Option<u16>,
+    sound_timer: u8,
+    now: u64,
+}
+
+impl Timeline {
+    pub fn new(out: Box<dyn Write>) -> io::Result<Timeline> {
+        let mut timeline = Timeline {
+            out,
+            first: true,
+            frame: 0,
+            frame_start: 0,
+            call_depth: 0,
+            key_wait: None,
+            sound_timer: 0,
+            now: 0,
+        };
+        writeln!(timeline.out, "{{\"traceEvents\":[")?;
+        for (tid, name) in [
+            (FRAMES, "Frames"),
+            (CALLS, "Subroutines"),
+            (VIDEO, "Video"),
+            (INPUT, "Input"),
+        ] {
+            timeline.event(&format!(
+                "\"ph\":\"M\",\"name\":\"thread_name\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"{}\"}}",
+                PROCESS, tid, name
+            ))?;
+        }
+        Ok(timeline)
+    }
+
+    pub fn create(path: &str) -> io::Result<Timeline> {
+        let file = File::create(path)?;
+        Timeline::new(Box::new(BufWriter::new(file)))
+    }
+
+    fn event(&mut self, body: &str) -> io::Result<()> {
+        let separator = if self.first { "" } else { ",\n" };
+        self.first = false;
+        write!(self.out, "{}{{{}}}", separator, body)
+    }
+
+    fn span(&mut self, phase: char, tid: u32, name: &str, args: &str) -> io::Result<()> {
+        let event = format!(
+            "\"ph\":\"{}\",\"name\":\"{}\",\"pid\":{},\"tid\":{},\"ts\":{}{}",
+            phase, name, PROCESS, tid, self.now, args
+        );
+        self.event(&event)
+    }
+
+    fn sound(&mut self, sound_timer: u8) -> io::Result<()> {
+        if sound_timer != self.sound_timer {
+            self.sound_timer = sound_timer;
+            let event = format!(
+                "\"ph\":\"C\",\"name\":\"sound timer\",\"pid\":{},\"ts\":{},\"args\":{{\"ST\":{}}}",
+                PROCESS, self.now, sound_timer
+            );
+            self.event(&event)?;
+        }
+        Ok(())
+    }
+
+    /// Log an executed instruction. `state` is the machine before it ran,
+    /// `next_pc` and `sound_timer` the values after.
+    pub fn instruction(&mut self, state: &Record, next_pc: u16, sound_timer: u8) -> io::Result<()> {
+        match decode(state.opcode) {
+            Instruction::Call(target) => {
+                self.call_depth += 1;
+                self.span('B', CALLS, &format!("sub_{:03X}", target), "")?;
+            }
+            Instruction::Ret if self.call_depth > 0 => {
+                self.call_depth -= 1;
+                self.span('E', CALLS, "", "")?;
+            }
+            Instruction::Draw(x, y, n) => {
+                let args = format!(
+                    ",\"dur\":1,\"args\":{{\"pc\":\"{:#05x}\",\"x\":{},\"y\":{},\"rows\":{},\"I\":\"{:#05x}\"}}",
+                    state.pc,
+                    state.registers[x as usize],
+                    state.registers[y as usize],
+                    n,
+                    state.index_register
+                );
+                self.span('X', VIDEO, "DRW", &args)?;
+            }
+            Instruction::Cls => self.span('X', VIDEO, "CLS", ",\"dur\":1")?,
+            Instruction::WaitKey(_) => {
+                if next_pc == state.pc {
+                    if self.key_wait.is_none() {
+                        self.key_wait = Some(state.pc);
+                        let args = format!(",\"args\":{{\"pc\":\"{:#05x}\"}}", state.pc);
+                        self.span('B', INPUT, "wait key", &args)?;
+                    }
+                } else if self.key_wait.take().is_some() {
+                    self.span('E', INPUT, "", "")?;
+                }
+            }
+            _ => (),
+        }
+        self.now += 1;
+        self.sound(sound_timer)
+    }
+
+    /// Close the current frame; called on every timer tick.
+    pub fn end_frame(&mut self, sound_timer: u8) -> io::Result<()> {
+        let duration = self.now - self.frame_start;
+        let event = format!(
+            "\"ph\":\"X\",\"name\":\"frame {}\",\"pid\":{},\"tid\":{},\"ts\":{},\"dur\":{},\"args\":{{\"instructions\":{}}}",
+            self.frame, PROCESS, FRAMES, self.frame_start, duration, duration
+        );
+        self.event(&event)?;
+        self.frame += 1;
+        self.frame_start = self.now;
+        self.sound(sound_timer)
+    }
+
+    /// Close any open spans and the JSON document.
+    fn finish(&mut self) -> io::Result<()> {
+        if self.key_wait.take().is_some() {
+            self.span('E', INPUT, "", "")?;
+        }
+        while self.call_depth > 0 {
+            self.call_depth -= 1;
+            self.span('E', CALLS, "", "")?;
+        }
+        writeln!(self.out, "\n]}}")?;
+        self.out.flush()
+    }
+}
+
+impl Drop for Timeline {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record(pc: u16, opcode: u16) -> Record {
+        Record {
+            cycle: 0,
+            frame: 0,
+            pc,
+            opcode,
+            registers: [0; 16],
+            index_register: 0x300,
+            sp: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+        }
+    }
+
+    #[test]
+    fn events() {
+        let buffer = Shared::default();
+        let mut timeline = Timeline::new(Box::new(buffer.clone())).unwrap();
+        timeline
+            .instruction(&record(0x200, 0x2206), 0x206, 0)
+            .unwrap();
+        timeline
+            .instruction(&record(0x206, 0xD015), 0x208, 0)
+            .unwrap();
+        timeline
+            .instruction(&record(0x208, 0xF00A), 0x208, 0)
+            .unwrap();
+        timeline
+            .instruction(&record(0x208, 0xF00A), 0x208, 0)
+            .unwrap();
+        timeline.end_frame(4).unwrap();
+        timeline
+            .instruction(&record(0x208, 0xF00A), 0x20A, 4)
+            .unwrap();
+        drop(timeline);
+
+        let json = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert!(json.starts_with("{\"traceEvents\":[\n"));
+        assert!(json.ends_with("\n]}\n"));
+        assert!(json.contains("\"ph\":\"B\",\"name\":\"sub_206\",\"pid\":1,\"tid\":2,\"ts\":0"));
+        assert!(json.contains("\"name\":\"DRW\",\"pid\":1,\"tid\":3,\"ts\":1,\"dur\":1"));
+        assert_eq!(json.matches("\"name\":\"wait key\"").count(), 1);
+        assert!(json.contains("\"ph\":\"E\",\"name\":\"\",\"pid\":1,\"tid\":4,\"ts\":4"));
+        assert!(json.contains("\"name\":\"frame 0\",\"pid\":1,\"tid\":1,\"ts\":0,\"dur\":4"));
+        assert!(json.contains(
+            "\"ph\":\"C\",\"name\":\"sound timer\",\"pid\":1,\"ts\":4,\"args\":{\"ST\":4}"
+        ));
+        // The open call is closed at the end.
+        assert!(json.contains("\"ph\":\"E\",\"name\":\"\",\"pid\":1,\"tid\":2,\"ts\":5"));
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+
+use crate::instruction::Instruction;
+
+pub const BINARY_MAGIC: &[u8; 4] = b"C8TR";
+pub const BINARY_RECORD_SIZE: usize = 37;
+const TEXT_HEADER: &str = "#  cycle  frame   PC   OP V0 V1 V2 V3 V4 V5 V6 V7 V8 V9 VA VB VC VD VE VF    I SP DT ST  instruction";
+
+/// Machine state right before an instruction executes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub cycle: u64,
+    pub frame: u32,
+    pub pc: u16,
+    pub opcode: u16,
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl Record {
+    /// `cycle frame pc opcode V0..VF I SP DT ST  mnemonic`, all hex except
+    /// the counters and SP.
+    pub fn to_text(&self, instruction: &Instruction) -> String {
+        let registers: Vec<String> = self
+            .registers
+            .iter()
+            .map(|r| format!("{:02X}", r))
+            .collect();
+        format!(
+            "{:8} {:6} {:04X} {:04X} {} {:04X} {:2} {:02X} {:02X}  {}",
+            self.cycle,
+            self.frame,
+            self.pc,
+            self.opcode,
+            registers.join(" "),
+            self.index_register,
+            self.sp,
+            self.delay_timer,
+            self.sound_timer,
+            instruction
+        )
+    }
+
+    /// Inverse of `to_binary`.
+    pub fn from_binary(buf: &[u8; BINARY_RECORD_SIZE]) -> Record {
+        let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+        let mut cycle = [0u8; 8];
+        cycle.copy_from_slice(&buf[0..8]);
+        let mut registers = [0u8; 16];
+        registers.copy_from_slice(&buf[16..32]);
+        Record {
+            cycle: u64::from_le_bytes(cycle),
+            frame: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            pc: u16_at(12),
+            opcode: u16_at(14),
+            registers,
+            index_register: u16_at(32),
+            sp: buf[34],
+            delay_timer: buf[35],
+            sound_timer: buf[36],
+        }
+    }
+
+    /// Fixed-size little-endian encoding, `BINARY_RECORD_SIZE` bytes.
+    pub fn to_binary(&self) -> [u8; BINARY_RECORD_SIZE] {
+        let mut buf = [0u8; BINARY_RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.cycle.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.frame.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.pc.to_le_bytes());
+        buf[14..16].copy_from_slice(&self.opcode.to_le_bytes());
+        buf[16..32].copy_from_slice(&self.registers);
+        buf[32..34].copy_from_slice(&self.index_register.to_le_bytes());
+        buf[34] = self.sp;
+        buf[35] = self.delay_timer;
+        buf[36] = self.sound_timer;
+        buf
+    }
+}
+
+/// Which instructions end up in the trace. Classes are the opcode's high
+/// nibble (`0x8` for the ALU group, `0xD` for draws, ...).
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub range: Option<RangeInclusive<u16>>,
+    pub classes: Vec<u8>,
+}
+
+impl Filter {
+    pub fn accepts(&self, pc: u16, opcode: u16) -> bool {
+        let in_range = match &self.range {
+            Some(range) => range.contains(&pc),
+            None => true,
+        };
+        let in_class = self.classes.is_empty() || self.classes.contains(&((opcode >> 12) as u8));
+        in_range && in_class
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Binary,
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: Format,
+    filter: Filter,
+}
+
+impl Tracer {
+    pub fn new(mut out: Box<dyn Write>, format: Format, filter: Filter) -> io::Result<Tracer> {
+        match format {
+            Format::Text => writeln!(out, "{}", TEXT_HEADER)?,
+            Format::Binary => out.write_all(BINARY_MAGIC)?,
+        }
+        Ok(Tracer {
+            out,
+            format,
+            filter,
+        })
+    }
+
+    pub fn create(path: &str, format: Format, filter: Filter) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Tracer::new(Box::new(BufWriter::new(file)), format, filter)
+    }
+
+    pub fn log(&mut self, record: &Record, instruction: &Instruction) -> io::Result<()> {
+        if !self.filter.accepts(record.pc, record.opcode) {
+            return Ok(());
+        }
+        match self.format {
+            Format::Text => writeln!(self.out, "{}", record.to_text(instruction)),
+            Format::Binary => self.out.write_all(&record.to_binary()),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// FNV-1a hash of which pixels are lit, row by row.
+pub fn framebuffer_hash(buffer: &[u8]) -> u64 {
+    buffer.iter().fold(0xcbf29ce484222325, |hash, pixel| {
+        (hash ^ (*pixel != 0) as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Parse `0x200-0x2FF` (or decimal) into an address range.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (from, to) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected FROM-TO, got `{}`", s))?;
+    Ok(parse_address(from)?..=parse_address(to)?)
+}
+
+/// Parse a comma separated list of opcode classes such as `8,D,F`.
+pub fn parse_classes(s: &str) -> Result<Vec<u8>, String> {
+    s.split(',')
+        .map(|c| {
+            u8::from_str_radix(c.trim().trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|c| *c < 16)
+                .ok_or_else(|| format!("invalid opcode class `{}`", c))
+        })
+        .collect()
+}
+
+/// Parse an address in hex (`0x2A0`) or decimal.
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid address `{}`", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::instruction::decode;
+
+    fn record() -> Record {
+        let mut registers = [0u8; 16];
+        registers[0xA] = 2;
+        Record {
+            cycle: 12,
+            frame: 3,
+            pc: 0x204,
+            opcode: 0x6A02,
+            registers,
+            index_register: 0x300,
+            sp: 1,
+            delay_timer: 0x3C,
+            sound_timer: 0,
+        }
+    }
+
+    #[test]
+    fn text_line() {
+        let r = record();
+        assert_eq!(
+            r.to_text(&decode(r.opcode)),
+            "      12      3 0204 6A02 00 00 00 00 00 00 00 00 00 00 02 00 00 00 00 00 0300  1 3C 00  LD VA, 0x02"
+        );
+    }
+
+    #[test]
+    fn binary_record() {
+        let bytes = record().to_binary();
+        assert_eq!(&bytes[0..8], &12u64.to_le_bytes());
+        assert_eq!(&bytes[12..16], &[0x04, 0x02, 0x02, 0x6A]);
+        assert_eq!(bytes[26], 2);
+        assert_eq!(&bytes[32..], &[0x00, 0x03, 1, 0x3C, 0]);
+        assert_eq!(Record::from_binary(&bytes), record());
+    }
+
+    #[test]
+    fn filters() {
+        let filter = Filter {
+            range: Some(parse_range("0x200-0x20F").unwrap()),
+            classes: parse_classes("6,d").unwrap(),
+        };
+        assert!(filter.accepts(0x204, 0x6A02));
+        assert!(filter.accepts(0x20F, 0xD015));
+        assert!(!filter.accepts(0x210, 0x6A02));
+        assert!(!filter.accepts(0x204, 0x7A02));
+        assert!(parse_classes("G").is_err());
+        assert!(parse_range("0x200").is_err());
+    }
+}
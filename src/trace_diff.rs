@@ -0,0 +1,398 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::chip::Chip;
+use crate::instruction::{decode_at, Instruction};
+use crate::trace::{framebuffer_hash, Record, BINARY_MAGIC, BINARY_RECORD_SIZE};
+
+/// One reference trace entry. Fields the reference did not record are
+/// `None` and never compared. `writes` and `framebuffer` describe the state
+/// after the instruction, everything else the state before it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Expected {
+    pub frame: Option<u32>,
+    pub pc: Option<u16>,
+    pub opcode: Option<u16>,
+    pub registers: [Option<u8>; 16],
+    pub index_register: Option<u16>,
+    pub sp: Option<u8>,
+    pub delay_timer: Option<u8>,
+    pub sound_timer: Option<u8>,
+    pub writes: Option<Vec<(u16, u8)>>,
+    pub framebuffer: Option<u64>,
+}
+
+impl From<&Record> for Expected {
+    fn from(record: &Record) -> Expected {
+        let mut registers = [None; 16];
+        for (expected, value) in registers.iter_mut().zip(record.registers.iter()) {
+            *expected = Some(*value);
+        }
+        Expected {
+            frame: Some(record.frame),
+            pc: Some(record.pc),
+            opcode: Some(record.opcode),
+            registers,
+            index_register: Some(record.index_register),
+            sp: Some(record.sp),
+            delay_timer: Some(record.delay_timer),
+            sound_timer: Some(record.sound_timer),
+            writes: None,
+            framebuffer: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Mismatch {
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// First point where the emulator and the reference disagree.
+#[derive(Debug)]
+pub struct Divergence {
+    pub index: usize,
+    pub state: Record,
+    pub instruction: Instruction,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Divergence at trace entry {} (cycle {}, PC {:#05x}: {:04X} {})",
+            self.index, self.state.cycle, self.state.pc, self.state.opcode, self.instruction
+        )?;
+        for m in self.mismatches.iter() {
+            writeln!(
+                f,
+                "    {:<12} expected {:<16} got {}",
+                m.field, m.expected, m.actual
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Load a reference trace: our binary format, our text format, or
+/// `key=value` lines (`pc`, `op`, `v0`..`vf`, `i`, `sp`, `dt`, `st`, `frame`,
+/// `mw=ADDR:VAL;...` for memory writes, `fb` for `framebuffer_hash`).
+pub fn load(path: &Path) -> Result<Vec<Expected>, String> {
+    let data = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    if data.starts_with(BINARY_MAGIC) {
+        return parse_binary(&data[BINARY_MAGIC.len()..]);
+    }
+    let text = String::from_utf8(data).map_err(|_| format!("{} is not a trace", path.display()))?;
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if let Some(entry) =
+            parse_line(line).map_err(|e| format!("{}:{}: {}", path.display(), n + 1, e))?
+        {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+pub fn parse_binary(data: &[u8]) -> Result<Vec<Expected>, String> {
+    if !data.chunks_exact(BINARY_RECORD_SIZE).remainder().is_empty() {
+        return Err("truncated binary trace".to_string());
+    }
+    Ok(data
+        .chunks_exact(BINARY_RECORD_SIZE)
+        .map(|chunk| {
+            let mut buf = [0u8; BINARY_RECORD_SIZE];
+            buf.copy_from_slice(chunk);
+            Expected::from(&Record::from_binary(&buf))
+        })
+        .collect())
+}
+
+/// Parse one text line. Blank lines and `#` comments give `None`.
+pub fn parse_line(line: &str) -> Result<Option<Expected>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields[0].chars().all(|c| c.is_ascii_digit()) && fields.len() >= 25 {
+        return parse_native(&fields).map(Some);
+    }
+    parse_key_values(line).map(Some)
+}
+
+fn hex<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(digits, 16)
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or_else(|| format!("invalid hex value `{}`", s))
+}
+
+fn dec<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid number `{}`", s))
+}
+
+fn parse_native(fields: &[&str]) -> Result<Expected, String> {
+    let mut expected = Expected {
+        frame: Some(dec(fields[1])?),
+        pc: Some(hex(fields[2])?),
+        opcode: Some(hex(fields[3])?),
+        index_register: Some(hex(fields[20])?),
+        sp: Some(dec(fields[21])?),
+        delay_timer: Some(hex(fields[22])?),
+        sound_timer: Some(hex(fields[23])?),
+        ..Expected::default()
+    };
+    for (reg, field) in fields[4..20].iter().enumerate() {
+        expected.registers[reg] = Some(hex(field)?);
+    }
+    Ok(expected)
+}
+
+fn parse_key_values(line: &str) -> Result<Expected, String> {
+    let mut expected = Expected::default();
+    for field in line.split(|c: char| c.is_whitespace() || c == ',') {
+        let (key, value) = match field.split_once('=').or_else(|| field.split_once(':')) {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let key = key.to_ascii_lowercase();
+        match key.as_str() {
+            "frame" => expected.frame = Some(dec(value)?),
+            "pc" => expected.pc = Some(hex(value)?),
+            "op" | "opcode" => expected.opcode = Some(hex(value)?),
+            "i" => expected.index_register = Some(hex(value)?),
+            "sp" => expected.sp = Some(hex(value)?),
+            "dt" => expected.delay_timer = Some(hex(value)?),
+            "st" => expected.sound_timer = Some(hex(value)?),
+            "fb" => expected.framebuffer = Some(hex(value)?),
+            "mw" => {
+                let mut writes = Vec::new();
+                for write in value.split(';').filter(|w| !w.is_empty()) {
+                    let (addr, byte) = write
+                        .split_once(':')
+                        .ok_or_else(|| format!("invalid memory write `{}`", write))?;
+                    writes.push((hex(addr)?, hex(byte)?));
+                }
+                expected.writes = Some(writes);
+            }
+            k if k.len() == 2 && k.starts_with('v') => {
+                let reg = hex::<u8>(&k[1..])?;
+                expected.registers[reg as usize] = Some(hex(value)?);
+            }
+            _ => (),
+        }
+    }
+    Ok(expected)
+}
+
+fn check<T: PartialEq + fmt::UpperHex>(
+    mismatches: &mut Vec<Mismatch>,
+    field: &str,
+    expected: Option<T>,
+    actual: T,
+) {
+    if let Some(expected) = expected {
+        if expected != actual {
+            mismatches.push(Mismatch {
+                field: field.to_string(),
+                expected: format!("{:X}", expected),
+                actual: format!("{:X}", actual),
+            });
+        }
+    }
+}
+
+fn compare_before(expected: &Expected, state: &Record) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    check(&mut mismatches, "PC", expected.pc, state.pc);
+    check(&mut mismatches, "opcode", expected.opcode, state.opcode);
+    for (reg, value) in expected.registers.iter().enumerate() {
+        check(
+            &mut mismatches,
+            &format!("V{:X}", reg),
+            *value,
+            state.registers[reg],
+        );
+    }
+    check(
+        &mut mismatches,
+        "I",
+        expected.index_register,
+        state.index_register,
+    );
+    check(&mut mismatches, "SP", expected.sp, state.sp);
+    check(
+        &mut mismatches,
+        "DT",
+        expected.delay_timer,
+        state.delay_timer,
+    );
+    check(
+        &mut mismatches,
+        "ST",
+        expected.sound_timer,
+        state.sound_timer,
+    );
+    mismatches
+}
+
+/// Run `chip` alongside `reference`, one instruction per entry. Returns the
+/// number of matching entries, or the first divergence.
+///
+/// When the reference records frame numbers the timers tick whenever the
+/// frame advances; otherwise they tick after every instruction. Random
+/// results (`Cxkk`) are taken from the reference so they cannot diverge.
+pub fn lockstep(chip: &mut Chip, reference: &[Expected]) -> Result<usize, Box<Divergence>> {
+    let mut last_random = None;
+    for (index, expected) in reference.iter().enumerate() {
+        if let Some(frame) = expected.frame {
+            while chip.frames() < frame {
+                chip.tick_timers();
+            }
+        }
+        if let Some(reg) = last_random.take() {
+            if let Some(value) = expected.registers[reg as usize] {
+                chip.set_register(reg, value);
+            }
+        }
+
+        let state = chip.state();
+        let instruction = decode_at(chip.memory(), state.pc as usize);
+        let diverged = |mismatches| {
+            Box::new(Divergence {
+                index,
+                state: state.clone(),
+                instruction,
+                mismatches,
+            })
+        };
+
+        let mismatches = compare_before(expected, &state);
+        if !mismatches.is_empty() {
+            return Err(diverged(mismatches));
+        }
+
+        let memory_before = expected.writes.as_ref().map(|_| chip.memory().to_vec());
+        chip.step();
+        if expected.frame.is_none() {
+            chip.tick_timers();
+        }
+        if let Instruction::Random(reg, _) = instruction {
+            last_random = Some(reg);
+        }
+
+        let mut mismatches = Vec::new();
+        if let (Some(writes), Some(before)) = (&expected.writes, memory_before) {
+            let actual: Vec<(u16, u8)> = before
+                .iter()
+                .zip(chip.memory().iter())
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(addr, (_, new))| (addr as u16, *new))
+                .collect();
+            let mut writes = writes.clone();
+            writes.sort_unstable();
+            if writes != actual {
+                let show = |w: &[(u16, u8)]| {
+                    let items: Vec<String> = w
+                        .iter()
+                        .map(|(a, v)| format!("{:03X}:{:02X}", a, v))
+                        .collect();
+                    format!("[{}]", items.join(";"))
+                };
+                mismatches.push(Mismatch {
+                    field: "memory writes".to_string(),
+                    expected: show(&writes),
+                    actual: show(&actual),
+                });
+            }
+        }
+        check(
+            &mut mismatches,
+            "framebuffer",
+            expected.framebuffer,
+            framebuffer_hash(chip.framebuffer()),
+        );
+        if !mismatches.is_empty() {
+            return Err(diverged(mismatches));
+        }
+    }
+    Ok(reference.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chip_with(program: &[u8]) -> Chip {
+        let mut chip = Chip::new();
+        chip.load_program(program);
+        chip
+    }
+
+    #[test]
+    fn parse_native_line() {
+        let line = "       0      0 0200 6A02 00 00 00 00 00 00 00 00 00 00 02 00 00 00 00 00 0300  1 3C 00  LD VA, 0x02";
+        let expected = parse_line(line).unwrap().unwrap();
+        assert_eq!(expected.pc, Some(0x200));
+        assert_eq!(expected.registers[0xA], Some(2));
+        assert_eq!(expected.index_register, Some(0x300));
+        assert_eq!(expected.delay_timer, Some(0x3C));
+        assert_eq!(parse_line("# header").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_key_value_line() {
+        let expected = parse_line("PC:0x0202 I=0300, V3=1F mw=300:01;301:02 fb=ff")
+            .unwrap()
+            .unwrap();
+        assert_eq!(expected.pc, Some(0x202));
+        assert_eq!(expected.index_register, Some(0x300));
+        assert_eq!(expected.registers[3], Some(0x1F));
+        assert_eq!(expected.writes, Some(vec![(0x300, 1), (0x301, 2)]));
+        assert_eq!(expected.framebuffer, Some(0xFF));
+        assert_eq!(expected.opcode, None);
+    }
+
+    #[test]
+    fn lockstep_finds_register_divergence() {
+        // LD V1, 5; ADD V1, 1; LD I, 0x300
+        let program = [0x61, 0x05, 0x71, 0x01, 0xA3, 0x00];
+        let reference: Vec<Expected> = ["pc=200", "pc=202 v1=05", "pc=204 v1=07"]
+            .iter()
+            .map(|l| parse_line(l).unwrap().unwrap())
+            .collect();
+        let divergence = lockstep(&mut chip_with(&program), &reference).unwrap_err();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(
+            divergence.mismatches,
+            vec![Mismatch {
+                field: "V1".to_string(),
+                expected: "7".to_string(),
+                actual: "6".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn lockstep_checks_memory_writes() {
+        // LD I, 0x300; LD V0, 0x42; LD [I], V0
+        let program = [0xA3, 0x00, 0x60, 0x42, 0xF0, 0x55];
+        let good: Vec<Expected> = ["pc=200", "pc=202", "pc=204 mw=300:42"]
+            .iter()
+            .map(|l| parse_line(l).unwrap().unwrap())
+            .collect();
+        assert_eq!(lockstep(&mut chip_with(&program), &good).unwrap(), 3);
+
+        let bad = vec![parse_line("pc=200 mw=300:42").unwrap().unwrap()];
+        let divergence = lockstep(&mut chip_with(&program), &bad).unwrap_err();
+        assert_eq!(divergence.mismatches[0].field, "memory writes");
+    }
+}
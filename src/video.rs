@@ -1,16 +1,21 @@
 pub const VIDEO_WIDTH: usize = 64;
 pub const VIDEO_HEIGHT: usize = 32;
-pub const PITCH: usize = 4 * VIDEO_WIDTH;
-const VIDEO_SIZE: usize = VIDEO_HEIGHT * VIDEO_WIDTH;
+/// SUPER-CHIP high resolution, selected by `00FF`.
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
 
+/// Logical pixel values: 0 is off, 1 is lit. The frontend maps them to
+/// colours through a palette.
 pub struct Video {
-    buf: [u32; VIDEO_SIZE],
+    buf: Vec<u8>,
+    hires: bool,
 }
 
 impl Video {
     pub fn new() -> Video {
         Video {
-            buf: [0u32; VIDEO_SIZE],
+            buf: vec![0; VIDEO_WIDTH * VIDEO_HEIGHT],
+            hires: false,
         }
     }
 
@@ -18,12 +23,44 @@ impl Video {
         self.buf.fill(0);
     }
 
-    pub fn buffer(&self) -> &[u32] {
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Switch resolution, clearing the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.buf = vec![0; self.width() * self.height()];
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            VIDEO_WIDTH
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            VIDEO_HEIGHT
+        }
+    }
+
+    /// Pixels row by row, `width()` per row.
+    pub fn buffer(&self) -> &[u8] {
         &self.buf[..]
     }
 
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+
     // TODO: fix this method -> swap x, y and use % inside method
-    pub fn pixel(&mut self, y: usize, x: usize) -> &mut u32 {
-        &mut self.buf[y * VIDEO_WIDTH + x]
+    pub fn pixel(&mut self, y: usize, x: usize) -> &mut u8 {
+        let width = self.width();
+        &mut self.buf[y * width + x]
     }
 }